@@ -5,7 +5,7 @@ use async_std::io;
 use futures::channel::oneshot;
 use sql_proxy::{
     packet::{DatabaseType, Packet},
-    packet_handler::PacketHandler,
+    packet_handler::{HandlerAction, PacketContext, PacketHandler},
 };
 
 struct PassthroughHandler {}
@@ -13,22 +13,22 @@ struct PassthroughHandler {}
 // Just forward the packet
 #[async_trait::async_trait]
 impl PacketHandler for PassthroughHandler {
-    async fn handle_request(&mut self, p: &Packet) -> Packet {
+    async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
         debug!(
             "c=>s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
-        p.clone()
+        HandlerAction::Forward
     }
 
-    async fn handle_response(&mut self, p: &Packet) -> Packet {
+    async fn handle_response(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
         debug!(
             "c<=s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
-        p.clone()
+        HandlerAction::Forward
     }
 }
 
@@ -54,10 +54,7 @@ async fn main() {
     // determine what type of database it is
     let db_type_str = args.next().unwrap_or_else(|| "mariadb".to_string());
     // let db_type_str = args.next().unwrap_or_else(|| "postgres".to_string());
-    let mut db_type = DatabaseType::PostgresSQL;
-    if db_type_str == "mariadb" {
-        db_type = DatabaseType::MariaDB;
-    }
+    let db_type: DatabaseType = db_type_str.parse().expect("Unknown database type");
 
     let mut server =
         sql_proxy::server::Server::new(bind_addr.clone(), db_type, db_addr.clone()).await;
@@ -0,0 +1,343 @@
+//! Query fingerprints, for aggregating statistics over queries that only differ in their values
+use crate::packet::DatabaseType;
+
+/// Normalizes a query the way pt-query-digest does, so that queries differing only in their
+/// literals share a fingerprint:
+/// - numbers, quoted strings and PostgresSQL `$1` parameters become `?`
+/// - `IN (...)` lists of values become `in (?)`, and the repeated rows of a multi-row
+///   `INSERT` become one
+/// - comments are removed, whitespace is collapsed, and everything but quoted identifiers is
+///   lowercased, as is a trailing `;`
+///
+/// `"..."` is a string in MariaDB, but an identifier in PostgresSQL. Backslashes escape quotes
+/// in MariaDB strings, and in PostgresSQL `E'...'` strings, but not in standard PostgresSQL ones
+/// ```
+/// # use sql_proxy::{fingerprint::fingerprint, packet::DatabaseType};
+/// let sql = "SELECT * FROM t WHERE id IN (1, 2, 3) AND name = 'it''s' -- comment";
+/// assert_eq!(
+///     fingerprint(DatabaseType::MariaDB, sql),
+///     "select * from t where id in (?) and name = ?"
+/// );
+/// ```
+pub fn fingerprint(db_type: DatabaseType, query: &str) -> String {
+    let chars: Vec<char> = query.chars().collect();
+    let mut f = Fingerprint {
+        out: String::with_capacity(query.len()),
+        open_parens: Vec::new(),
+    };
+    let mariadb = db_type == DatabaseType::MariaDB;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            '-' if next == Some('-') => {
+                i = skip_line(&chars, i);
+                f.space();
+            }
+            '#' if mariadb => {
+                i = skip_line(&chars, i);
+                f.space();
+            }
+            '/' if next == Some('*') => {
+                i = match find(&chars, i + 2, &['*', '/']) {
+                    Some(end) => end + 2,
+                    None => chars.len(),
+                };
+                f.space();
+            }
+            '\'' => {
+                i = skip_string(&chars, i, mariadb);
+                f.literal();
+            }
+            '"' if mariadb => {
+                i = skip_string(&chars, i, true);
+                f.literal();
+            }
+            // Quoted identifiers are kept as they are
+            '"' | '`' => {
+                let end = match chars[(i + 1)..].iter().position(|d| *d == c) {
+                    Some(n) => i + 1 + n + 1,
+                    None => chars.len(),
+                };
+                f.out.extend(&chars[i..end]);
+                i = end;
+            }
+            '$' if !mariadb && next.is_some_and(|d| d.is_ascii_digit()) => {
+                i = skip_while(&chars, i + 1, |d| d.is_ascii_digit());
+                f.literal();
+            }
+            '$' if !mariadb => match dollar_quote_end(&chars, i) {
+                Some(end) => {
+                    i = end;
+                    f.literal();
+                }
+                None => {
+                    f.out.push(c);
+                    i += 1;
+                }
+            },
+            '0'..='9' => {
+                i = skip_number(&chars, i);
+                f.literal();
+            }
+            '.' if next.is_some_and(|d| d.is_ascii_digit()) => {
+                i = skip_number(&chars, i);
+                f.literal();
+            }
+            c if c.is_whitespace() => {
+                f.space();
+                i += 1;
+            }
+            c if is_word_start(c) => {
+                let end = skip_while(&chars, i, is_word_char);
+                let prefixed_string =
+                    end == i + 1 && chars.get(end) == Some(&'\'') && "eEnNxXbB".contains(c);
+                if prefixed_string {
+                    // e.g. E'\n', X'ff'
+                    i = skip_string(&chars, end, mariadb || c == 'e' || c == 'E');
+                    f.literal();
+                } else {
+                    let word: String = chars[i..end].iter().collect();
+                    f.out.push_str(&word.to_lowercase());
+                    i = end;
+                }
+            }
+            '(' => {
+                f.open_parens.push(f.out.len());
+                f.out.push('(');
+                i += 1;
+            }
+            ')' => {
+                f.close_paren();
+                i += 1;
+            }
+            ',' => {
+                f.trim_space();
+                f.out.push_str(", ");
+                i += 1;
+            }
+            _ => {
+                f.out.push(c);
+                i += 1;
+            }
+        }
+    }
+    f.trim_space();
+    if f.out.ends_with(';') {
+        f.out.pop();
+        f.trim_space();
+    }
+    f.out
+}
+
+struct Fingerprint {
+    out: String,
+    /// Where the parentheses that are still open are in `out`
+    open_parens: Vec<usize>,
+}
+
+impl Fingerprint {
+    fn space(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with(' ') && !self.out.ends_with('(') {
+            self.out.push(' ');
+        }
+    }
+
+    fn trim_space(&mut self) {
+        if self.out.ends_with(' ') {
+            self.out.pop();
+        }
+    }
+
+    fn literal(&mut self) {
+        self.out.push('?');
+    }
+
+    fn close_paren(&mut self) {
+        self.trim_space();
+        let open = match self.open_parens.pop() {
+            Some(open) => open,
+            None => {
+                self.out.push(')');
+                return;
+            }
+        };
+        let inside = &self.out[(open + 1)..];
+        let before = self.out[..open].trim_end();
+        // Byte offsets, so the end of `before` may be in the middle of a multi-byte character
+        let is_in = before
+            .get(before.len().saturating_sub(2)..)
+            .is_some_and(|end| end.eq_ignore_ascii_case("in"))
+            && !before[..(before.len() - 2)]
+                .chars()
+                .next_back()
+                .is_some_and(is_word_char);
+        if is_in && inside.split(", ").all(|value| value == "?") {
+            let in_end = before.len();
+            self.out.truncate(in_end);
+            self.out.push_str(" (?)");
+            return;
+        }
+        self.out.push(')');
+        // A row of a multi-row INSERT repeating the previous one
+        let row = &self.out[open..];
+        if let Some(previous) = self.out[..open].strip_suffix(", ") {
+            if previous.ends_with(row) && row.len() < previous.len() {
+                self.out.truncate(open - 2);
+            }
+        }
+    }
+}
+
+fn is_word_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+fn skip_while<F: Fn(char) -> bool>(chars: &[char], mut i: usize, f: F) -> usize {
+    while i < chars.len() && f(chars[i]) {
+        i += 1;
+    }
+    i
+}
+
+/// Index after the end of the line, for `--` and `#` comments
+fn skip_line(chars: &[char], i: usize) -> usize {
+    skip_while(chars, i, |c| c != '\n')
+}
+
+fn find(chars: &[char], from: usize, pattern: &[char]) -> Option<usize> {
+    chars
+        .get(from..)?
+        .windows(pattern.len())
+        .position(|w| w == pattern)
+        .map(|n| from + n)
+}
+
+/// Index after the string starting with the quote at `i`.
+/// A doubled quote is part of the string, as is one after a backslash if `backslash_escapes`
+fn skip_string(chars: &[char], i: usize, backslash_escapes: bool) -> usize {
+    let quote = chars[i];
+    let mut j = i + 1;
+    while j < chars.len() {
+        match chars[j] {
+            '\\' if backslash_escapes => j += 2,
+            c if c == quote && chars.get(j + 1) == Some(&quote) => j += 2,
+            c if c == quote => return j + 1,
+            _ => j += 1,
+        }
+    }
+    chars.len()
+}
+
+/// Index after a PostgresSQL dollar-quoted string starting at `i`, e.g. `$$...$$` or
+/// `$body$...$body$`, or `None` if there is none
+fn dollar_quote_end(chars: &[char], i: usize) -> Option<usize> {
+    let tag_end = skip_while(chars, i + 1, |c| c.is_alphanumeric() || c == '_');
+    if chars.get(tag_end) != Some(&'$') {
+        return None;
+    }
+    let tag = &chars[i..=tag_end];
+    match find(chars, tag_end + 1, tag) {
+        Some(end) => Some(end + tag.len()),
+        None => Some(chars.len()),
+    }
+}
+
+/// Index after the number starting at `i`: hex, or decimal with an optional exponent
+fn skip_number(chars: &[char], i: usize) -> usize {
+    if chars[i] == '0' && (chars.get(i + 1) == Some(&'x') || chars.get(i + 1) == Some(&'X')) {
+        return skip_while(chars, i + 2, |c| c.is_ascii_hexdigit());
+    }
+    let mut j = skip_while(chars, i, |c| c.is_ascii_digit() || c == '.');
+    if j < chars.len() && (chars[j] == 'e' || chars[j] == 'E') {
+        let mut k = j + 1;
+        if k < chars.len() && (chars[k] == '+' || chars[k] == '-') {
+            k += 1;
+        }
+        if k < chars.len() && chars[k].is_ascii_digit() {
+            j = skip_while(chars, k, |c| c.is_ascii_digit());
+        }
+    }
+    j
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mariadb(query: &str) -> String {
+        fingerprint(DatabaseType::MariaDB, query)
+    }
+
+    fn postgres(query: &str) -> String {
+        fingerprint(DatabaseType::PostgresSQL, query)
+    }
+
+    #[test]
+    fn replaces_literals() {
+        assert_eq!(
+            mariadb("SELECT a, b FROM t1 WHERE id = 42 AND x > -1.5e3 AND h = 0xFF"),
+            "select a, b from t1 where id = ? and x > -? and h = ?"
+        );
+        assert_eq!(
+            mariadb("UPDATE t SET name = 'O\\'Brien', note = \"say \"\"hi\"\"\" WHERE id=.5"),
+            "update t set name = ?, note = ? where id=?"
+        );
+        assert_eq!(mariadb("SELECT X'ff', _utf8mb4'x'"), "select ?, _utf8mb4?");
+        assert_eq!(
+            postgres("SELECT * FROM \"Users\" WHERE id = $1 AND note = 'C:\\' AND s = E'it\\'s'"),
+            "select * from \"Users\" where id = ? and note = ? and s = ?"
+        );
+        assert_eq!(
+            postgres("SELECT $body$ it's ; $$ $body$, $$x$$::text"),
+            "select ?, ?::text"
+        );
+    }
+
+    #[test]
+    fn removes_comments_and_whitespace() {
+        assert_eq!(
+            mariadb("/* app:web */ SELECT  1 -- one\n  FROM\tdual # really ;\n;"),
+            "select ? from dual"
+        );
+        // # is an operator in PostgresSQL
+        assert_eq!(postgres("SELECT 5 # 3"), "select ? # ?");
+        assert_eq!(
+            mariadb("SELECT `Weird Name` FROM `T`"),
+            "select `Weird Name` from `T`"
+        );
+    }
+
+    #[test]
+    fn collapses_lists() {
+        assert_eq!(
+            mariadb("SELECT * FROM t WHERE id IN (1,2, 3) OR id in('a')"),
+            "select * from t where id in (?) or id in (?)"
+        );
+        assert_eq!(
+            mariadb("SELECT * FROM t WHERE id IN (SELECT id FROM u WHERE x IN (1, 2))"),
+            "select * from t where id in (select id from u where x in (?))"
+        );
+        assert_eq!(
+            mariadb("INSERT INTO t (a, b) VALUES (1, 'x'), (2, 'y'),(3,'z')"),
+            "insert into t (a, b) values (?, ?)"
+        );
+        // Only values are collapsed
+        assert_eq!(
+            mariadb("SELECT max(a, b) FROM t"),
+            "select max(a, b) from t"
+        );
+        assert_eq!(mariadb("SELECT (1)) FROM t"), "select (?)) from t");
+        // Multi-byte characters before the parenthesis
+        assert_eq!(mariadb("SELECT 中(1)"), "select 中(?)");
+        assert_eq!(
+            mariadb("SELECT * FROM t WHERE é IN (1, 2) AND 中in (3)"),
+            "select * from t where é in (?) and 中in (?)"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Commands run on the database once a client has authenticated,
+//! see `PipeOptions::init_commands`
+use std::sync::Mutex;
+
+use crate::{
+    packet::{DatabaseType, Packet, PacketType},
+    query_timer::{mariadb_response_complete, Response},
+};
+
+#[derive(Debug)]
+enum State {
+    /// Waiting for the client to authenticate
+    Pending(Vec<String>),
+    /// Holding back the end of authentication from the client until the commands are done
+    Running {
+        held: Vec<u8>,
+        left: usize,
+        response: Response,
+    },
+    Done,
+}
+
+/// The init commands of a connection, run by its backward pipe.
+///
+/// The end of authentication is held back from the client while the commands run, so the
+/// client can't send anything in the meantime, and their responses are consumed by the pipe.
+/// For MariaDB that is the OK answering the handshake response, or the last response of an
+/// authentication switch. For PostgresSQL it is the first ReadyForQuery, which follows the
+/// AuthenticationOk, ParameterStatus and BackendKeyData messages
+#[derive(Debug)]
+pub(crate) struct InitCommands(Mutex<State>);
+
+impl InitCommands {
+    pub(crate) fn new(commands: Vec<String>) -> InitCommands {
+        if commands.is_empty() {
+            InitCommands(Mutex::new(State::Done))
+        } else {
+            InitCommands(Mutex::new(State::Pending(commands)))
+        }
+    }
+
+    pub(crate) fn is_pending(&self) -> bool {
+        matches!(*self.0.lock().unwrap(), State::Pending(_))
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        matches!(*self.0.lock().unwrap(), State::Running { .. })
+    }
+
+    /// Holds back `held`, the bytes telling the client it has authenticated,
+    /// and returns the commands to send to the database
+    pub(crate) fn start(&self, db_type: DatabaseType, held: Vec<u8>) -> Vec<Packet> {
+        let mut state = self.0.lock().unwrap();
+        let commands = match std::mem::replace(&mut *state, State::Done) {
+            State::Pending(commands) => commands,
+            other => {
+                *state = other;
+                return Vec::new();
+            }
+        };
+        *state = State::Running {
+            held,
+            left: commands.len(),
+            response: Response::First,
+        };
+        commands
+            .iter()
+            .map(|sql| match db_type {
+                DatabaseType::MariaDB => {
+                    // COM_QUERY
+                    let mut payload = vec![0x03];
+                    payload.extend_from_slice(sql.as_bytes());
+                    Packet::mariadb(0, &payload)
+                }
+                DatabaseType::PostgresSQL => {
+                    let mut payload = sql.as_bytes().to_vec();
+                    payload.push(0);
+                    Packet::postgres(b'Q', &payload)
+                }
+            })
+            .collect()
+    }
+
+    /// Consumes a packet of the commands' responses.
+    /// Returns the held bytes once the last response is complete
+    pub(crate) fn consume(&self, p: &Packet) -> Option<Vec<u8>> {
+        let mut state = self.0.lock().unwrap();
+        let (left, response) = match &mut *state {
+            State::Running { left, response, .. } => (left, response),
+            _ => return None,
+        };
+        let complete = match p.get_db_type() {
+            DatabaseType::MariaDB => mariadb_response_complete(response, p),
+            DatabaseType::PostgresSQL => {
+                p.get_packet_type().ok() == Some(PacketType::ReadyForQuery)
+            }
+        };
+        if !complete {
+            return None;
+        }
+        *left -= 1;
+        *response = Response::First;
+        if *left > 0 {
+            return None;
+        }
+        match std::mem::replace(&mut *state, State::Done) {
+            State::Running { held, .. } => Some(held),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_mariadb_ok_until_commands_complete() {
+        let init = InitCommands::new(vec![
+            "SET NAMES utf8mb4".to_string(),
+            "SELECT 1".to_string(),
+        ]);
+        assert!(init.is_pending());
+        let commands = init.start(DatabaseType::MariaDB, vec![7, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(commands[0].get_query().unwrap(), "SET NAMES utf8mb4");
+        assert_eq!(commands[1].get_query().unwrap(), "SELECT 1");
+        assert!(init.is_running());
+        // Starting again does nothing
+        assert!(init.start(DatabaseType::MariaDB, Vec::new()).is_empty());
+
+        let ok = Packet::mariadb(1, &[0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(init.consume(&ok), None);
+        let result_set = [
+            Packet::mariadb(1, &[1]),
+            Packet::mariadb(2, b"\x03def..."),
+            Packet::mariadb(3, &[0xfe, 0, 0, 2, 0]),
+            Packet::mariadb(4, b"\x011"),
+        ];
+        for p in result_set.iter() {
+            assert_eq!(init.consume(p), None);
+        }
+        let eof = Packet::mariadb(5, &[0xfe, 0, 0, 2, 0]);
+        assert_eq!(
+            init.consume(&eof).unwrap(),
+            vec![7, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0]
+        );
+        assert!(!init.is_running());
+        assert_eq!(init.consume(&ok), None);
+    }
+
+    #[test]
+    fn holds_postgres_ready_for_query() {
+        let init = InitCommands::new(vec!["SET TIME ZONE 'UTC'".to_string()]);
+        let commands = init.start(DatabaseType::PostgresSQL, b"Z\0\0\0\x05I".to_vec());
+        assert_eq!(commands[0].get_query().unwrap(), "SET TIME ZONE 'UTC'");
+        let complete = Packet::postgres(b'C', b"SET\0");
+        assert_eq!(init.consume(&complete), None);
+        let ready = Packet::postgres(b'Z', b"I");
+        assert_eq!(init.consume(&ready).unwrap(), b"Z\0\0\0\x05I".to_vec());
+    }
+
+    #[test]
+    fn nothing_to_run() {
+        let init = InitCommands::new(Vec::new());
+        assert!(!init.is_pending());
+        assert!(init.start(DatabaseType::MariaDB, Vec::new()).is_empty());
+        assert!(!init.is_running());
+    }
+}
@@ -3,10 +3,22 @@
 #[macro_use]
 extern crate log;
 
+pub mod fingerprint;
+mod init_commands;
 pub mod packet;
 pub mod packet_handler;
 pub mod pipe;
+pub mod prepared;
+pub mod proxy_protocol;
+pub mod query_timer;
+pub mod request_tracker;
+pub mod router;
 pub mod server;
+pub mod session;
+pub mod stream;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod tls;
 
 #[cfg(test)]
 mod tests {
@@ -1,18 +1,112 @@
-use std::io::{Error, ErrorKind};
+use std::{
+    fmt::{self, Write},
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
 
 use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use bytes::{Bytes, BytesMut};
 
-/// A packet is just a wrapper for a Vec<u8>
+use crate::{
+    fingerprint,
+    session::{
+        parse_handshake_response, read_null_terminated, CLIENT_PLUGIN_AUTH,
+        CLIENT_SECURE_CONNECTION,
+    },
+};
+
+/// A packet is just a wrapper for its bytes, header included.
+/// `Bytes` is reference counted, so framing a packet out of a pipe's buffer
+/// and cloning it are free. It derefs to `&[u8]`
 /// For reference, see https://dev.mysql.com/doc/internals/en/mysql-packet.html
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Packet {
     db_type: DatabaseType,
-    pub bytes: Vec<u8>,
+    pub bytes: Bytes,
 }
 
 impl Packet {
-    pub fn new(db_type: DatabaseType, bytes: Vec<u8>) -> Packet {
-        Packet { db_type, bytes }
+    /// Takes a `Vec<u8>` or `Bytes` without copying, and as they are.
+    /// See `new_checked` for packets built by hand
+    pub fn new<B: Into<Bytes>>(db_type: DatabaseType, bytes: B) -> Packet {
+        Packet {
+            db_type,
+            bytes: bytes.into(),
+        }
+    }
+
+    /// Same as `new`, but errors unless `bytes` is exactly one packet, whose header declares
+    /// the length it has. Catches bugs in handlers that build packets, before the client or
+    /// database gets confused by them. PostgresSQL messages must have a known type byte,
+    /// or be a startup message, SSLRequest or CancelRequest. There is no size limit.
+    /// A reassembled MariaDB packet, see `PipeOptions::reassemble_packets`, is longer than
+    /// its header says and errors
+    pub fn new_checked<B: Into<Bytes>>(db_type: DatabaseType, bytes: B) -> Result<Packet, Error> {
+        let bytes = bytes.into();
+        let mut buf = BytesMut::from(&bytes[..]);
+        let size = match get_packet(db_type, &mut buf, usize::MAX, false) {
+            Ok(Some(packet)) => packet.get_size(),
+            Ok(None) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Incomplete {:?} packet of {} bytes", db_type, bytes.len()),
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if size != bytes.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{:?} packet of {} bytes followed by {} more bytes",
+                    db_type,
+                    size,
+                    bytes.len() - size
+                ),
+            ));
+        }
+        Ok(Packet::new(db_type, bytes))
+    }
+
+    /// Create a MariaDB packet, prepending the 3-byte length and sequence id header.
+    /// `payload` must be shorter than 0xFFFFFF bytes, larger payloads span several packets
+    pub fn mariadb(sequence_id: u8, payload: &[u8]) -> Packet {
+        let mut bytes: Vec<u8> = Vec::with_capacity(4 + payload.len());
+        bytes
+            .write_u32::<LittleEndian>(payload.len() as u32)
+            .unwrap();
+        bytes.pop(); // we need 3 byte length, so discard last byte
+        bytes.push(sequence_id);
+        bytes.extend_from_slice(payload);
+        Packet::new(DatabaseType::MariaDB, bytes)
+    }
+
+    /// Create a PostgresSQL message with a type byte, e.g. b'Q',
+    /// followed by the big-endian length (which counts itself, but not the type byte)
+    pub fn postgres(msg_type: u8, payload: &[u8]) -> Packet {
+        let mut bytes: Vec<u8> = Vec::with_capacity(5 + payload.len());
+        bytes.push(msg_type);
+        bytes
+            .write_u32::<BigEndian>(4 + payload.len() as u32)
+            .unwrap();
+        bytes.extend_from_slice(payload);
+        Packet::new(DatabaseType::PostgresSQL, bytes)
+    }
+
+    /// Create a PostgresSQL StartupMessage for protocol 3.0 with `params`, e.g.
+    /// `[("user", "root"), ("database", "testdb")]`
+    pub fn postgres_startup(params: &[(&str, &str)]) -> Packet {
+        let mut bytes: Vec<u8> = vec![0, 0, 0, 0, 0, 3, 0, 0];
+        for (name, value) in params {
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        bytes.push(0);
+        let length = bytes.len() as u32;
+        BigEndian::write_u32(&mut bytes[0..4], length);
+        Packet::new(DatabaseType::PostgresSQL, bytes)
     }
 
     /**
@@ -27,48 +121,521 @@ impl Packet {
         payload.extend_from_slice(&state); // SQL STATE
         payload.extend_from_slice(msg.as_bytes());
 
-        // create header with length and sequence id
-        let mut header: Vec<u8> = Vec::with_capacity(4 + 9 + msg.len());
-        header
-            .write_u32::<LittleEndian>(payload.len() as u32)
-            .unwrap();
-        header.pop(); // we need 3 byte length, so discard last byte
-        header.push(1); // sequence_id
+        Packet::mariadb(1, &payload)
+    }
 
-        // combine the vectors
-        header.extend_from_slice(&payload);
+    /// Create a PostgresSQL ErrorResponse with severity ERROR, e.g. code `*b"42501"`
+    /// https://www.postgresql.org/docs/12/protocol-error-fields.html
+    pub fn error_packet_postgres(code: [u8; 5], msg: String) -> Self {
+        let mut payload: Vec<u8> = Vec::with_capacity(24 + msg.len());
+        payload.extend_from_slice(b"SERROR\0"); // severity, localized
+        payload.extend_from_slice(b"VERROR\0"); // severity
+        payload.push(b'C'); // SQLSTATE code
+        payload.extend_from_slice(&code);
+        payload.push(0);
+        payload.push(b'M'); // message
+        payload.extend_from_slice(msg.as_bytes());
+        payload.push(0);
+        payload.push(0); // terminator
+        Packet::postgres(b'E', &payload)
+    }
 
-        // now move the vector into the packet
-        Packet {
-            db_type: DatabaseType::MariaDB,
-            bytes: header,
-        }
+    /// Splits the first complete packet off the front of `buf`, leaving any bytes after it.
+    /// Returns `Ok(None)`, and leaves `buf` untouched, while more bytes are needed.
+    /// Errors if `buf` doesn't start with a packet, or with one larger than `max_packet_size`
+    /// bytes, header included, after which there is no telling where the next packet starts.
+    /// Pipes pass `PipeOptions::max_packet_size`, `DEFAULT_MAX_PACKET_SIZE` unless set.
+    ///
+    /// MariaDB packets split across maximum-length packets are returned one by one, unless
+    /// `reassemble` is set, see `PipeOptions::reassemble_packets`: then they are joined into
+    /// one packet, keeping the first header, and `max_packet_size` applies to the whole.
+    /// The packet shares `buf`'s memory, unless it was reassembled
+    pub fn try_parse(
+        db_type: DatabaseType,
+        buf: &mut BytesMut,
+        max_packet_size: usize,
+        reassemble: bool,
+    ) -> Result<Option<Packet>, Error> {
+        get_packet(db_type, buf, max_packet_size, reassemble).map_err(Error::from)
+    }
+
+    pub fn get_db_type(&self) -> DatabaseType {
+        self.db_type
     }
 
+    /// The length of the bytes, header included. Only packets from `new_checked`, or read by
+    /// a pipe without `PipeOptions::reassemble_packets`, are sure to have the size their
+    /// header declares. A reassembled MariaDB packet is longer, it keeps its first header
     pub fn get_size(&self) -> usize {
         self.bytes.len()
     }
 
+    /// Whether this is a MariaDB packet of the maximum payload length (0xFFFFFF bytes),
+    /// which the next packet continues, rather than a complete one. A handler that can't
+    /// follow split packets should refuse these, as the continuation's payload won't parse.
+    /// Packets reassembled by `PipeOptions::reassemble_packets` are complete, and false here,
+    /// except for a payload of exactly 0xFFFFFF bytes, whose header looks the same
+    /// https://mariadb.com/kb/en/0-packet/#packet-splitting
+    pub fn is_mariadb_continuation(&self) -> bool {
+        self.db_type == DatabaseType::MariaDB
+            && self.bytes.len() == 4 + MARIADB_MAX_PAYLOAD
+            && self.bytes[0..3] == [0xff, 0xff, 0xff]
+    }
+
+    /// Formats the bytes, header included, as lines of offset, 16 bytes in hex and
+    /// the same bytes in ASCII, with `.` for anything unprintable:
+    /// `00000000  01 00 00 00 0e                                   |.....|`
+    pub fn hexdump(&self) -> String {
+        let mut dump = String::with_capacity(self.bytes.len() / 16 * 78 + 78);
+        for (i, line) in self.bytes.chunks(16).enumerate() {
+            let _ = write!(dump, "{:08x} ", i * 16);
+            for j in 0..16 {
+                if j == 8 {
+                    dump.push(' ');
+                }
+                match line.get(j) {
+                    Some(b) => {
+                        let _ = write!(dump, " {:02x}", b);
+                    }
+                    None => dump.push_str("   "),
+                }
+            }
+            dump.push_str("  |");
+            dump.extend(line.iter().map(|b| match b {
+                0x20..=0x7e => *b as char,
+                _ => '.',
+            }));
+            dump.push_str("|\n");
+        }
+        dump
+    }
+
+    /// Returns the SQL text carried by a query packet.
+    /// For MariaDB, this is the payload following the COM_QUERY (0x03) command byte.
+    /// For PostgresSQL, this is the null-terminated string of a simple Query ('Q') message.
+    /// Returns an error for non-query packets or if the query is not valid UTF-8.
+    /// This is lossless, see `get_query_lossy` for queries carrying binary data
     pub fn get_query(&self) -> Result<String, Error> {
+        String::from_utf8(self.query_bytes()?.to_vec())
+            .map_err(|_e| Error::other("Query is not valid UTF-8"))
+    }
+
+    /// Same as `get_query`, but never fails on the query's encoding: invalid UTF-8, e.g. a blob
+    /// inlined in an INSERT, is replaced with U+FFFD. The result is lossy, and only meant for
+    /// logging, the original bytes stay in `bytes`. Still an error for non-query packets
+    pub fn get_query_lossy(&self) -> Result<String, Error> {
+        Ok(String::from_utf8_lossy(self.query_bytes()?).into_owned())
+    }
+
+    /// The bytes of the SQL text of a query packet, see `get_query`
+    fn query_bytes(&self) -> Result<&[u8], Error> {
         match (self.db_type, self.get_packet_type()) {
-            (DatabaseType::MariaDB, Ok(PacketType::ComQuery)) => {
-                Ok(String::from_utf8(self.bytes[5..].to_vec()).expect("Invalid UTF-8"))
-            }
+            (DatabaseType::MariaDB, Ok(PacketType::ComQuery)) => Ok(&self.bytes[5..]),
             (DatabaseType::PostgresSQL, Ok(PacketType::Query)) => {
-                Ok(String::from_utf8(self.bytes[5..].to_vec()).expect("Invalid UTF-8"))
+                if self.bytes.len() < 5 {
+                    return Err(Error::other("Query packet too short"));
+                }
+                // length includes itself, but not the message type byte
+                let length = BigEndian::read_u32(&self.bytes[1..5]) as usize;
+                let end = std::cmp::min(1 + length, self.bytes.len());
+                if end < 5 {
+                    return Err(Error::other("Invalid query packet length"));
+                }
+                let query = &self.bytes[5..end];
+                Ok(match query.iter().position(|b| *b == 0) {
+                    Some(i) => &query[..i],
+                    None => query,
+                })
+            }
+            _ => Err(Error::other("Packet is not a query")),
+        }
+    }
+
+    /// Returns the fingerprint of a query, see `fingerprint::fingerprint`.
+    /// Returns an error if the packet is not a query. Invalid UTF-8 is usually inside a string,
+    /// which the fingerprint replaces anyway, so this goes through `get_query_lossy`
+    pub fn get_query_fingerprint(&self) -> Result<String, Error> {
+        self.get_query_lossy()
+            .map(|query| fingerprint::fingerprint(self.db_type, &query))
+    }
+
+    /// Returns the database name of a MariaDB COM_INIT_DB, the rest of its payload.
+    /// Returns an error for other packets or if the name is not valid UTF-8
+    pub fn get_init_db(&self) -> Result<String, Error> {
+        match (self.db_type, self.get_packet_type()) {
+            (DatabaseType::MariaDB, Ok(PacketType::ComInitDb)) => {
+                String::from_utf8(self.bytes[5..].to_vec())
+                    .map_err(|_e| Error::other("Database name is not valid UTF-8"))
             }
-            _ => Err(Error::new(ErrorKind::Other, "Packet is not a query")),
+            _ => Err(Error::other("Packet is not a COM_INIT_DB")),
         }
     }
 
+    /// Returns the sequence id from the 4-byte MariaDB header
     pub fn get_sequence_id(&self) -> Result<u8, Error> {
         match self.db_type {
+            DatabaseType::MariaDB if self.bytes.len() < 4 => {
+                Err(Error::other("MariaDB packet too short for a sequence ID"))
+            }
             DatabaseType::MariaDB => Ok(self.bytes[3]),
-            DatabaseType::PostgresSQL => Err(Error::new(
-                ErrorKind::Other,
-                "PostgresSQL does not use sequence IDs",
-            )),
+            DatabaseType::PostgresSQL => Err(Error::other("PostgresSQL does not use sequence IDs")),
+        }
+    }
+
+    /// Overwrites the sequence id in the 4-byte MariaDB header.
+    /// Handlers that rewrite or synthesize packets must keep sequence ids monotonic
+    pub fn set_sequence_id(&mut self, id: u8) -> Result<(), Error> {
+        match self.db_type {
+            DatabaseType::MariaDB if self.bytes.len() < 4 => {
+                Err(Error::other("MariaDB packet too short for a sequence ID"))
+            }
+            DatabaseType::MariaDB => {
+                // Bytes is immutable, so this copies
+                let mut bytes = BytesMut::from(&self.bytes[..]);
+                bytes[3] = id;
+                self.bytes = bytes.freeze();
+                Ok(())
+            }
+            DatabaseType::PostgresSQL => Err(Error::other("PostgresSQL does not use sequence IDs")),
+        }
+    }
+
+    /// Returns the capability flags a MariaDB database offers in its initial handshake,
+    /// the first packet it sends. The lower 16 bits follow the auth data and filler,
+    /// the upper 16 bits follow the charset and status flags, and are 0 if absent
+    /// https://mariadb.com/kb/en/connection/#initial-handshake-packet
+    pub fn get_handshake_capabilities(&self) -> Result<u32, Error> {
+        let (lower, upper) = self.handshake_capability_offsets()?;
+        let mut capabilities = LittleEndian::read_u16(&self.bytes[lower..(lower + 2)]) as u32;
+        if let Some(upper) = upper {
+            capabilities |= (LittleEndian::read_u16(&self.bytes[upper..(upper + 2)]) as u32) << 16;
+        }
+        Ok(capabilities)
+    }
+
+    /// Overwrites the capability flags of a MariaDB initial handshake,
+    /// see `get_handshake_capabilities`
+    pub fn set_handshake_capabilities(&mut self, capabilities: u32) -> Result<(), Error> {
+        let (lower, upper) = self.handshake_capability_offsets()?;
+        if upper.is_none() && capabilities >> 16 != 0 {
+            return Err(Error::other(
+                "Initial handshake has no upper capability flags",
+            ));
+        }
+        // Bytes is immutable, so this copies
+        let mut bytes = BytesMut::from(&self.bytes[..]);
+        LittleEndian::write_u16(&mut bytes[lower..(lower + 2)], capabilities as u16);
+        if let Some(upper) = upper {
+            LittleEndian::write_u16(&mut bytes[upper..(upper + 2)], (capabilities >> 16) as u16);
         }
+        self.bytes = bytes.freeze();
+        Ok(())
+    }
+
+    /// Clears `flags` in a MariaDB initial handshake, e.g. `CLIENT_SSL` so that clients
+    /// stay in plaintext. The packet is unchanged on error
+    pub fn clear_handshake_capabilities(&mut self, flags: u32) -> Result<(), Error> {
+        let capabilities = self.get_handshake_capabilities()?;
+        self.set_handshake_capabilities(capabilities & !flags)
+    }
+
+    /// Returns the auth plugin name of a MariaDB initial handshake (the database's first
+    /// packet) or handshake response (the client's first packet after any SSLRequest),
+    /// e.g. `mysql_native_password`. Call it on those packets only: a handshake response
+    /// cannot be told apart from any other client packet with sequence id 1 or more.
+    ///
+    /// Only protocol version 10 (MySQL 4.1 and later) handshakes name a plugin, and only when
+    /// the capability `CLIENT_PLUGIN_AUTH` is set. Older clients and databases return `None`,
+    /// and authenticate as `mysql_native_password`, or `mysql_old_password` under version 9.
+    /// The database can still switch plugins afterwards with an AuthSwitchRequest
+    /// https://mariadb.com/kb/en/connection/#initial-handshake-packet
+    pub fn get_mariadb_auth_plugin(&self) -> Option<String> {
+        if self.db_type != DatabaseType::MariaDB || self.bytes.len() < 5 {
+            return None;
+        }
+        if self.bytes[3] != 0 {
+            let response = parse_handshake_response(&self.bytes[..]);
+            return response.auth_plugin;
+        }
+        let capabilities = self.get_handshake_capabilities().ok()?;
+        let (_lower, upper) = self.handshake_capability_offsets().ok()?;
+        if capabilities & CLIENT_PLUGIN_AUTH == 0 {
+            return None;
+        }
+        // The length of the auth data, then 10 reserved bytes
+        let auth_data_len = *self.bytes.get(upper? + 2)? as usize;
+        let mut i = upper? + 2 + 1 + 10;
+        if capabilities & CLIENT_SECURE_CONNECTION != 0 {
+            // The rest of the auth data, at least 13 bytes including a null byte
+            i += std::cmp::max(13, auth_data_len.saturating_sub(8));
+        }
+        // Some versions of MySQL leave out the final null byte
+        read_null_terminated(&self.bytes[..], &mut i)
+    }
+
+    /// Offsets of the lower and upper capability flags of a MariaDB initial handshake.
+    /// The protocol version is followed by the null-terminated server version,
+    /// a 4-byte connection id, 8 bytes of auth data and a filler byte
+    fn handshake_capability_offsets(&self) -> Result<(usize, Option<usize>), Error> {
+        let version_end = match self.db_type {
+            DatabaseType::MariaDB if self.bytes.len() > 5 && self.bytes[4] == 0x0a => self
+                .bytes
+                .iter()
+                .skip(5)
+                .position(|b| *b == 0)
+                .map(|i| 5 + i),
+            _ => return Err(Error::other("Packet is not a MariaDB initial handshake")),
+        };
+        let lower = match version_end {
+            Some(i) if self.bytes.len() >= i + 1 + 4 + 8 + 1 + 2 => i + 1 + 4 + 8 + 1,
+            _ => return Err(Error::other("Initial handshake packet too short")),
+        };
+        // After the charset and status flags
+        let upper = lower + 2 + 1 + 2;
+        let upper = if self.bytes.len() >= upper + 2 {
+            Some(upper)
+        } else {
+            None
+        };
+        Ok((lower, upper))
+    }
+
+    /// Returns the protocol version and parameters of a PostgresSQL StartupMessage
+    /// https://www.postgresql.org/docs/12/protocol-message-formats.html
+    pub fn get_postgres_startup(&self) -> Result<StartupParams, Error> {
+        if self.db_type != DatabaseType::PostgresSQL
+            || self.get_packet_type()? != PacketType::StartupMessage
+        {
+            return Err(Error::other("Packet is not a StartupMessage"));
+        }
+        let length = self.startup_length()?;
+        let mut params = StartupParams {
+            major_version: BigEndian::read_u16(&self.bytes[4..6]),
+            minor_version: BigEndian::read_u16(&self.bytes[6..8]),
+            parameters: Vec::new(),
+        };
+        // name/value pairs of null-terminated strings, ending with an empty name
+        let mut fields = self.bytes[8..length]
+            .split(|b| *b == 0)
+            .map(|f| String::from_utf8_lossy(f).into_owned());
+        while let Some(name) = fields.next() {
+            if name.is_empty() {
+                break;
+            }
+            let value = fields.next().unwrap_or_default();
+            params.parameters.push((name, value));
+        }
+        Ok(params)
+    }
+
+    /// The declared length of a StartupMessage, up to the bytes there are.
+    /// Its parameters follow the 8 bytes of length and protocol version
+    fn startup_length(&self) -> Result<usize, Error> {
+        let length = std::cmp::min(
+            BigEndian::read_u32(&self.bytes[0..4]) as usize,
+            self.bytes.len(),
+        );
+        if length < 8 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "StartupMessage length {} is shorter than its header",
+                    length
+                ),
+            ));
+        }
+        Ok(length)
+    }
+
+    /// Sets a parameter of a PostgresSQL StartupMessage, e.g. to map a tenant's `user`
+    /// to a real role, adding it if missing. The length prefix is recomputed
+    pub fn set_postgres_startup_param(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        // Validates the packet, including its length
+        self.get_postgres_startup()?;
+        if key.is_empty() || key.contains('\0') || value.contains('\0') {
+            return Err(Error::other(
+                "Startup parameters can't be empty or contain null bytes",
+            ));
+        }
+        let length = self.startup_length()?;
+        let mut bytes: Vec<u8> = Vec::with_capacity(length + key.len() + value.len() + 2);
+        bytes.extend_from_slice(&self.bytes[0..8]);
+        let mut found = false;
+        let mut fields = self.bytes[8..length].split(|b| *b == 0);
+        while let Some(name) = fields.next() {
+            if name.is_empty() {
+                break;
+            }
+            let old_value = fields.next().unwrap_or_default();
+            bytes.extend_from_slice(name);
+            bytes.push(0);
+            if name == key.as_bytes() {
+                found = true;
+                bytes.extend_from_slice(value.as_bytes());
+            } else {
+                bytes.extend_from_slice(old_value);
+            }
+            bytes.push(0);
+        }
+        if !found {
+            bytes.extend_from_slice(key.as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        bytes.push(0);
+        let length = bytes.len() as u32;
+        BigEndian::write_u32(&mut bytes[0..4], length);
+        self.bytes = bytes.into();
+        Ok(())
+    }
+
+    /// Decodes a COM_STMT_PREPARE_OK, the response to a successful COM_STMT_PREPARE.
+    /// Only the first packet of the response is one, and it can't be told apart from an OK,
+    /// so check that the request was a ComStmtPrepare.
+    /// https://mariadb.com/kb/en/com_stmt_prepare/#com_stmt_prepare_ok
+    pub fn get_stmt_prepare_ok(&self) -> Option<StmtPrepareOk> {
+        if self.db_type != DatabaseType::MariaDB || self.bytes.len() < 4 + 12 || self.bytes[4] != 0
+        {
+            return None;
+        }
+        Some(StmtPrepareOk {
+            statement_id: LittleEndian::read_u32(&self.bytes[5..9]),
+            num_columns: LittleEndian::read_u16(&self.bytes[9..11]),
+            num_params: LittleEndian::read_u16(&self.bytes[11..13]),
+            // bytes[13] is reserved
+            warnings: LittleEndian::read_u16(&self.bytes[14..16]),
+        })
+    }
+
+    /// Returns the statement id of a MariaDB COM_STMT_EXECUTE, see `get_stmt_prepare_ok`
+    /// https://mariadb.com/kb/en/com_stmt_execute/
+    pub fn get_stmt_execute_id(&self) -> Option<u32> {
+        match (self.db_type, self.get_packet_type()) {
+            (DatabaseType::MariaDB, Ok(PacketType::ComStmtExecute)) if self.bytes.len() >= 9 => {
+                Some(LittleEndian::read_u32(&self.bytes[5..9]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Decodes a PostgresSQL Parse ('P'), which prepares the SQL of the extended query protocol.
+    /// `None` for any other message, or a truncated one
+    /// https://www.postgresql.org/docs/12/protocol-message-formats.html
+    pub fn get_postgres_parse(&self) -> Option<PostgresParse> {
+        if self.db_type != DatabaseType::PostgresSQL
+            || self.get_packet_type().ok()? != PacketType::Parse
+        {
+            return None;
+        }
+        let length = BigEndian::read_u32(self.bytes.get(1..5)?) as usize;
+        let payload = self
+            .bytes
+            .get(5..std::cmp::min(1 + length, self.bytes.len()))?;
+        let mut fields = payload.splitn(3, |b| *b == 0);
+        let statement_name = String::from_utf8_lossy(fields.next()?).into_owned();
+        let query = String::from_utf8_lossy(fields.next()?).into_owned();
+        let rest = fields.next()?;
+        let num_params = BigEndian::read_u16(rest.get(0..2)?) as usize;
+        let param_types = rest
+            .get(2..(2 + 4 * num_params))?
+            .chunks(4)
+            .map(BigEndian::read_u32)
+            .collect();
+        Some(PostgresParse {
+            statement_name,
+            query,
+            param_types,
+        })
+    }
+
+    /// Returns the error code and message of a MariaDB ERR packet
+    /// https://mariadb.com/kb/en/err_packet/
+    pub fn get_mariadb_error(&self) -> Result<(u16, String), Error> {
+        if self.db_type != DatabaseType::MariaDB
+            || self.get_response_type()? != PacketType::ComErr
+            || self.bytes.len() < 7
+        {
+            return Err(Error::other("Packet is not a MariaDB error"));
+        }
+        let code = LittleEndian::read_u16(&self.bytes[5..7]);
+        let mut msg = &self.bytes[7..];
+        // skip sql_state_marker and SQL STATE, if present
+        if msg.len() >= 6 && msg[0] == b'#' {
+            msg = &msg[6..];
+        }
+        Ok((code, String::from_utf8_lossy(msg).into_owned()))
+    }
+
+    /// Returns the fields of a PostgresSQL ErrorResponse ('E') as (type, value) pairs in order,
+    /// e.g. ('S', "ERROR"), ('C', "42P01"), ('M', "relation \"t\" does not exist").
+    /// `None` for any other message, or a truncated one
+    /// https://www.postgresql.org/docs/12/protocol-error-fields.html
+    pub fn get_postgres_error(&self) -> Option<Vec<(char, String)>> {
+        if self.db_type != DatabaseType::PostgresSQL
+            || self.bytes.len() < 5
+            || self.bytes[0] != b'E'
+        {
+            return None;
+        }
+        let mut fields = Vec::new();
+        let mut rest = &self.bytes[5..];
+        // The fields end with a single null byte
+        while let Some((&field_type, value)) = rest.split_first() {
+            if field_type == 0 {
+                return Some(fields);
+            }
+            let end = value.iter().position(|&b| b == 0)?;
+            fields.push((
+                field_type as char,
+                String::from_utf8_lossy(&value[..end]).into_owned(),
+            ));
+            rest = &value[(end + 1)..];
+        }
+        None
+    }
+
+    /// Determine the type of a packet sent by the database.
+    /// MariaDB responses reuse command bytes, so they are classified as
+    /// OK (0x00), ERR (0xff) or EOF (0xfe with a payload shorter than 9 bytes).
+    /// The initial handshake can only be told apart by being the first packet,
+    /// see `get_response_type_with_handshake`
+    pub fn get_response_type(&self) -> Result<PacketType, Error> {
+        match self.db_type {
+            // https://mariadb.com/kb/en/result-set-packets/
+            DatabaseType::MariaDB => {
+                if self.bytes.len() < 5 {
+                    return Err(Error::other(
+                        "Invalid packet type: MariaDB packet too short",
+                    ));
+                }
+                match self.bytes[4] {
+                    0x00 => Ok(PacketType::ComOk),
+                    0xff => Ok(PacketType::ComErr),
+                    0xfe if self.bytes.len() - 4 < 9 => Ok(PacketType::ComEof),
+                    b => Ok(PacketType::ComUnknown(b)),
+                }
+            }
+            DatabaseType::PostgresSQL => self.get_packet_type(),
+        }
+    }
+
+    /// Like `get_response_type`, but a MariaDB packet with sequence id 0 and protocol version
+    /// 10 (0x0a) is the initial handshake while `handshake_seen` is false.
+    /// Pipes keep the flag per connection, see `PacketContext::handshake_seen`
+    pub fn get_response_type_with_handshake(
+        &self,
+        handshake_seen: bool,
+    ) -> Result<PacketType, Error> {
+        let is_handshake = self.db_type == DatabaseType::MariaDB
+            && !handshake_seen
+            && self.bytes.len() > 4
+            && self.bytes[3] == 0
+            && self.bytes[4] == 0x0a;
+        if is_handshake {
+            return Ok(PacketType::Handshake);
+        }
+        self.get_response_type()
     }
 
     /// Determine the type of packet
@@ -76,60 +643,62 @@ impl Packet {
         match self.db_type {
             // https://dev.mysql.com/doc/internals/en/mysql-packet.html
             // https://dev.mysql.com/doc/internals/en/text-protocol.html
-            DatabaseType::MariaDB => match self.bytes[4] {
-                0x00 => Ok(PacketType::ComSleep),
-                0x01 => Ok(PacketType::ComQuit),
-                0x02 => Ok(PacketType::ComInitDb),
-                0x03 => Ok(PacketType::ComQuery),
-                0x04 => Ok(PacketType::ComFieldList),
-                0x05 => Ok(PacketType::ComCreateDb),
-                0x06 => Ok(PacketType::ComDropDb),
-                0x07 => Ok(PacketType::ComRefresh),
-                0x08 => Ok(PacketType::ComShutdown),
-                0x09 => Ok(PacketType::ComStatistics),
-                0x0a => Ok(PacketType::ComProcessInfo),
-                0x0b => Ok(PacketType::ComConnect),
-                0x0c => Ok(PacketType::ComProcessKill),
-                0x0d => Ok(PacketType::ComDebug),
-                0x0e => Ok(PacketType::ComPing),
-                0x0f => Ok(PacketType::ComTime),
-                0x10 => Ok(PacketType::ComDelayedInsert),
-                0x11 => Ok(PacketType::ComChangeUser),
-                0x12 => Ok(PacketType::ComBinlogDump),
-                0x13 => Ok(PacketType::ComTableDump),
-                0x14 => Ok(PacketType::ComConnectOut),
-                0x15 => Ok(PacketType::ComRegisterSlave),
-                0x16 => Ok(PacketType::ComStmtPrepare),
-                0x17 => Ok(PacketType::ComStmtExecute),
-                0x18 => Ok(PacketType::ComStmtSendLongData),
-                0x19 => Ok(PacketType::ComStmtClose),
-                0x1a => Ok(PacketType::ComStmtReset),
-                0x1b => Ok(PacketType::ComSetOption),
-                0x1c => Ok(PacketType::ComStmtFetch),
-                0x1d => Ok(PacketType::ComDaemon),
-                0x1e => Ok(PacketType::ComBinlogDumpGtid),
-                0x1f => Ok(PacketType::ComResetConnection),
-
-                0x8f => Ok(PacketType::ComUnknown), // TODO: fix
-                0x8c => Ok(PacketType::ComUnknown), // TODO: fix
-                0xfc => Ok(PacketType::ComUnknown), // TODO: fix
-                0xfd => Ok(PacketType::ComUnknown), // TODO: fix
-
-                0xfe => Ok(PacketType::ComEof),
-                0xff => Ok(PacketType::ComErr),
-                _ => Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Invalid packet type {:#04x}", self.bytes[4]),
-                )),
-            },
+            DatabaseType::MariaDB => {
+                if self.bytes.len() < 5 {
+                    return Err(Error::other(
+                        "Invalid packet type: MariaDB packet too short",
+                    ));
+                }
+                match self.bytes[4] {
+                    0x00 => Ok(PacketType::ComSleep),
+                    0x01 => Ok(PacketType::ComQuit),
+                    0x02 => Ok(PacketType::ComInitDb),
+                    0x03 => Ok(PacketType::ComQuery),
+                    0x04 => Ok(PacketType::ComFieldList),
+                    0x05 => Ok(PacketType::ComCreateDb),
+                    0x06 => Ok(PacketType::ComDropDb),
+                    0x07 => Ok(PacketType::ComRefresh),
+                    0x08 => Ok(PacketType::ComShutdown),
+                    0x09 => Ok(PacketType::ComStatistics),
+                    0x0a => Ok(PacketType::ComProcessInfo),
+                    0x0b => Ok(PacketType::ComConnect),
+                    0x0c => Ok(PacketType::ComProcessKill),
+                    0x0d => Ok(PacketType::ComDebug),
+                    0x0e => Ok(PacketType::ComPing),
+                    0x0f => Ok(PacketType::ComTime),
+                    0x10 => Ok(PacketType::ComDelayedInsert),
+                    0x11 => Ok(PacketType::ComChangeUser),
+                    0x12 => Ok(PacketType::ComBinlogDump),
+                    0x13 => Ok(PacketType::ComTableDump),
+                    0x14 => Ok(PacketType::ComConnectOut),
+                    0x15 => Ok(PacketType::ComRegisterSlave),
+                    0x16 => Ok(PacketType::ComStmtPrepare),
+                    0x17 => Ok(PacketType::ComStmtExecute),
+                    0x18 => Ok(PacketType::ComStmtSendLongData),
+                    0x19 => Ok(PacketType::ComStmtClose),
+                    0x1a => Ok(PacketType::ComStmtReset),
+                    0x1b => Ok(PacketType::ComSetOption),
+                    0x1c => Ok(PacketType::ComStmtFetch),
+                    0x1d => Ok(PacketType::ComDaemon),
+                    0x1e => Ok(PacketType::ComBinlogDumpGtid),
+                    0x1f => Ok(PacketType::ComResetConnection),
+
+                    0xfe => Ok(PacketType::ComEof),
+                    0xff => Ok(PacketType::ComErr),
+                    // Responses (e.g. result set rows) and unrecognized commands
+                    b => Ok(PacketType::ComUnknown(b)),
+                }
+            }
 
             // https://www.postgresql.org/docs/12/protocol-message-types.html
             // https://www.postgresql.org/docs/12/protocol-message-formats.html
+            DatabaseType::PostgresSQL if self.bytes.is_empty() => Err(Error::other(
+                "Invalid packet type: PostgresSQL packet is empty",
+            )),
             DatabaseType::PostgresSQL => match self.bytes[0] as char {
                 'R' => {
                     if self.bytes.len() < 9 {
-                        return Err(Error::new(
-                            ErrorKind::Other,
+                        return Err(Error::other(
                             "Invalid packet type: Authentication Packet too short",
                         ));
                     }
@@ -147,8 +716,7 @@ impl Packet {
                         (_, 10) => Ok(PacketType::AuthenticationSASL),
                         (_, 11) => Ok(PacketType::AuthenticationSASLContinue),
                         (_, 12) => Ok(PacketType::AuthenticationSASLFinal),
-                        _ => Err(Error::new(
-                            ErrorKind::Other,
+                        _ => Err(Error::other(
                             "Invalid packet type: Authentication Packet unrecognized",
                         )),
                     }
@@ -159,8 +727,7 @@ impl Packet {
                 '3' => Ok(PacketType::CloseComplete),
                 'C' => {
                     if self.bytes.len() < 6 {
-                        Err(Error::new(
-                            ErrorKind::Other,
+                        Err(Error::other(
                             "Invalid packet type: Close/CommandComplete packet too short",
                         ))
                     } else if self.bytes[5] as char == 'S' || self.bytes[5] as char == 'P' {
@@ -175,8 +742,7 @@ impl Packet {
                 'G' => Ok(PacketType::CopyInResponse),
                 'H' => {
                     if self.bytes.len() < 5 {
-                        return Err(Error::new(
-                            ErrorKind::Other,
+                        return Err(Error::other(
                             "Invalid packet type: Authentication Packet too short",
                         ));
                     }
@@ -190,8 +756,7 @@ impl Packet {
                 'W' => Ok(PacketType::CopyBothResponse),
                 'D' => {
                     if self.bytes.len() < 6 {
-                        Err(Error::new(
-                            ErrorKind::Other,
+                        Err(Error::other(
                             "Invalid packet type: DataRow/Describe packet too short",
                         ))
                     } else if self.bytes[5] as char == 'S' || self.bytes[5] as char == 'P' {
@@ -203,13 +768,11 @@ impl Packet {
                 'I' => Ok(PacketType::EmptyQueryResponse),
                 'E' => {
                     if self.bytes.len() < 6 {
-                        Err(Error::new(
-                            ErrorKind::Other,
+                        Err(Error::other(
                             "Invalid packet type: Execute/ErrorResponse packet too short",
                         ))
                     // https://www.postgresql.org/docs/12/protocol-error-fields.html
                     } else if self.bytes[5] as char == 'S'
-                        || self.bytes[5] as char == 'V'
                         || self.bytes[5] as char == 'V'
                         || self.bytes[5] as char == 'C'
                         || self.bytes[5] as char == 'M'
@@ -227,7 +790,6 @@ impl Packet {
                         || self.bytes[5] as char == 'F'
                         || self.bytes[5] as char == 'L'
                         || self.bytes[5] as char == 'R'
-                        || self.bytes[5] as char == 'P'
                     {
                         Ok(PacketType::ErrorResponse)
                     } else {
@@ -244,8 +806,7 @@ impl Packet {
                 't' => Ok(PacketType::ParameterDescription),
                 'S' => {
                     if self.bytes.len() < 5 {
-                        return Err(Error::new(
-                            ErrorKind::Other,
+                        return Err(Error::other(
                             "Invalid packet type: Sync/ParameterStatus Packet too short",
                         ));
                     }
@@ -265,8 +826,7 @@ impl Packet {
                 'X' => Ok(PacketType::Terminate),
                 _ => {
                     if self.bytes.len() < 8 {
-                        return Err(Error::new(
-                            ErrorKind::Other,
+                        return Err(Error::other(
                             "Invalid packet type: Default packet too short",
                         ));
                     }
@@ -277,7 +837,7 @@ impl Packet {
                         (8, 80_877_103) => Ok(PacketType::SSLRequest),
                         (8, 80_877_104) => Ok(PacketType::GSSENCRequest),
                         (_, 196_608) => Ok(PacketType::StartupMessage),
-                        _ => Err(Error::new(ErrorKind::Other, "Invalid packet type")),
+                        _ => Err(Error::other("Invalid packet type")),
                     }
                 }
             }, // end match packet_type
@@ -285,17 +845,341 @@ impl Packet {
     } // end fn
 }
 
+/// e.g. `MariaDB ComQuery packet, 13 bytes`. MariaDB responses are shown as the
+/// command sharing their first byte, see `Packet::get_response_type`
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get_packet_type() {
+            Ok(packet_type) => write!(f, "{:?} {:?} packet", self.db_type, packet_type)?,
+            Err(_) => write!(f, "{:?} packet of unknown type", self.db_type)?,
+        }
+        write!(f, ", {} bytes", self.get_size())
+    }
+}
+
+/// Contents of a MariaDB COM_STMT_PREPARE_OK
 #[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StmtPrepareOk {
+    /// Referenced by COM_STMT_EXECUTE, COM_STMT_CLOSE, etc.
+    pub statement_id: u32,
+    /// Column definitions follow the packet, unless zero
+    pub num_columns: u16,
+    /// Parameter definitions follow the packet, unless zero
+    pub num_params: u16,
+    pub warnings: u16,
+}
+
+/// Contents of a PostgresSQL Parse
+#[derive(Clone, Debug, PartialEq)]
+pub struct PostgresParse {
+    /// Referenced by Bind, Describe and Close. Empty for the unnamed statement
+    pub statement_name: String,
+    pub query: String,
+    /// Object ids of the parameter types the client specified, 0 leaves a type unspecified.
+    /// May be fewer than the parameters in the query
+    pub param_types: Vec<u32>,
+}
+
+/// Contents of a PostgresSQL StartupMessage
+#[derive(Clone, Debug, PartialEq)]
+pub struct StartupParams {
+    pub major_version: u16,
+    pub minor_version: u16,
+    /// e.g. ("user", "root"), ("database", "testdb")
+    pub parameters: Vec<(String, String)>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum DatabaseType {
     MariaDB,
     PostgresSQL,
 }
 
+impl DatabaseType {
+    /// Guesses the protocol from the first bytes of a connection, from either side:
+    /// - PostgresSQL clients speak first, with a StartupMessage (protocol 3.0), SSLRequest,
+    ///   GSSENCRequest or CancelRequest, each recognizable from its first 8 bytes
+    /// - MariaDB databases speak first, with a protocol 10 handshake, or an ERR if they
+    ///   refuse the connection
+    ///
+    /// So a MariaDB client, which waits for the handshake, sends nothing to detect,
+    /// and neither does a PostgresSQL database. `None` if there are too few bytes, or
+    /// they match neither. Other protocols can match by accident, e.g. any 8 bytes
+    /// ending in 00 03 00 00 with a plausible length look like a StartupMessage
+    pub fn detect(first_bytes: &[u8]) -> Option<DatabaseType> {
+        if first_bytes.len() >= 8 {
+            let length = BigEndian::read_u32(&first_bytes[0..4]);
+            let code = BigEndian::read_u32(&first_bytes[4..8]);
+            let postgres = match code {
+                80_877_102 => length == 16,
+                80_877_103 | 80_877_104 => length == 8,
+                // The database refuses StartupMessages over 10000 bytes
+                196_608 => (8..=10_000).contains(&length),
+                _ => false,
+            };
+            if postgres {
+                return Some(DatabaseType::PostgresSQL);
+            }
+        }
+        // A first packet from the database has sequence id 0
+        if first_bytes.len() >= 5 && first_bytes[3] == 0 {
+            match first_bytes[4] {
+                0x0a | 0xff => return Some(DatabaseType::MariaDB),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// Parses the names used in configs and on the command line, ignoring case:
+/// `mariadb` or `mysql`, and `postgres`, `postgresql` or `pg`
+impl FromStr for DatabaseType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DatabaseType, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "mariadb" | "mysql" => Ok(DatabaseType::MariaDB),
+            "postgres" | "postgresql" | "pg" => Ok(DatabaseType::PostgresSQL),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown database type: {}", s),
+            )),
+        }
+    }
+}
+
+/// The names `FromStr` parses, `mariadb` or `postgres`
+impl fmt::Display for DatabaseType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatabaseType::MariaDB => write!(f, "mariadb"),
+            DatabaseType::PostgresSQL => write!(f, "postgres"),
+        }
+    }
+}
+
+/// Splits a captured stream of one direction into packets, see `Packet::try_parse`.
+/// Packets are limited to `DEFAULT_MAX_PACKET_SIZE`, and split MariaDB packets are returned
+/// one by one. Stops at the first incomplete, malformed or oversized packet, and returns the
+/// packets along with the
+/// number of bytes they took, so the rest of `bytes` can be kept for when more arrives.
+/// The packets share one copy of `bytes`
+pub fn parse_all(db_type: DatabaseType, bytes: &[u8]) -> (Vec<Packet>, usize) {
+    let mut buf = BytesMut::from(bytes);
+    let mut packets = Vec::new();
+    while let Ok(Some(p)) = Packet::try_parse(db_type, &mut buf, DEFAULT_MAX_PACKET_SIZE, false) {
+        packets.push(p);
+    }
+    (packets, bytes.len() - buf.len())
+}
+
+/// Default for `PipeOptions::max_packet_size`, MySQL's default `max_allowed_packet`
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+/// Payload length of a MariaDB packet that is continued by the next packet,
+/// see `Packet::is_mariadb_continuation`
+pub const MARIADB_MAX_PAYLOAD: usize = 0xff_ffff;
+
+/// MariaDB capability flags, see `Packet::get_handshake_capabilities`
+/// https://mariadb.com/kb/en/connection/#capabilities
+pub const CLIENT_COMPRESS: u32 = 0x0020;
+/// Set in the handshake response of a client that sends an SSLRequest first
+pub const CLIENT_SSL: u32 = 0x0800;
+
+/// Every PostgresSQL (protocol 3.0) message type byte, frontend and backend.
+/// Messages without one of these are only valid if they are a StartupMessage,
+/// SSLRequest, CancelRequest or GSSENCRequest
 pub const POSTGRES_IDS: [char; 31] = [
     'R', 'K', 'B', '2', '3', 'C', 'd', 'c', 'f', 'G', 'H', 'W', 'D', 'I', 'E', 'F', 'V', 'p', 'v',
     'n', 'N', 'A', 't', 'S', 'P', '1', 's', 'Q', 'Z', 'T', 'X',
 ];
-#[derive(Copy, Clone, Debug)]
+
+/// Why a packet couldn't be framed, after which there is no telling where the next one starts
+#[derive(Debug, PartialEq)]
+pub(crate) enum FramingError {
+    /// The packet, header included, would be larger than the limit
+    TooLarge { size: usize, max_packet_size: usize },
+    /// Something that is not a packet
+    Malformed(String),
+}
+
+impl From<FramingError> for Error {
+    fn from(e: FramingError) -> Error {
+        let msg = match e {
+            FramingError::TooLarge {
+                size,
+                max_packet_size,
+            } => format!(
+                "Packet of {} bytes exceeds max_packet_size of {} bytes",
+                size, max_packet_size
+            ),
+            FramingError::Malformed(msg) => msg,
+        };
+        Error::new(ErrorKind::InvalidData, msg)
+    }
+}
+
+fn mariadb_payload_length(header: &[u8]) -> usize {
+    (((header[2] as u32) << 16) | ((header[1] as u32) << 8) | header[0] as u32) as usize
+}
+
+fn check_packet_size(s: usize, max_packet_size: usize) -> Result<(), FramingError> {
+    if s > max_packet_size {
+        return Err(FramingError::TooLarge {
+            size: s,
+            max_packet_size,
+        });
+    }
+    Ok(())
+}
+
+/// Whether the length and request code of a message without a type byte
+/// belong to a StartupMessage (protocol 3.x), SSLRequest, CancelRequest or GSSENCRequest
+fn is_postgres_typeless_code(header: &[u8]) -> bool {
+    let length = BigEndian::read_u32(&header[0..4]);
+    let code = BigEndian::read_u32(&header[4..8]);
+    match code {
+        80_877_102 => length == 16,
+        80_877_103 | 80_877_104 => length == 8,
+        _ => code >> 16 == 3 && length >= 8,
+    }
+}
+
+fn invalid_postgres_message(first_byte: u8) -> FramingError {
+    FramingError::Malformed(format!(
+        "Invalid PostgresSQL message, firstbyte={:#04x} is not a message type",
+        first_byte
+    ))
+}
+
+/// Splits the first packet off `packet_buf`, see `Packet::try_parse`
+pub(crate) fn get_packet(
+    db_type: DatabaseType,
+    packet_buf: &mut BytesMut,
+    max_packet_size: usize,
+    reassemble: bool,
+) -> Result<Option<Packet>, FramingError> {
+    match db_type {
+        DatabaseType::MariaDB if reassemble => {
+            // Walk the headers until a packet that isn't continued
+            let mut offset = 0;
+            let mut payload_length = 0;
+            loop {
+                // Check for header
+                if packet_buf.len() < offset + 4 {
+                    return Ok(None);
+                }
+                let l = mariadb_payload_length(&packet_buf[offset..]);
+                payload_length += l;
+                // Refuse to buffer packets that are too large
+                check_packet_size(4 + payload_length, max_packet_size)?;
+                offset += 4 + l;
+                // Check for entire packet size
+                if packet_buf.len() < offset {
+                    return Ok(None);
+                }
+                if l < MARIADB_MAX_PAYLOAD {
+                    break;
+                }
+            }
+            // Keep the first header, and strip the headers of continuation packets
+            let raw = packet_buf.split_to(offset);
+            let mut bytes: Vec<u8> = Vec::with_capacity(4 + payload_length);
+            bytes.extend_from_slice(&raw[0..4]);
+            let mut i = 0;
+            while i < raw.len() {
+                let l = mariadb_payload_length(&raw[i..]);
+                bytes.extend_from_slice(&raw[(i + 4)..(i + 4 + l)]);
+                i += 4 + l;
+            }
+            Ok(Some(Packet::new(DatabaseType::MariaDB, bytes)))
+        }
+        DatabaseType::MariaDB => {
+            // Check for header
+            if packet_buf.len() < 4 {
+                return Ok(None);
+            }
+            let l = mariadb_payload_length(&packet_buf[..]);
+            let s = 4 + l;
+            // Refuse to buffer packets that are too large
+            check_packet_size(s, max_packet_size)?;
+            // Check for entire packet size
+            if packet_buf.len() < s {
+                return Ok(None);
+            }
+            Ok(Some(Packet::new(
+                DatabaseType::MariaDB,
+                packet_buf.split_to(s).freeze(),
+            )))
+        } // end MariaDB
+        DatabaseType::PostgresSQL => {
+            // Nothing in packet_buf
+            if packet_buf.is_empty() {
+                trace!(
+                    "get_packet(PostgresSQL): FAIL packet_buf(size={}) trying to read first byte",
+                    packet_buf.len()
+                );
+                return Ok(None);
+            }
+            let id = packet_buf[0] as char;
+            let mut size = 0;
+            if POSTGRES_IDS.contains(&id) {
+                size += 1;
+            } else if packet_buf[0] != 0 {
+                // Typeless messages start with a length, which is never this large
+                return Err(invalid_postgres_message(packet_buf[0]));
+            } else if packet_buf.len() >= 8 && !is_postgres_typeless_code(&packet_buf[0..8]) {
+                return Err(invalid_postgres_message(packet_buf[0]));
+            }
+
+            // Check if I can read the length field
+            if packet_buf.len() < (size + 4) {
+                trace!(
+                    "get_packet(PostgresSQL): FAIL packet_buf(size={}) trying to read length, firstbyte={:#04x}={}, size={}",
+                    packet_buf.len(), packet_buf[0], id, size+4
+                );
+                return Ok(None);
+            }
+            let length = BigEndian::read_u32(&packet_buf[size..(size + 4)]) as usize; // read length
+            if length < 4 {
+                return Err(FramingError::Malformed(format!(
+                    "Invalid PostgresSQL message length {}, firstbyte={:#04x}",
+                    length, packet_buf[0]
+                )));
+            }
+            size += length;
+            // Refuse to buffer packets that are too large
+            check_packet_size(size, max_packet_size)?;
+
+            // Check if don't have entire packet
+            if packet_buf.len() < size {
+                trace!(
+                    "get_packet(PostgresSQL): FAIL packet_buf(size={}) too small, firstbyte={:#04x}={}, size={}, length={}",
+                    packet_buf.len(), packet_buf[0], id, size, length
+                );
+                return Ok(None);
+            }
+            trace!(
+                "get_packet(PostgresSQL): SUCCESS firstbyte={:#04x}={}, size={}, length={}",
+                packet_buf[0],
+                id,
+                size,
+                length
+            );
+
+            Ok(Some(Packet::new(
+                DatabaseType::PostgresSQL,
+                packet_buf.split_to(size).freeze(),
+            )))
+        } // end PostgresSQL
+    } // end match
+} // end get_packet
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u16)]
 pub enum PacketType {
     // MariaDB
     ComSleep = 0x00,
@@ -315,6 +1199,9 @@ pub enum PacketType {
     ComPing = 0x0e,
     ComTime = 0x0f,
     ComDelayedInsert = 0x10,
+    /// Re-authenticates as another user and resets the session, e.g. when a pool reuses a
+    /// connection. Like the handshake, it may be followed by an AuthSwitchRequest
+    /// renegotiating the auth plugin
     ComChangeUser = 0x11,
     ComBinlogDump = 0x12,
     ComTableDump = 0x13,
@@ -332,7 +1219,10 @@ pub enum PacketType {
     ComResetConnection = 0x1f,
     ComEof = 0xfe,
     ComErr = 0xff,
-    ComUnknown,
+    ComUnknown(u8),
+    ComOk,
+    /// The first packet from the database, see `Packet::get_response_type_with_handshake`
+    Handshake,
 
     //PostgresSQL
     AuthenticationOk,
@@ -390,3 +1280,596 @@ pub enum PacketType {
     Sync,
     Terminate,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mariadb_command_types() {
+        let query = Packet::new(
+            DatabaseType::MariaDB,
+            vec![4, 0, 0, 0, 0x03, b'S', b'E', b'L'],
+        );
+        assert_eq!(query.get_packet_type().unwrap(), PacketType::ComQuery);
+        let prepare = Packet::new(DatabaseType::MariaDB, vec![1, 0, 0, 0, 0x16]);
+        assert_eq!(
+            prepare.get_packet_type().unwrap(),
+            PacketType::ComStmtPrepare
+        );
+        let unknown = Packet::new(DatabaseType::MariaDB, vec![1, 0, 0, 0, 0x8c]);
+        assert_eq!(
+            unknown.get_packet_type().unwrap(),
+            PacketType::ComUnknown(0x8c)
+        );
+        let short = Packet::new(DatabaseType::MariaDB, vec![0, 0, 0, 0]);
+        assert!(short.get_packet_type().is_err());
+    }
+
+    #[test]
+    fn mariadb_response_types() {
+        let ok = Packet::new(
+            DatabaseType::MariaDB,
+            vec![7, 0, 0, 1, 0x00, 0, 0, 2, 0, 0, 0],
+        );
+        assert_eq!(ok.get_response_type().unwrap(), PacketType::ComOk);
+        let err = Packet::error_packet_mariadb(1064, *b"42000", "Syntax error".to_string());
+        assert_eq!(err.get_response_type().unwrap(), PacketType::ComErr);
+        let eof = Packet::new(DatabaseType::MariaDB, vec![5, 0, 0, 5, 0xfe, 0, 0, 2, 0]);
+        assert_eq!(eof.get_response_type().unwrap(), PacketType::ComEof);
+        let row = Packet::new(DatabaseType::MariaDB, vec![2, 0, 0, 3, 0x01, b'1']);
+        assert_eq!(
+            row.get_response_type().unwrap(),
+            PacketType::ComUnknown(0x01)
+        );
+    }
+
+    #[test]
+    fn mariadb_handshake_type() {
+        let handshake = Packet::mariadb(0, b"\x0a5.5.5-10.5.8-MariaDB\0");
+        assert_eq!(
+            handshake.get_response_type_with_handshake(false).unwrap(),
+            PacketType::Handshake
+        );
+        // Only the first packet is the handshake, a later one is e.g. a row
+        assert_eq!(
+            handshake.get_response_type_with_handshake(true).unwrap(),
+            PacketType::ComUnknown(0x0a)
+        );
+        let err = Packet::error_packet_mariadb(1040, *b"08004", "Too many connections".to_string());
+        assert_eq!(
+            err.get_response_type_with_handshake(false).unwrap(),
+            PacketType::ComErr
+        );
+        let row = Packet::mariadb(3, b"\x0a");
+        assert_eq!(
+            row.get_response_type_with_handshake(false).unwrap(),
+            PacketType::ComUnknown(0x0a)
+        );
+    }
+
+    #[test]
+    fn stmt_prepare_ok_fields() {
+        let ok = Packet::mariadb(1, &[0x00, 7, 0, 0, 0, 2, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(
+            ok.get_stmt_prepare_ok(),
+            Some(StmtPrepareOk {
+                statement_id: 7,
+                num_columns: 2,
+                num_params: 1,
+                warnings: 0,
+            })
+        );
+        let err = Packet::error_packet_mariadb(1064, *b"42000", "Syntax error".to_string());
+        assert_eq!(err.get_stmt_prepare_ok(), None);
+        let short = Packet::mariadb(1, &[0x00, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(short.get_stmt_prepare_ok(), None);
+    }
+
+    #[test]
+    fn stmt_execute_id() {
+        // statement id, flags, iteration count
+        let execute = Packet::mariadb(0, &[0x17, 7, 1, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(execute.get_stmt_execute_id(), Some(263));
+        let close = Packet::mariadb(0, &[0x19, 7, 1, 0, 0]);
+        assert_eq!(close.get_stmt_execute_id(), None);
+        assert_eq!(Packet::mariadb(0, &[0x17, 7]).get_stmt_execute_id(), None);
+    }
+
+    #[test]
+    fn get_packet_rejects_oversized_mariadb_packet() {
+        let mut packet_buf = BytesMut::from(&[0xff, 0xff, 0xff, 0x00, 0x03][..]);
+        assert!(get_packet(DatabaseType::MariaDB, &mut packet_buf, 1024, false).is_err());
+    }
+
+    #[test]
+    fn get_packet_rejects_oversized_postgres_message() {
+        // A query claiming to be almost 4 GiB long
+        let mut packet_buf = BytesMut::from(&b"Q\xff\xff\xff\xffSEL"[..]);
+        let e = get_packet(DatabaseType::PostgresSQL, &mut packet_buf, 1024, false).unwrap_err();
+        assert_eq!(
+            e,
+            FramingError::TooLarge {
+                size: 0x1_0000_0000,
+                max_packet_size: 1024
+            }
+        );
+        // Limits apply to the whole message, type byte included
+        let mut packet_buf = BytesMut::from(&b"Q\0\0\0\x08sel\0"[..]);
+        assert!(get_packet(DatabaseType::PostgresSQL, &mut packet_buf, 8, false).is_err());
+    }
+
+    #[test]
+    fn get_packet_reads_postgres_query() {
+        let mut packet_buf = BytesMut::from(&b"Q\0\0\0\x08sel\0Z"[..]);
+        let packet = get_packet(DatabaseType::PostgresSQL, &mut packet_buf, 1024, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.get_packet_type().unwrap(), PacketType::Query);
+        assert_eq!(packet.get_query().unwrap(), "sel");
+        // The next message is incomplete
+        assert_eq!(packet_buf, vec![b'Z']);
+    }
+
+    #[test]
+    fn get_packet_does_not_copy() {
+        let mut packet_buf = BytesMut::from(&[1, 0, 0, 0, 0x0e, 1, 0, 0, 0, 0x0e][..]);
+        let start = packet_buf.as_ptr();
+        let first = get_packet(DatabaseType::MariaDB, &mut packet_buf, 1024, false)
+            .unwrap()
+            .unwrap();
+        let second = get_packet(DatabaseType::MariaDB, &mut packet_buf, 1024, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.bytes.as_ptr(), start);
+        assert_eq!(second.bytes.as_ptr(), start.wrapping_add(5));
+        assert!(packet_buf.is_empty());
+    }
+
+    #[test]
+    fn get_packet_reads_postgres_startup_message() {
+        let mut packet_buf = BytesMut::from(&[0, 0, 0, 8, 0, 3, 0, 0][..]);
+        let packet = get_packet(DatabaseType::PostgresSQL, &mut packet_buf, 1024, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            packet.get_packet_type().unwrap(),
+            PacketType::StartupMessage
+        );
+    }
+
+    #[test]
+    fn get_packet_rejects_postgres_junk() {
+        for junk in &[
+            vec![0xde, 0xad, 0xbe, 0xef],
+            vec![0, 0, 0, 8, 0xde, 0xad, 0xbe, 0xef],
+            vec![b'Q', 0, 0, 0, 0],
+        ] {
+            let mut packet_buf = BytesMut::from(&junk[..]);
+            assert!(
+                get_packet(DatabaseType::PostgresSQL, &mut packet_buf, 1024, false).is_err(),
+                "accepted {:?}",
+                junk
+            );
+        }
+    }
+
+    #[test]
+    fn try_parse_drains_one_packet() {
+        let ping = Packet::mariadb(0, &[0x0e]);
+        let mut buf = BytesMut::from(&ping.bytes[..]);
+        buf.extend_from_slice(&ping.bytes[..3]);
+        assert_eq!(
+            Packet::try_parse(DatabaseType::MariaDB, &mut buf, 1024, false).unwrap(),
+            Some(ping.clone())
+        );
+        assert_eq!(buf.len(), 3);
+        assert_eq!(
+            Packet::try_parse(DatabaseType::MariaDB, &mut buf, 1024, false).unwrap(),
+            None
+        );
+        assert_eq!(buf.len(), 3);
+
+        let mut buf = BytesMut::from(&b"\x01\x02\x03\x04\x05"[..]);
+        assert!(Packet::try_parse(DatabaseType::PostgresSQL, &mut buf, 1024, false).is_err());
+        let mut buf = BytesMut::from(&ping.bytes[..]);
+        let e = Packet::try_parse(DatabaseType::MariaDB, &mut buf, 4, false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn detects_database_type() {
+        let startup = [0, 0, 0, 9, 0, 3, 0, 0, 0];
+        assert_eq!(
+            DatabaseType::detect(&startup),
+            Some(DatabaseType::PostgresSQL)
+        );
+        let ssl = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+        assert_eq!(DatabaseType::detect(&ssl), Some(DatabaseType::PostgresSQL));
+        let handshake = Packet::mariadb(0, b"\x0a10.4.12-MariaDB\0");
+        assert_eq!(
+            DatabaseType::detect(&handshake.bytes),
+            Some(DatabaseType::MariaDB)
+        );
+        assert_eq!(DatabaseType::detect(&startup[..4]), None);
+        assert_eq!(DatabaseType::detect(b"GET / HTTP/1.1\r\n"), None);
+    }
+
+    #[test]
+    fn parses_database_types() {
+        for name in &["mariadb", "MySQL", "MARIADB"] {
+            assert_eq!(name.parse::<DatabaseType>().unwrap(), DatabaseType::MariaDB);
+        }
+        for name in &["postgres", "PostgreSQL", "pg"] {
+            assert_eq!(
+                name.parse::<DatabaseType>().unwrap(),
+                DatabaseType::PostgresSQL
+            );
+        }
+        let e = "oracle".parse::<DatabaseType>().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        for db_type in &[DatabaseType::MariaDB, DatabaseType::PostgresSQL] {
+            assert_eq!(
+                db_type.to_string().parse::<DatabaseType>().unwrap(),
+                *db_type
+            );
+        }
+    }
+
+    #[test]
+    fn postgres_error_fields() {
+        let err = Packet::error_packet_postgres(*b"42P01", "relation \"t\" does not exist".into());
+        assert_eq!(
+            err.get_postgres_error().unwrap(),
+            vec![
+                ('S', "ERROR".to_string()),
+                ('V', "ERROR".to_string()),
+                ('C', "42P01".to_string()),
+                ('M', "relation \"t\" does not exist".to_string()),
+            ]
+        );
+        let notice = Packet::postgres(b'N', b"SNOTICE\0\0");
+        assert_eq!(notice.get_postgres_error(), None);
+        let truncated = Packet::postgres(b'E', b"SERROR\0C42");
+        assert_eq!(truncated.get_postgres_error(), None);
+    }
+
+    #[test]
+    fn postgres_parse_fields() {
+        let mut payload = b"stmt1\0SELECT $1\0".to_vec();
+        payload.extend_from_slice(&[0, 1, 0, 0, 0, 23]); // one int4 parameter
+        let parse = Packet::postgres(b'P', &payload)
+            .get_postgres_parse()
+            .unwrap();
+        assert_eq!(parse.statement_name, "stmt1");
+        assert_eq!(parse.query, "SELECT $1");
+        assert_eq!(parse.param_types, vec![23]);
+
+        let unnamed = Packet::postgres(b'P', b"\0SELECT 1\0\0\0");
+        let parse = unnamed.get_postgres_parse().unwrap();
+        assert_eq!(parse.statement_name, "");
+        assert!(parse.param_types.is_empty());
+
+        // Fewer parameter types than it says
+        let truncated = Packet::postgres(b'P', b"\0SELECT $1\0\0\x01");
+        assert_eq!(truncated.get_postgres_parse(), None);
+        let query = Packet::postgres(b'Q', b"SELECT 1\0");
+        assert_eq!(query.get_postgres_parse(), None);
+    }
+
+    #[test]
+    fn mariadb_error_fields() {
+        let err = Packet::error_packet_mariadb(1064, *b"42000", "Syntax error".to_string());
+        assert_eq!(
+            err.get_mariadb_error().unwrap(),
+            (1064, "Syntax error".to_string())
+        );
+        let ok = Packet::new(
+            DatabaseType::MariaDB,
+            vec![7, 0, 0, 1, 0x00, 0, 0, 2, 0, 0, 0],
+        );
+        assert!(ok.get_mariadb_error().is_err());
+    }
+
+    #[test]
+    fn postgres_startup_params() {
+        let startup = Packet::postgres_startup(&[("user", "root"), ("database", "testdb")]);
+        let params = startup.get_postgres_startup().unwrap();
+        assert_eq!((params.major_version, params.minor_version), (3, 0));
+        assert_eq!(
+            params.parameters,
+            vec![
+                ("user".to_string(), "root".to_string()),
+                ("database".to_string(), "testdb".to_string())
+            ]
+        );
+        let short = Packet::new(DatabaseType::PostgresSQL, vec![0, 0, 0, 4, 0, 3, 0, 0, 0]);
+        let e = short.get_postgres_startup().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sets_postgres_startup_params() {
+        let mut startup = Packet::postgres_startup(&[("user", "tenant"), ("database", "testdb")]);
+        startup
+            .set_postgres_startup_param("user", "role_1")
+            .unwrap();
+        startup
+            .set_postgres_startup_param("application_name", "proxy")
+            .unwrap();
+        let expected = Packet::postgres_startup(&[
+            ("user", "role_1"),
+            ("database", "testdb"),
+            ("application_name", "proxy"),
+        ]);
+        assert_eq!(startup, expected);
+        assert!(startup.set_postgres_startup_param("user", "a\0b").is_err());
+        let mut query = Packet::postgres(b'Q', b"SELECT 1\0");
+        assert!(query.set_postgres_startup_param("user", "root").is_err());
+        let mut short = Packet::new(DatabaseType::PostgresSQL, vec![0, 0, 0, 4, 0, 3, 0, 0, 0]);
+        let e = short
+            .set_postgres_startup_param("user", "root")
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(short.bytes, vec![0, 0, 0, 4, 0, 3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn constructs_postgres_error() {
+        let err = Packet::error_packet_postgres(*b"42501", "Denied".to_string());
+        assert_eq!(err.get_packet_type().unwrap(), PacketType::ErrorResponse);
+        let mut expected = vec![b'E', 0, 0, 0, 34];
+        expected.extend_from_slice(b"SERROR\0VERROR\0C42501\0MDenied\0\0");
+        assert_eq!(err.bytes, expected);
+    }
+
+    #[test]
+    fn postgres_typeless_request_types() {
+        let cancel = Packet::new(
+            DatabaseType::PostgresSQL,
+            vec![0, 0, 0, 16, 0x04, 0xd2, 0x16, 0x2e, 0, 0, 0, 42, 1, 2, 3, 4],
+        );
+        assert_eq!(cancel.get_packet_type().unwrap(), PacketType::CancelRequest);
+        let ssl = Packet::new(
+            DatabaseType::PostgresSQL,
+            vec![0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f],
+        );
+        assert_eq!(ssl.get_packet_type().unwrap(), PacketType::SSLRequest);
+    }
+
+    #[test]
+    fn constructs_headers() {
+        let ping = Packet::mariadb(3, &[0x0e]);
+        assert_eq!(ping.bytes, vec![1, 0, 0, 3, 0x0e]);
+        assert_eq!(ping.get_packet_type().unwrap(), PacketType::ComPing);
+        let query = Packet::postgres(b'Q', b"SELECT 1\0");
+        assert_eq!(query.bytes[0..5], [b'Q', 0, 0, 0, 13]);
+        assert_eq!(query.get_query().unwrap(), "SELECT 1");
+    }
+
+    #[test]
+    fn formats_packets() {
+        let query = Packet::mariadb(0, b"\x03SELECT 1 FROM dual");
+        assert_eq!(query.to_string(), "MariaDB ComQuery packet, 23 bytes");
+        assert_eq!(
+            query.hexdump(),
+            "00000000  13 00 00 00 03 53 45 4c  45 43 54 20 31 20 46 52  |.....SELECT 1 FR|\n\
+             00000010  4f 4d 20 64 75 61 6c                              |OM dual|\n"
+        );
+        let junk = Packet::new(DatabaseType::PostgresSQL, vec![0xde, 0xad]);
+        assert_eq!(
+            junk.to_string(),
+            "PostgresSQL packet of unknown type, 2 bytes"
+        );
+        let empty = Packet::new(DatabaseType::PostgresSQL, vec![]);
+        assert_eq!(
+            empty.to_string(),
+            "PostgresSQL packet of unknown type, 0 bytes"
+        );
+        assert_eq!(empty.hexdump(), "");
+    }
+
+    #[test]
+    fn query_text() {
+        let mariadb = Packet::new(
+            DatabaseType::MariaDB,
+            b"\x09\x00\x00\x00\x03SELECT 1".to_vec(),
+        );
+        assert_eq!(mariadb.get_query().unwrap(), "SELECT 1");
+        let postgres = Packet::new(
+            DatabaseType::PostgresSQL,
+            b"Q\x00\x00\x00\x0dSELECT 1\x00".to_vec(),
+        );
+        assert_eq!(postgres.get_query().unwrap(), "SELECT 1");
+        let invalid = Packet::new(DatabaseType::MariaDB, vec![2, 0, 0, 0, 0x03, 0xff]);
+        assert!(invalid.get_query().is_err());
+        assert_eq!(invalid.get_query_lossy().unwrap(), "\u{fffd}");
+        let blob = Packet::mariadb(0, b"\x03INSERT INTO t VALUES ('\xff\xfe')");
+        assert_eq!(
+            blob.get_query_lossy().unwrap(),
+            "INSERT INTO t VALUES ('\u{fffd}\u{fffd}')"
+        );
+        assert_eq!(postgres.get_query_lossy().unwrap(), "SELECT 1");
+        let ping = Packet::mariadb(0, &[0x0e]);
+        assert!(ping.get_query_lossy().is_err());
+    }
+
+    #[test]
+    fn query_text_not_utf8() {
+        let mariadb = Packet::mariadb(0, b"\x03SELECT '\xc3\x28'");
+        let e = mariadb.get_query().unwrap_err();
+        assert_eq!(e.to_string(), "Query is not valid UTF-8");
+        let postgres = Packet::postgres(b'Q', b"SELECT '\xff'\0");
+        assert!(postgres.get_query().is_err());
+    }
+
+    #[test]
+    fn handshake_capabilities() {
+        let mut payload = vec![10];
+        payload.extend_from_slice(b"10.4.12-MariaDB\0");
+        payload.extend_from_slice(&[1, 0, 0, 0]); // connection id
+        payload.extend_from_slice(b"12345678\0"); // auth data and filler
+        payload.extend_from_slice(&[0xff, 0xf7]); // lower capabilities without CLIENT_SSL
+        payload.extend_from_slice(&[8, 2, 0]); // charset and status flags
+        payload.extend_from_slice(&[0xff, 0x81]); // upper capabilities
+        let mut p = Packet::mariadb(0, &payload);
+        assert_eq!(p.get_handshake_capabilities().unwrap(), 0x81ff_f7ff);
+        p.clear_handshake_capabilities(CLIENT_COMPRESS | 0x0100_0000)
+            .unwrap();
+        assert_eq!(p.get_handshake_capabilities().unwrap(), 0x80ff_f7df);
+        p.set_handshake_capabilities(0x80ff_f7df | CLIENT_SSL)
+            .unwrap();
+        assert_eq!(p.bytes.len(), 4 + payload.len());
+        assert_eq!(
+            p.bytes[(p.bytes.len() - 7)..(p.bytes.len() - 5)],
+            [0xdf, 0xff]
+        );
+
+        // Without the upper capabilities
+        let mut short = Packet::mariadb(0, &payload[..(payload.len() - 5)]);
+        assert_eq!(short.get_handshake_capabilities().unwrap(), 0xf7ff);
+        assert!(short.set_handshake_capabilities(0x0001_0000).is_err());
+        assert!(short.clear_handshake_capabilities(CLIENT_COMPRESS).is_ok());
+
+        let truncated = Packet::mariadb(0, &payload[..20]);
+        assert!(truncated.get_handshake_capabilities().is_err());
+        let query = Packet::mariadb(0, &[0x03, b'S']);
+        assert!(query.get_handshake_capabilities().is_err());
+    }
+
+    #[test]
+    fn query_fingerprints() {
+        let p = Packet::mariadb(0, b"\x03SELECT * FROM t WHERE id IN (1, 2)");
+        assert_eq!(
+            p.get_query_fingerprint().unwrap(),
+            "select * from t where id in (?)"
+        );
+        let ping = Packet::mariadb(0, &[0x0e]);
+        assert!(ping.get_query_fingerprint().is_err());
+    }
+
+    #[test]
+    fn parses_all_packets() {
+        let mut stream = Packet::mariadb(0, b"\x03SELECT 1").bytes.to_vec();
+        stream.extend_from_slice(&[1, 0, 0, 0, 0x0e]);
+        let complete = stream.len();
+        // An incomplete ping
+        stream.extend_from_slice(&[1, 0, 0]);
+        let (packets, consumed) = parse_all(DatabaseType::MariaDB, &stream);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].get_query().unwrap(), "SELECT 1");
+        assert_eq!(packets[1].get_packet_type().unwrap(), PacketType::ComPing);
+        assert_eq!(consumed, complete);
+
+        // The startup message, then a query, then junk
+        let mut stream = vec![0, 0, 0, 8, 0, 3, 0, 0];
+        stream.extend_from_slice(b"Q\0\0\0\x0dSELECT 1\0");
+        let complete = stream.len();
+        stream.extend_from_slice(&[0xde, 0xad]);
+        let (packets, consumed) = parse_all(DatabaseType::PostgresSQL, &stream);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(consumed, complete);
+        assert_eq!(parse_all(DatabaseType::PostgresSQL, &[]), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn checks_packets() {
+        let ping = Packet::new_checked(DatabaseType::MariaDB, vec![1, 0, 0, 0, 0x0e]).unwrap();
+        assert_eq!(ping.get_size(), 5);
+        let e = Packet::new_checked(DatabaseType::MariaDB, vec![2, 0, 0, 0, 0x0e]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "Incomplete MariaDB packet of 5 bytes");
+        let e = Packet::new_checked(DatabaseType::MariaDB, vec![0, 0, 0, 0, 0x0e]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "MariaDB packet of 4 bytes followed by 1 more bytes"
+        );
+        assert!(Packet::new_checked(DatabaseType::MariaDB, Vec::new()).is_err());
+
+        let query = Packet::postgres(b'Q', b"SELECT 1\0");
+        assert!(Packet::new_checked(DatabaseType::PostgresSQL, query.bytes).is_ok());
+        let ssl_request = vec![0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+        assert!(Packet::new_checked(DatabaseType::PostgresSQL, ssl_request).is_ok());
+        // An unknown type byte, and a length that doesn't count itself
+        assert!(Packet::new_checked(DatabaseType::PostgresSQL, b"@\0\0\0\x04".to_vec()).is_err());
+        assert!(Packet::new_checked(DatabaseType::PostgresSQL, b"Q\0\0\0\x02".to_vec()).is_err());
+    }
+
+    #[test]
+    fn mariadb_continuations() {
+        let mut payload = vec![0x03];
+        payload.resize(MARIADB_MAX_PAYLOAD, b'a');
+        let first = Packet::mariadb(0, &payload);
+        assert!(first.is_mariadb_continuation());
+        let last = Packet::mariadb(1, b"aaa");
+        assert!(!last.is_mariadb_continuation());
+        // Reassembled, with the header of the first packet
+        let mut reassembled = first.bytes.to_vec();
+        reassembled.extend_from_slice(b"aaa");
+        assert!(!Packet::new(DatabaseType::MariaDB, reassembled).is_mariadb_continuation());
+        // The empty packet ending a payload of exactly 0xFFFFFF bytes
+        assert!(!Packet::mariadb(1, &[]).is_mariadb_continuation());
+        let postgres = Packet::new(DatabaseType::PostgresSQL, first.bytes);
+        assert!(!postgres.is_mariadb_continuation());
+    }
+
+    #[test]
+    fn clones_share_bytes() {
+        let p = Packet::mariadb(0, b"\x03SELECT 1");
+        let copy = p.clone();
+        assert_eq!(copy, p);
+        assert_eq!(copy.bytes.as_ptr(), p.bytes.as_ptr());
+        assert_ne!(Packet::mariadb(1, b"\x03SELECT 1"), p);
+        assert_ne!(Packet::new(DatabaseType::PostgresSQL, p.bytes.clone()), p);
+    }
+
+    #[test]
+    fn mariadb_auth_plugin() {
+        let mut payload = vec![10];
+        payload.extend_from_slice(b"10.4.12-MariaDB\0");
+        payload.extend_from_slice(&[1, 0, 0, 0]); // connection id
+        payload.extend_from_slice(b"12345678\0"); // auth data and filler
+        payload.extend_from_slice(&[0xff, 0xf7]); // lower capabilities
+        payload.extend_from_slice(&[8, 2, 0]); // charset and status flags
+        payload.extend_from_slice(&[0xff, 0x81]); // upper capabilities, with CLIENT_PLUGIN_AUTH
+        payload.push(21); // auth data length
+        payload.extend_from_slice(&[0; 10]);
+        payload.extend_from_slice(b"abcdefghijkl\0"); // rest of the auth data
+        payload.extend_from_slice(b"mysql_native_password\0");
+        let handshake = Packet::mariadb(0, &payload);
+        assert_eq!(
+            handshake.get_mariadb_auth_plugin().unwrap(),
+            "mysql_native_password"
+        );
+        // Without the final null byte
+        let unterminated = Packet::mariadb(0, &payload[..(payload.len() - 1)]);
+        assert_eq!(
+            unterminated.get_mariadb_auth_plugin().unwrap(),
+            "mysql_native_password"
+        );
+        // Without CLIENT_PLUGIN_AUTH
+        payload[35] = 0xf7;
+        assert_eq!(Packet::mariadb(0, &payload).get_mariadb_auth_plugin(), None);
+        // Protocol version 9
+        payload[0] = 9;
+        assert_eq!(Packet::mariadb(0, &payload).get_mariadb_auth_plugin(), None);
+
+        let mut response = vec![0_u8; 32];
+        LittleEndian::write_u32(
+            &mut response[0..4],
+            CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH,
+        );
+        response.extend_from_slice(b"root\0");
+        response.extend_from_slice(&[3, 1, 2, 3]); // auth response
+        response.extend_from_slice(b"mysql_old_password\0");
+        let p = Packet::mariadb(1, &response);
+        assert_eq!(p.get_mariadb_auth_plugin().unwrap(), "mysql_old_password");
+        // The SSLRequest sent before the handshake response
+        assert_eq!(
+            Packet::mariadb(1, &response[..32]).get_mariadb_auth_plugin(),
+            None
+        );
+        let query = Packet::mariadb(0, &[0x03, b'S']);
+        assert_eq!(query.get_mariadb_auth_plugin(), None);
+    }
+}
@@ -1,14 +1,985 @@
-use crate::packet::Packet;
+use futures::lock::Mutex;
+use regex::{Regex, RegexBuilder};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 
-#[derive(Debug)]
+use crate::{
+    packet::{DatabaseType, Packet, PacketType},
+    router::first_keyword,
+    session::{parse_handshake_response, used_databases, SessionState},
+};
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Direction {
     Forward,  // corresponds to handle_request
     Backward, // corresponds to handle_response
 }
 
-/// Packet handlers need to implement this trait
+/// Describes the pipe a packet is travelling through
+#[derive(Clone, Debug, PartialEq)]
+pub struct PacketContext {
+    pub db_type: DatabaseType,
+    pub direction: Direction,
+    pub pipe_name: String,
+    /// Unique per connection, shared by both of its pipes
+    pub connection_id: u64,
+    /// The session as of this packet, see `SessionState` for what is tracked
+    pub session: SessionState,
+    /// Whether either pipe of the connection has stopped reading from its source because its
+    /// sink is behind, see `PipeOptions::write_buf_high_water_mark` and `Pipe::with_backpressure`.
+    /// A handler can use it to shed load, e.g. by rejecting expensive queries while the client
+    /// isn't keeping up with its results
+    pub backpressure: bool,
+    /// Bytes waiting to be written to the pipe's sink.
+    /// Both this and `backpressure` are snapshots from the pipe's last iteration, best-effort
+    pub write_buf_len: usize,
+    /// Whether the database had sent its MariaDB initial handshake before this packet, so
+    /// `p.get_response_type_with_handshake(ctx.handshake_seen)` tells the handshake apart.
+    /// Also true when the proxy terminated TLS and the pipes never saw it
+    pub handshake_seen: bool,
+    /// Whether this is the MariaDB client's handshake response, its first packet, which may
+    /// name a database (CLIENT_CONNECT_WITH_DB) and is answered with an OK or ERR
+    pub handshake_response: bool,
+}
+
+/// What a handler keeps about a request for its response, see `PacketHandler::correlate`
+pub type CorrelationState = Arc<dyn Any + Send + Sync>;
+
+/// The request a response answers, see `PacketHandler::handle_response_for`
+#[derive(Clone)]
+pub struct RequestContext {
+    /// Counts the requests of the connection that were forwarded or replaced, from 0
+    pub id: u64,
+    /// The request as the client sent it, before the handler saw it
+    pub packet: Packet,
+    /// What `PacketHandler::correlate` returned for the request, `None` if it returned nothing
+    pub state: Option<CorrelationState>,
+}
+
+impl fmt::Debug for RequestContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RequestContext")
+            .field("id", &self.id)
+            .field("packet", &self.packet)
+            .field("state", &self.state.is_some())
+            .finish()
+    }
+}
+
+/// A query seen by a pipe, see `PipeOptions::query_events`
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryEvent {
+    /// When the pipe parsed the query, before the handler saw it
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub connection_id: u64,
+    pub query: String,
+}
+
+/// What the pipe should do with a packet after a handler has seen it
+#[derive(Clone, Debug, PartialEq)]
+pub enum HandlerAction {
+    /// Forward the original packet unchanged
+    Forward,
+    /// Forward these packets, in order, in place of the original
+    Replace(Vec<Packet>),
+    /// Swallow the packet, nothing is written to the sink
+    Drop,
+    /// Swallow the packet and send this packet back to the source instead.
+    /// For MariaDB, the response must carry the request's sequence id plus one
+    /// (see `Packet::set_sequence_id`), otherwise the client drops the connection
+    Respond(Packet),
+}
+
+/// How to answer a client that may ask for TLS, see `PacketHandler::on_ssl_request`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SslDecision {
+    /// Keep the connection plaintext. PostgresSQL clients get an 'N' to their SSLRequest,
+    /// and the MariaDB handshake tells clients the database doesn't support SSL
+    Deny,
+    /// Let the client negotiate TLS with the database, after which packets are opaque
+    AllowPassthrough,
+    /// Negotiate TLS with the proxy. This needs `Server::with_tls`, which handles TLS
+    /// before the pipes start, so pipes that are asked for it deny instead
+    TerminateTls,
+}
+
+/// Packet handlers need to implement this trait.
+///
+/// Both pipes of a connection share its handler. To handle a response according to what was
+/// done with its request, e.g. to restore the column names of a rewritten query, keep what
+/// is needed in `correlate` and get it back in `handle_response_for`:
+/// ```
+/// # use sql_proxy::packet::Packet;
+/// # use sql_proxy::packet_handler::*;
+/// # use std::sync::Arc;
+/// struct Rewriter {}
+///
+/// #[async_trait::async_trait]
+/// impl PacketHandler for Rewriter {
+///     async fn handle_request(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+///         HandlerAction::Forward
+///     }
+///
+///     async fn correlate(
+///         &mut self,
+///         request: &Packet,
+///         _ctx: &PacketContext,
+///     ) -> Option<CorrelationState> {
+///         let query = request.get_query().ok()?;
+///         Some(Arc::new(query))
+///     }
+///
+///     async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+///         HandlerAction::Forward
+///     }
+///
+///     async fn handle_response_for(
+///         &mut self,
+///         p: &Packet,
+///         ctx: &PacketContext,
+///         request: &RequestContext,
+///     ) -> HandlerAction {
+///         let query = request.state.as_ref().and_then(|s| s.downcast_ref::<String>());
+///         // Rewrite the response to `query`
+///         HandlerAction::Forward
+///     }
+/// }
+/// ```
 #[async_trait::async_trait]
 pub trait PacketHandler {
-    async fn handle_request(&mut self, p: &Packet) -> Packet;
-    async fn handle_response(&mut self, p: &Packet) -> Packet;
+    async fn handle_request(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction;
+    async fn handle_response(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction;
+
+    /// Called after `handle_request` forwards or replaces a request, with the request as the
+    /// client sent it. What it returns is given back with every packet of the response, in
+    /// `RequestContext::state`. Keeps nothing by default
+    async fn correlate(
+        &mut self,
+        _request: &Packet,
+        _ctx: &PacketContext,
+    ) -> Option<CorrelationState> {
+        None
+    }
+
+    /// Called instead of `handle_response` when the pipes know which request a response
+    /// answers, see `RequestTracker` for how they pair them. Calls `handle_response` by default
+    async fn handle_response_for(
+        &mut self,
+        p: &Packet,
+        ctx: &PacketContext,
+        _request: &RequestContext,
+    ) -> HandlerAction {
+        self.handle_response(p, ctx).await
+    }
+
+    /// Called on a PostgresSQL SSLRequest, and on the MariaDB handshake, which tells
+    /// the client whether it may ask for TLS. Not called if `PipeOptions::allow_ssl_passthrough`
+    /// is set, in which case TLS is always passed through. Denies by default
+    fn on_ssl_request(&self, _db_type: DatabaseType) -> SslDecision {
+        SslDecision::Deny
+    }
+
+    /// Called by the server once both pipes of connection `connection_id` have finished,
+    /// however the connection closed, so a handler shared by every connection can drop
+    /// what it keeps for it. Does nothing by default
+    async fn connection_closed(&mut self, _connection_id: u64) {}
+}
+
+#[async_trait::async_trait]
+impl<H: PacketHandler + Send + ?Sized> PacketHandler for Box<H> {
+    async fn handle_request(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        (**self).handle_request(p, ctx).await
+    }
+
+    async fn handle_response(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        (**self).handle_response(p, ctx).await
+    }
+
+    async fn correlate(
+        &mut self,
+        request: &Packet,
+        ctx: &PacketContext,
+    ) -> Option<CorrelationState> {
+        (**self).correlate(request, ctx).await
+    }
+
+    async fn handle_response_for(
+        &mut self,
+        p: &Packet,
+        ctx: &PacketContext,
+        request: &RequestContext,
+    ) -> HandlerAction {
+        (**self).handle_response_for(p, ctx, request).await
+    }
+
+    fn on_ssl_request(&self, db_type: DatabaseType) -> SslDecision {
+        (**self).on_ssl_request(db_type)
+    }
+
+    async fn connection_closed(&mut self, connection_id: u64) {
+        (**self).connection_closed(connection_id).await
+    }
+}
+
+/// Creates a handler for each connection, see `Server::run_with_factory`.
+/// Closures taking the connection id and client address implement it
+pub trait HandlerFactory: Send + Sync {
+    /// Called once per connection, before its pipes start. Both pipes share the handler
+    fn new_handler(&self, connection_id: u64, client_addr: &str) -> Box<dyn PacketHandler + Send>;
+}
+
+impl<F: Fn(u64, &str) -> Box<dyn PacketHandler + Send> + Send + Sync> HandlerFactory for F {
+    fn new_handler(&self, connection_id: u64, client_addr: &str) -> Box<dyn PacketHandler + Send> {
+        self(connection_id, client_addr)
+    }
+}
+
+/// Forwards every packet unchanged
+#[derive(Clone, Debug, Default)]
+pub struct PassthroughHandler {}
+
+#[async_trait::async_trait]
+impl PacketHandler for PassthroughHandler {
+    async fn handle_request(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+        HandlerAction::Forward
+    }
+
+    async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+        HandlerAction::Forward
+    }
+}
+
+/// Runs several handlers in sequence, each one seeing the packets output by the previous one.
+/// Requests go through the handlers in order, responses in reverse order.
+/// The chain stops at the first handler that drops a packet or responds directly.
+/// The handlers' `on_ssl_request` are not consulted, the chain denies.
+/// Each handler's `correlate` sees the request as the client sent it, and its
+/// `handle_response_for` gets back what it kept
+pub struct ChainHandler {
+    handlers: Vec<Arc<Mutex<dyn PacketHandler + Send>>>,
+}
+
+/// What each handler of a chain kept about a request, by index
+struct ChainState(Vec<Option<CorrelationState>>);
+
+impl ChainHandler {
+    pub fn new(handlers: Vec<Arc<Mutex<dyn PacketHandler + Send>>>) -> ChainHandler {
+        ChainHandler { handlers }
+    }
+
+    async fn handle<'a, I>(
+        handlers: I,
+        p: &Packet,
+        ctx: &PacketContext,
+        request: Option<&RequestContext>,
+    ) -> HandlerAction
+    where
+        I: Iterator<Item = (usize, &'a Arc<Mutex<dyn PacketHandler + Send>>)>,
+    {
+        let mut packets = vec![p.clone()];
+        let mut replaced = false;
+        for (i, handler) in handlers {
+            let mut next = Vec::with_capacity(packets.len());
+            for packet in packets.iter() {
+                let mut h = handler.lock().await;
+                let action = match (ctx.direction, request) {
+                    (Direction::Forward, _) => h.handle_request(packet, ctx).await,
+                    (Direction::Backward, Some(request)) => {
+                        let request = ChainHandler::request_for(request, i);
+                        h.handle_response_for(packet, ctx, &request).await
+                    }
+                    (Direction::Backward, None) => h.handle_response(packet, ctx).await,
+                };
+                match action {
+                    HandlerAction::Forward => next.push(packet.clone()),
+                    HandlerAction::Replace(replacements) => {
+                        replaced = true;
+                        next.extend(replacements);
+                    }
+                    HandlerAction::Drop | HandlerAction::Respond(_) => return action,
+                }
+            }
+            packets = next;
+        }
+        if replaced {
+            HandlerAction::Replace(packets)
+        } else {
+            HandlerAction::Forward
+        }
+    }
+
+    /// The request as handler `i` sees it, with what its own `correlate` kept
+    fn request_for(request: &RequestContext, i: usize) -> RequestContext {
+        let state = request
+            .state
+            .as_ref()
+            .and_then(|s| s.downcast_ref::<ChainState>())
+            .and_then(|states| states.0.get(i).cloned().flatten());
+        RequestContext {
+            state,
+            ..request.clone()
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketHandler for ChainHandler {
+    async fn handle_request(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        ChainHandler::handle(self.handlers.iter().enumerate(), p, ctx, None).await
+    }
+
+    async fn handle_response(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        ChainHandler::handle(self.handlers.iter().enumerate().rev(), p, ctx, None).await
+    }
+
+    async fn correlate(
+        &mut self,
+        request: &Packet,
+        ctx: &PacketContext,
+    ) -> Option<CorrelationState> {
+        let mut states = Vec::with_capacity(self.handlers.len());
+        for handler in self.handlers.iter() {
+            states.push(handler.lock().await.correlate(request, ctx).await);
+        }
+        Some(Arc::new(ChainState(states)))
+    }
+
+    async fn handle_response_for(
+        &mut self,
+        p: &Packet,
+        ctx: &PacketContext,
+        request: &RequestContext,
+    ) -> HandlerAction {
+        let handlers = self.handlers.iter().enumerate().rev();
+        ChainHandler::handle(handlers, p, ctx, Some(request)).await
+    }
+
+    async fn connection_closed(&mut self, connection_id: u64) {
+        for handler in self.handlers.iter() {
+            handler.lock().await.connection_closed(connection_id).await;
+        }
+    }
+}
+
+/// Blocks queries matching any of its rules, answering the client with an error
+/// instead of forwarding them. Only MariaDB COM_QUERY and PostgresSQL Query ('Q')
+/// are inspected, prepared statements are not
+#[derive(Clone, Debug)]
+pub struct FirewallHandler {
+    rules: Vec<Regex>,
+    denied_keywords: Vec<String>,
+}
+
+impl FirewallHandler {
+    /// Blocks queries that match any of `patterns`, ignoring case,
+    /// e.g. `\bDELETE\b.*\bWHERE 1\b`
+    pub fn new(patterns: &[&str]) -> Result<FirewallHandler, regex::Error> {
+        let mut firewall = FirewallHandler::deny_keywords(&[]);
+        for pattern in patterns {
+            firewall = firewall.with_rule(pattern)?;
+        }
+        Ok(firewall)
+    }
+
+    /// Blocks queries containing a statement that starts with any of `keywords`,
+    /// e.g. `DROP` or `TRUNCATE`, ignoring case, leading whitespace and comments
+    pub fn deny_keywords(keywords: &[&str]) -> FirewallHandler {
+        FirewallHandler {
+            rules: Vec::new(),
+            denied_keywords: keywords.iter().map(|k| k.to_ascii_uppercase()).collect(),
+        }
+    }
+
+    /// Adds a case-insensitive pattern to the rules
+    pub fn with_rule(mut self, pattern: &str) -> Result<FirewallHandler, regex::Error> {
+        self.rules
+            .push(RegexBuilder::new(pattern).case_insensitive(true).build()?);
+        Ok(self)
+    }
+
+    pub fn is_denied(&self, query: &str) -> bool {
+        // Splitting on every ';', even inside string literals, errs on the side of blocking
+        let denied_statement = query.split(';').any(|statement| {
+            let keyword = first_keyword(statement).to_ascii_uppercase();
+            self.denied_keywords.contains(&keyword)
+        });
+        denied_statement || self.rules.iter().any(|r| r.is_match(query))
+    }
+}
+
+/// Answers the query `p` with an error instead of forwarding it.
+/// `mariadb_code` and `state` make the MariaDB ERR packet, `postgres_code` the ErrorResponse
+fn reject_query(
+    p: &Packet,
+    ctx: &PacketContext,
+    (mariadb_code, state): (u16, [u8; 5]),
+    postgres_code: [u8; 5],
+    msg: String,
+) -> HandlerAction {
+    match ctx.db_type {
+        DatabaseType::MariaDB => {
+            let mut err = Packet::error_packet_mariadb(mariadb_code, state, msg);
+            let sequence_id = p.get_sequence_id().unwrap_or(0).wrapping_add(1);
+            let _ = err.set_sequence_id(sequence_id);
+            HandlerAction::Respond(err)
+        }
+        DatabaseType::PostgresSQL => {
+            // The client waits for a ReadyForQuery after the error.
+            // The transaction is unaffected, since the database never saw the query
+            let status = if ctx.session.in_failed_transaction {
+                b'E'
+            } else if ctx.session.in_transaction {
+                b'T'
+            } else {
+                b'I'
+            };
+            let err = Packet::error_packet_postgres(postgres_code, msg);
+            let mut bytes = err.bytes.to_vec();
+            bytes.extend_from_slice(&Packet::postgres(b'Z', &[status]).bytes);
+            HandlerAction::Respond(Packet::new(DatabaseType::PostgresSQL, bytes))
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketHandler for FirewallHandler {
+    async fn handle_request(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        match p.get_query() {
+            Ok(query) if self.is_denied(&query) => {
+                warn!(
+                    "FirewallHandler: Blocked query on connection #{}: {}",
+                    ctx.connection_id, query
+                );
+                // ER_SPECIFIC_ACCESS_DENIED_ERROR, insufficient_privilege
+                reject_query(
+                    p,
+                    ctx,
+                    (1227, *b"42000"),
+                    *b"42501",
+                    "Query blocked by the proxy firewall".to_string(),
+                )
+            }
+            _ => HandlerAction::Forward,
+        }
+    }
+
+    async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+        HandlerAction::Forward
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Limits the queries (MariaDB COM_QUERY, PostgresSQL 'Q') of each connection with a token
+/// bucket, answering queries over the limit with an error instead of forwarding them.
+/// A connection's bucket is dropped when it sends COM_QUIT or Terminate, or when it closes
+#[derive(Debug)]
+pub struct RateLimitHandler {
+    queries_per_second: f64,
+    burst: f64,
+    buckets: HashMap<u64, TokenBucket>,
+}
+
+impl RateLimitHandler {
+    /// Allows each connection `queries_per_second` queries on average,
+    /// and up to `burst` at once after being idle
+    pub fn new(queries_per_second: f64, burst: u32) -> RateLimitHandler {
+        RateLimitHandler {
+            queries_per_second,
+            burst: f64::from(burst.max(1)),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token from the connection's bucket, if it has one
+    fn allow(&mut self, connection_id: u64, now: Instant) -> bool {
+        let burst = self.burst;
+        let bucket = self.buckets.entry(connection_id).or_insert(TokenBucket {
+            tokens: burst,
+            refilled: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.queries_per_second).min(burst);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketHandler for RateLimitHandler {
+    async fn handle_request(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        match p.get_packet_type() {
+            Ok(PacketType::ComQuit) | Ok(PacketType::Terminate) => {
+                self.buckets.remove(&ctx.connection_id);
+                HandlerAction::Forward
+            }
+            Ok(PacketType::ComQuery) | Ok(PacketType::Query) => {
+                if self.allow(ctx.connection_id, Instant::now()) {
+                    return HandlerAction::Forward;
+                }
+                debug!(
+                    "RateLimitHandler: Rejected query on connection #{}",
+                    ctx.connection_id
+                );
+                // ER_USER_LIMIT_REACHED, configuration_limit_exceeded
+                reject_query(
+                    p,
+                    ctx,
+                    (1226, *b"42000"),
+                    *b"53400",
+                    "Too many queries, slow down".to_string(),
+                )
+            }
+            _ => HandlerAction::Forward,
+        }
+    }
+
+    async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+        HandlerAction::Forward
+    }
+
+    async fn connection_closed(&mut self, connection_id: u64) {
+        self.buckets.remove(&connection_id);
+    }
+}
+
+/// Only lets clients use the databases it allows, answering with an access denied error
+/// instead of forwarding the switch. Checks the database of the MariaDB handshake response,
+/// COM_INIT_DB and every `USE` statement of a query, and the database of PostgresSQL
+/// StartupMessages (defaulting to the user name)
+#[derive(Clone, Debug)]
+pub struct DatabaseAllowlistHandler {
+    allowed: HashSet<String>,
+}
+
+impl DatabaseAllowlistHandler {
+    pub fn new(databases: &[&str]) -> DatabaseAllowlistHandler {
+        DatabaseAllowlistHandler {
+            allowed: databases.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    pub fn is_allowed(&self, database: &str) -> bool {
+        self.allowed.contains(database)
+    }
+
+    /// The databases `p` switches to
+    fn targets(p: &Packet, ctx: &PacketContext) -> Vec<String> {
+        if let Ok(startup) = p.get_postgres_startup() {
+            let param = |name: &str| {
+                startup
+                    .parameters
+                    .iter()
+                    .find(|(k, _v)| k == name)
+                    .map(|(_k, v)| v.clone())
+            };
+            return param("database")
+                .or_else(|| param("user"))
+                .into_iter()
+                .collect();
+        }
+        if ctx.handshake_response {
+            return parse_handshake_response(&p.bytes[..])
+                .database
+                .into_iter()
+                .collect();
+        }
+        match p.get_packet_type() {
+            Ok(PacketType::ComInitDb) => p.get_init_db().into_iter().collect(),
+            Ok(PacketType::ComQuery) => p
+                .get_query_lossy()
+                .map(|q| used_databases(&q))
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketHandler for DatabaseAllowlistHandler {
+    async fn handle_request(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        let database = match DatabaseAllowlistHandler::targets(p, ctx)
+            .into_iter()
+            .find(|database| !self.is_allowed(database))
+        {
+            Some(database) => database,
+            None => return HandlerAction::Forward,
+        };
+        warn!(
+            "DatabaseAllowlistHandler: Denied database {} on connection #{}",
+            database, ctx.connection_id
+        );
+        let msg = format!("Access denied to database '{}'", database);
+        match ctx.db_type {
+            DatabaseType::PostgresSQL if p.get_postgres_startup().is_ok() => {
+                // No ReadyForQuery, the client gives up on the connection
+                HandlerAction::Respond(Packet::error_packet_postgres(*b"42501", msg))
+            }
+            // ER_DBACCESS_DENIED_ERROR, insufficient_privilege
+            _ => reject_query(p, ctx, (1044, *b"42000"), *b"42501", msg),
+        }
+    }
+
+    async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+        HandlerAction::Forward
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::context;
+    use std::time::Duration;
+
+    /// Appends its tag to the payload of every packet
+    struct TagHandler {
+        tag: u8,
+    }
+
+    #[async_trait::async_trait]
+    impl PacketHandler for TagHandler {
+        async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            let mut bytes = p.bytes.to_vec();
+            bytes.push(self.tag);
+            HandlerAction::Replace(vec![Packet::new(DatabaseType::MariaDB, bytes)])
+        }
+
+        async fn handle_response(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+            self.handle_request(p, ctx).await
+        }
+
+        async fn correlate(
+            &mut self,
+            _request: &Packet,
+            _ctx: &PacketContext,
+        ) -> Option<CorrelationState> {
+            Some(Arc::new(self.tag))
+        }
+
+        /// Appends the tag kept by `correlate`, if there is one
+        async fn handle_response_for(
+            &mut self,
+            p: &Packet,
+            _ctx: &PacketContext,
+            request: &RequestContext,
+        ) -> HandlerAction {
+            let mut bytes = p.bytes.to_vec();
+            let tag = request.state.as_ref().and_then(|s| s.downcast_ref::<u8>());
+            bytes.extend(tag);
+            HandlerAction::Replace(vec![Packet::new(DatabaseType::MariaDB, bytes)])
+        }
+    }
+
+    struct DropHandler {}
+
+    #[async_trait::async_trait]
+    impl PacketHandler for DropHandler {
+        async fn handle_request(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Drop
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Drop
+        }
+    }
+
+    #[tokio::test]
+    async fn chain_runs_handlers_in_order() {
+        let mut chain = ChainHandler::new(vec![
+            Arc::new(Mutex::new(TagHandler { tag: 1 })),
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Arc::new(Mutex::new(TagHandler { tag: 2 })),
+        ]);
+        let p = Packet::new(DatabaseType::MariaDB, vec![]);
+        let expected =
+            |bytes| HandlerAction::Replace(vec![Packet::new(DatabaseType::MariaDB, bytes)]);
+        let ctx = context(DatabaseType::MariaDB, Direction::Forward);
+        assert_eq!(chain.handle_request(&p, &ctx).await, expected(vec![1, 2]));
+        let ctx = context(DatabaseType::MariaDB, Direction::Backward);
+        assert_eq!(chain.handle_response(&p, &ctx).await, expected(vec![2, 1]));
+    }
+
+    #[tokio::test]
+    async fn chain_correlates_each_handler() {
+        let mut chain = ChainHandler::new(vec![
+            Arc::new(Mutex::new(TagHandler { tag: 1 })),
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Arc::new(Mutex::new(TagHandler { tag: 2 })),
+        ]);
+        let p = Packet::new(DatabaseType::MariaDB, vec![]);
+        let request = RequestContext {
+            id: 0,
+            packet: p.clone(),
+            state: chain
+                .correlate(&p, &context(DatabaseType::MariaDB, Direction::Forward))
+                .await,
+        };
+        let ctx = context(DatabaseType::MariaDB, Direction::Backward);
+        assert_eq!(
+            chain.handle_response_for(&p, &ctx, &request).await,
+            HandlerAction::Replace(vec![Packet::new(DatabaseType::MariaDB, vec![2, 1])])
+        );
+        // Nothing kept
+        let request = RequestContext {
+            state: None,
+            ..request
+        };
+        assert_eq!(
+            chain.handle_response_for(&p, &ctx, &request).await,
+            HandlerAction::Replace(vec![p])
+        );
+    }
+
+    #[tokio::test]
+    async fn chain_stops_at_drop() {
+        let tagger = Arc::new(Mutex::new(TagHandler { tag: 1 }));
+        let mut chain = ChainHandler::new(vec![Arc::new(Mutex::new(DropHandler {})), tagger]);
+        let p = Packet::new(DatabaseType::MariaDB, vec![]);
+        let ctx = context(DatabaseType::MariaDB, Direction::Forward);
+        assert_eq!(chain.handle_request(&p, &ctx).await, HandlerAction::Drop);
+    }
+
+    #[test]
+    fn rate_limit_allows_bursts() {
+        let mut limiter = RateLimitHandler::new(10.0, 3);
+        let start = Instant::now();
+        assert!((0..3).all(|_| limiter.allow(1, start)));
+        assert!(!limiter.allow(1, start));
+        // Other connections have their own bucket
+        assert!(limiter.allow(2, start));
+        // Idling refills up to the burst, no further
+        let later = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| limiter.allow(1, later)));
+        assert!(!limiter.allow(1, later));
+    }
+
+    #[test]
+    fn rate_limit_steady_state() {
+        let mut limiter = RateLimitHandler::new(10.0, 1);
+        let start = Instant::now();
+        // One query every 100ms is exactly the rate, any faster and some are rejected
+        let allowed = (0..100)
+            .filter(|i| limiter.allow(1, start + Duration::from_millis(i * 100)))
+            .count();
+        assert_eq!(allowed, 100);
+        let start = start + Duration::from_secs(60);
+        let allowed = (0..100)
+            .filter(|i| limiter.allow(1, start + Duration::from_millis(i * 50)))
+            .count();
+        assert_eq!(allowed, 50);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_rejects_queries() {
+        let mut limiter = RateLimitHandler::new(0.001, 1);
+        let ctx = context(DatabaseType::MariaDB, Direction::Forward);
+        let select = Packet::mariadb(0, b"\x03SELECT 1");
+        assert_eq!(
+            limiter.handle_request(&select, &ctx).await,
+            HandlerAction::Forward
+        );
+        match limiter.handle_request(&select, &ctx).await {
+            HandlerAction::Respond(err) => {
+                assert_eq!(err.get_mariadb_error().unwrap().0, 1226);
+            }
+            action => panic!("Unexpected {:?}", action),
+        }
+        // Only queries count
+        let ping = Packet::mariadb(0, &[0x0e]);
+        assert_eq!(
+            limiter.handle_request(&ping, &ctx).await,
+            HandlerAction::Forward
+        );
+        let quit = Packet::mariadb(0, &[0x01]);
+        limiter.handle_request(&quit, &ctx).await;
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_forgets_closed_connections() {
+        let limiter = Arc::new(Mutex::new(RateLimitHandler::new(10.0, 1)));
+        let mut chain = ChainHandler::new(vec![limiter.clone()]);
+        let mut ctx = context(DatabaseType::MariaDB, Direction::Forward);
+        let select = Packet::mariadb(0, b"\x03SELECT 1");
+        for connection_id in 1..=2 {
+            ctx.connection_id = connection_id;
+            chain.handle_request(&select, &ctx).await;
+        }
+        // Closed without COM_QUIT
+        chain.connection_closed(1).await;
+        let buckets = &limiter.lock().await.buckets;
+        assert_eq!(buckets.keys().collect::<Vec<_>>(), vec![&2]);
+    }
+
+    #[tokio::test]
+    async fn firewall_blocks_denied_queries() {
+        let mut firewall = FirewallHandler::deny_keywords(&["DROP", "truncate"])
+            .with_rule(r"\bDELETE\s+FROM\s+\w+\s*$")
+            .unwrap();
+        assert!(firewall.is_denied("  drop table t"));
+        assert!(firewall.is_denied("SELECT 1; /* sneaky */ TRUNCATE t"));
+        assert!(firewall.is_denied("delete from t"));
+        assert!(!firewall.is_denied("DELETE FROM t WHERE id = 1"));
+        assert!(!firewall.is_denied("SELECT 'drop'"));
+
+        let ctx = context(DatabaseType::MariaDB, Direction::Forward);
+        let drop_table = Packet::mariadb(0, b"\x03DROP TABLE t");
+        match firewall.handle_request(&drop_table, &ctx).await {
+            HandlerAction::Respond(err) => {
+                assert_eq!(err.get_sequence_id().unwrap(), 1);
+                assert_eq!(err.get_mariadb_error().unwrap().0, 1227);
+            }
+            action => panic!("Unexpected {:?}", action),
+        }
+        let select = Packet::mariadb(0, b"\x03SELECT 1");
+        assert!(FirewallHandler::new(&["("]).is_err());
+        assert!(FirewallHandler::new(&["^drop"])
+            .unwrap()
+            .is_denied("DROP TABLE t"));
+        assert_eq!(
+            firewall.handle_request(&select, &ctx).await,
+            HandlerAction::Forward
+        );
+
+        let ctx = context(DatabaseType::PostgresSQL, Direction::Forward);
+        let drop_table = Packet::postgres(b'Q', b"DROP TABLE t\0");
+        match firewall.handle_request(&drop_table, &ctx).await {
+            HandlerAction::Respond(response) => {
+                assert_eq!(response.bytes[0], b'E');
+                assert!(response.bytes.ends_with(&[b'Z', 0, 0, 0, 5, b'I']));
+            }
+            action => panic!("Unexpected {:?}", action),
+        }
+        // The transaction's status is echoed back, failed or not
+        let mut ctx = ctx;
+        ctx.session.in_transaction = true;
+        for (failed, status) in [(false, b'T'), (true, b'E')] {
+            ctx.session.in_failed_transaction = failed;
+            match firewall.handle_request(&drop_table, &ctx).await {
+                HandlerAction::Respond(response) => {
+                    assert!(response.bytes.ends_with(&[b'Z', 0, 0, 0, 5, status]));
+                }
+                action => panic!("Unexpected {:?}", action),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn allowlist_denies_other_databases() {
+        let mut allowlist = DatabaseAllowlistHandler::new(&["tenant"]);
+        let ctx = context(DatabaseType::MariaDB, Direction::Forward);
+        let init_db = Packet::mariadb(0, b"\x02tenant");
+        assert_eq!(
+            allowlist.handle_request(&init_db, &ctx).await,
+            HandlerAction::Forward
+        );
+        for packet in &[
+            Packet::mariadb(0, b"\x02other"),
+            Packet::mariadb(0, b"\x03USE `other`"),
+            Packet::mariadb(0, b"\x03USE`other`"),
+            Packet::mariadb(0, b"\x03/* x */ USE other"),
+            Packet::mariadb(0, b"\x03SELECT 1; USE other"),
+            Packet::mariadb(0, b"\x03USE tenant; USE other; SELECT 1"),
+        ] {
+            match allowlist.handle_request(packet, &ctx).await {
+                HandlerAction::Respond(err) => {
+                    assert_eq!(err.get_mariadb_error().unwrap().0, 1044);
+                }
+                action => panic!("Unexpected {:?}", action),
+            }
+        }
+
+        // A handshake response with CLIENT_SECURE_CONNECTION | CLIENT_CONNECT_WITH_DB
+        let handshake_response = |database: &[u8]| {
+            let mut payload = vec![0_u8; 32];
+            payload[0..2].copy_from_slice(&[0x08, 0x80]);
+            payload.extend_from_slice(b"root\0\0");
+            payload.extend_from_slice(database);
+            payload.push(0);
+            Packet::mariadb(1, &payload)
+        };
+        let ctx = PacketContext {
+            handshake_response: true,
+            ..context(DatabaseType::MariaDB, Direction::Forward)
+        };
+        assert_eq!(
+            allowlist
+                .handle_request(&handshake_response(b"tenant"), &ctx)
+                .await,
+            HandlerAction::Forward
+        );
+        match allowlist
+            .handle_request(&handshake_response(b"other"), &ctx)
+            .await
+        {
+            HandlerAction::Respond(err) => {
+                assert_eq!(err.get_mariadb_error().unwrap().0, 1044);
+                assert_eq!(err.get_sequence_id().unwrap(), 2);
+            }
+            action => panic!("Unexpected {:?}", action),
+        }
+
+        let ctx = context(DatabaseType::PostgresSQL, Direction::Forward);
+        let allowed = Packet::postgres_startup(&[("user", "root"), ("database", "tenant")]);
+        assert_eq!(
+            allowlist.handle_request(&allowed, &ctx).await,
+            HandlerAction::Forward
+        );
+        // The database defaults to the user name
+        match allowlist
+            .handle_request(&Packet::postgres_startup(&[("user", "root")]), &ctx)
+            .await
+        {
+            HandlerAction::Respond(err) => {
+                assert_eq!(err.bytes[0], b'E');
+                assert_eq!(err.get_postgres_error().unwrap()[2].1, "42501");
+            }
+            action => panic!("Unexpected {:?}", action),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn query_events_round_trip_through_json() {
+        let event = QueryEvent {
+            timestamp: std::time::SystemTime::UNIX_EPOCH,
+            direction: Direction::Forward,
+            connection_id: 3,
+            query: "SELECT 1".to_string(),
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.contains("\"direction\":\"Forward\""));
+        assert_eq!(serde_json::from_str::<QueryEvent>(&json).unwrap(), event);
+        assert_eq!(
+            serde_json::to_string(&DatabaseType::PostgresSQL).unwrap(),
+            "\"PostgresSQL\""
+        );
+    }
+
+    #[tokio::test]
+    async fn passthrough_forwards_unchanged() {
+        let ctx = context(DatabaseType::MariaDB, Direction::Forward);
+        let p = Packet::new(DatabaseType::MariaDB, vec![1, 0, 0, 0, 0x0e]);
+        let mut h = PassthroughHandler {};
+        assert_eq!(h.handle_request(&p, &ctx).await, HandlerAction::Forward);
+        assert_eq!(h.handle_response(&p, &ctx).await, HandlerAction::Forward);
+    }
 }
@@ -1,8 +1,13 @@
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::BytesMut;
 use futures::{
-    channel::mpsc::{Receiver, Sender},
+    channel::{
+        mpsc::{Receiver, Sender},
+        oneshot,
+    },
+    future::Fuse,
     lock::Mutex,
-    select,
+    pin_mut, select_biased,
     sink::SinkExt,
     FutureExt, StreamExt,
 };
@@ -10,27 +15,687 @@ use futures::{
 //    future::FutureExt,
 //    stream::StreamExt,
 //};
+use log::Level;
 use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    fmt,
+    hash::{BuildHasher, Hasher},
     io::{Error, ErrorKind},
-    sync::Arc,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Result},
+    time::{delay_until, timeout, Instant},
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt, Result};
 
 use crate::{
-    packet::{DatabaseType, Packet, PacketType, POSTGRES_IDS},
-    packet_handler::{Direction, PacketHandler},
+    init_commands::InitCommands,
+    packet::{
+        get_packet, DatabaseType, FramingError, Packet, PacketType, CLIENT_COMPRESS, CLIENT_SSL,
+        DEFAULT_MAX_PACKET_SIZE, MARIADB_MAX_PAYLOAD,
+    },
+    packet_handler::{
+        Direction, HandlerAction, PacketContext, PacketHandler, QueryEvent, SslDecision,
+    },
+    query_timer::QueryTimer,
+    request_tracker::RequestTracker,
+    session::SessionTracker,
+    tls,
 };
 
-pub struct Pipe<T: AsyncReadExt, U: AsyncWriteExt> {
+/// Receives per-pipe byte counts and backpressure changes, e.g. to export as metrics
+/// Implementations are called inline on the pipe's task, so they should not block
+pub trait PipeMetrics: Send + Sync {
+    /// Called after `n` bytes have been read from the source
+    fn bytes_read(&self, name: &str, direction: Direction, n: usize);
+    /// Called after `n` bytes have been written to the sink
+    fn bytes_written(&self, name: &str, direction: Direction, n: usize);
+    /// Called when the pipe stops reading from its source because its sink is behind
+    /// (`engaged`), and when it starts again, see `PipeOptions::write_buf_high_water_mark`.
+    /// Tells when one side of a connection is outpacing the other. Does nothing by default
+    fn backpressure_changed(
+        &self,
+        _name: &str,
+        _connection_id: u64,
+        _direction: Direction,
+        _engaged: bool,
+    ) {
+    }
+}
+
+impl fmt::Debug for dyn PipeMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PipeMetrics")
+    }
+}
+
+/// Sees the bytes of every read from the source, before they are framed into packets,
+/// e.g. to dump them for debugging. Closures taking the direction and bytes implement it.
+/// Called inline on the pipe's task, so it should not block
+pub trait RawTap: Send + Sync {
+    fn tap(&self, direction: Direction, bytes: &[u8]);
+}
+
+impl<F: Fn(Direction, &[u8]) + Send + Sync> RawTap for F {
+    fn tap(&self, direction: Direction, bytes: &[u8]) {
+        self(direction, bytes)
+    }
+}
+
+impl fmt::Debug for dyn RawTap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RawTap")
+    }
+}
+
+/// A log record of a pipe, see `PipeOptions::log_sink`
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEvent {
+    pub level: Level,
+    pub pipe_name: String,
+    pub connection_id: u64,
+    pub direction: Direction,
+    /// What happened, e.g. "Other pipe closed"
+    pub event: String,
+    /// The number of bytes involved, for reads, writes and the like
+    pub bytes: Option<usize>,
+}
+
+/// Receives a pipe's log records in place of the `log` macros, e.g. to emit them as JSON.
+/// Closures taking a `LogEvent` implement it. Records of every level are passed on,
+/// filtering is up to the sink. Called inline on the pipe's task, so it should not block
+pub trait LogSink: Send + Sync {
+    fn log(&self, event: LogEvent);
+}
+
+impl<F: Fn(LogEvent) + Send + Sync> LogSink for F {
+    fn log(&self, event: LogEvent) {
+        self(event)
+    }
+}
+
+impl fmt::Debug for dyn LogSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LogSink")
+    }
+}
+
+/// Why `Pipe::run` returned. `run` returns the clean closes as `Ok`, the rest as `Err`
+#[derive(Debug)]
+pub enum CloseReason {
+    /// The source closed, e.g. a client that disconnected between queries
+    SourceClosed,
+    /// The other pipe of the connection finished, so nothing more can be sent to the database
+    PeerClosed,
+    /// The kill switch fired
+    Killed,
+    /// The MariaDB client sent COM_QUIT, after which the database closed the connection
+    ClientQuit,
+    /// The database closed while the client was still connected
+    DatabaseClosed,
+    /// Nothing was read from the source or the other pipe for this long
+    IdleTimeout(Duration),
+    /// The pipe ran for `PipeOptions::max_connection_lifetime`
+    LifetimeExceeded,
+    /// A packet would not fit in `PipeOptions::max_packet_size`
+    PacketTooLarge { size: usize, max_packet_size: usize },
+    /// The source sent something that is not a packet, after which the stream can't be followed
+    MalformedPacket(String),
+    /// The client negotiated a protocol feature the pipe can't follow
+    UnsupportedProtocol(String),
+    /// The handler panicked while handling a packet
+    HandlerPanicked,
+    /// Reading from the source failed, e.g. because its peer reset the connection
+    SourceReadError {
+        name: String,
+        direction: Direction,
+        error: Error,
+    },
+    /// Writing to the sink failed, e.g. because its peer went away.
+    /// With `SourceReadError`, tells which side of the connection hung up
+    SinkWriteError {
+        name: String,
+        direction: Direction,
+        error: Error,
+    },
+    /// Reaching the other pipe, or any other I/O, failed
+    Io(Error),
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloseReason::SourceClosed => write!(f, "Source closed"),
+            CloseReason::PeerClosed => write!(f, "Other pipe closed"),
+            CloseReason::Killed => write!(f, "Killed"),
+            CloseReason::ClientQuit => write!(f, "Client quit"),
+            CloseReason::DatabaseClosed => write!(f, "Database closed the connection"),
+            CloseReason::IdleTimeout(d) => write!(f, "Idle for {:?}", d),
+            CloseReason::LifetimeExceeded => write!(f, "Maximum connection lifetime exceeded"),
+            CloseReason::PacketTooLarge {
+                size,
+                max_packet_size,
+            } => write!(
+                f,
+                "Packet of {} bytes exceeds max_packet_size of {} bytes",
+                size, max_packet_size
+            ),
+            CloseReason::MalformedPacket(msg) | CloseReason::UnsupportedProtocol(msg) => {
+                write!(f, "{}", msg)
+            }
+            CloseReason::HandlerPanicked => write!(f, "Handler panicked"),
+            CloseReason::SourceReadError {
+                name,
+                direction,
+                error,
+            } => write!(
+                f,
+                "[{}:{:?}]: Reading from the source failed: {}",
+                name, direction, error
+            ),
+            CloseReason::SinkWriteError {
+                name,
+                direction,
+                error,
+            } => write!(
+                f,
+                "[{}:{:?}]: Writing to the sink failed: {}",
+                name, direction, error
+            ),
+            CloseReason::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CloseReason {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CloseReason::SourceReadError { error, .. }
+            | CloseReason::SinkWriteError { error, .. }
+            | CloseReason::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for CloseReason {
+    fn from(e: Error) -> CloseReason {
+        CloseReason::Io(e)
+    }
+}
+
+impl From<FramingError> for CloseReason {
+    fn from(e: FramingError) -> CloseReason {
+        match e {
+            FramingError::TooLarge {
+                size,
+                max_packet_size,
+            } => CloseReason::PacketTooLarge {
+                size,
+                max_packet_size,
+            },
+            FramingError::Malformed(msg) => CloseReason::MalformedPacket(msg),
+        }
+    }
+}
+
+impl From<CloseReason> for Error {
+    fn from(reason: CloseReason) -> Error {
+        let kind = match reason {
+            CloseReason::Io(e) => return e,
+            CloseReason::SourceReadError { ref error, .. }
+            | CloseReason::SinkWriteError { ref error, .. } => error.kind(),
+            CloseReason::IdleTimeout(_) => ErrorKind::TimedOut,
+            CloseReason::PacketTooLarge { .. } | CloseReason::MalformedPacket(_) => {
+                ErrorKind::InvalidData
+            }
+            CloseReason::DatabaseClosed => ErrorKind::ConnectionAborted,
+            _ => ErrorKind::Other,
+        };
+        Error::new(kind, reason.to_string())
+    }
+}
+
+/// Options controlling the behavior of a Pipe
+#[derive(Clone, Debug)]
+pub struct PipeOptions {
+    /// Let clients negotiate SSL with the database, instead of asking the handler's
+    /// `on_ssl_request`. Once the database accepts, both pipes of the connection stop
+    /// framing packets and copy the encrypted stream verbatim
+    pub allow_ssl_passthrough: bool,
+    /// Size of the buffer each read from the client goes into, in the forward pipe
+    /// (default 4096 bytes). Requests are usually small
+    pub forward_buf_size: usize,
+    /// Size of the buffer each read from the database goes into, in the backward pipe
+    /// (default 4096 bytes). Larger buffers take fewer reads for big result sets
+    pub backward_buf_size: usize,
+    /// Largest packet (header included) the pipe will buffer before closing the connection,
+    /// for either database. Defaults to `DEFAULT_MAX_PACKET_SIZE`, 16 MiB.
+    /// This also stops a misaligned stream, whose headers are garbage, from wedging the pipe
+    /// on a huge bogus length. A bogus length below the limit is caught by `idle_timeout`
+    pub max_packet_size: usize,
+    /// Close the pipe if nothing has been read from the source or the other pipe for this long.
+    /// Pipes sharing an `Activity` only time out once neither has read anything, so a
+    /// connection waiting on a slow response is idle only if the response stalls too.
+    /// Writes don't count. `None` (the default) never times out
+    pub idle_timeout: Option<Duration>,
+    /// Close the pipe once it has been running this long, however busy it is, e.g. so that
+    /// clients reconnect and pick up DNS or certificate changes on the database side.
+    /// Both pipes of a connection start together, so both close, flushing what they have
+    /// processed, as with the kill switch. `None` (the default) lets connections live forever
+    pub max_connection_lifetime: Option<Duration>,
+    /// Optional hook for byte counts. `None` (the default) has no overhead
+    pub metrics: Option<Arc<dyn PipeMetrics>>,
+    /// Optional hook for the raw bytes read. `None` (the default) has no overhead
+    pub raw_tap: Option<Arc<dyn RawTap>>,
+    /// Where the pipe's log records go. `None` (the default) logs through the `log` macros,
+    /// prefixed with `[name#connection_id:direction]`
+    pub log_sink: Option<Arc<dyn LogSink>>,
+    /// Stop reading from the source once this many bytes are waiting to be written to the sink
+    /// (default 1 MiB)
+    pub write_buf_high_water_mark: usize,
+    /// Resume reading from the source once the sink has drained to this many bytes
+    /// (default 256 KiB)
+    pub write_buf_low_water_mark: usize,
+    /// Combine MariaDB payloads split across maximum-length (0xFFFFFF) packets into one
+    /// logical packet before calling the handler, and split them again on the way out.
+    /// A logical packet keeps the header of its first packet. `max_packet_size` applies to
+    /// the logical packet. Without this, handlers get each packet on its own, and can tell
+    /// one that is continued with `Packet::is_mariadb_continuation`
+    pub reassemble_packets: bool,
+    /// If the handler takes longer than this (including waiting for the handler lock),
+    /// the original packet is forwarded unchanged. `None` (the default) waits indefinitely
+    pub handler_timeout: Option<Duration>,
+    /// Every MariaDB COM_QUERY and PostgresSQL Query ('Q') from the client is sent here as a
+    /// `QueryEvent`.
+    /// Events are dropped while the channel is full, so a slow consumer never stalls the pipe.
+    /// `None` (the default) sends nothing
+    pub query_events: Option<Sender<QueryEvent>>,
+    /// How long a closing pipe keeps trying to write what is left for the sink (default 5s)
+    pub drain_timeout: Duration,
+    /// Queries whose response takes longer than this to complete are logged with `warn!`,
+    /// see `QueryTimer`. `None` (the default) times nothing
+    pub slow_query_threshold: Option<Duration>,
+    /// Once there is something to write to the sink, wait this long for more before writing,
+    /// so packets from several reads go out in one write. Trades a little latency for fewer
+    /// syscalls under load. Writes start early once `write_buf_high_water_mark` is reached.
+    /// `None` (the default) writes as soon as possible
+    pub write_linger: Option<Duration>,
+    /// SQL the backward pipe runs on the database once the client has authenticated, before
+    /// passing on the end of authentication, e.g. `SET NAMES utf8mb4`. Their responses are
+    /// not passed on to the client, failures are logged with `warn!`. See `InitCommands` for
+    /// how the end of authentication is found. A MariaDB client whose authentication was
+    /// relayed by `tls::accept_mariadb` is past it before the pipes start, so nothing is run.
+    /// Empty (the default) runs nothing
+    pub init_commands: Vec<String>,
+    /// Caps how fast the pipe reads from its source, in raw bytes. Each pipe of a connection
+    /// has its own limit, so the forward pipe's caps what a client sends, and the backward
+    /// pipe's what it receives. `None` (the default) reads as fast as the source allows
+    pub byte_rate_limit: Option<ByteRateLimit>,
+    /// Holds back what the pipe writes to its sink, for testing how clients and databases
+    /// cope with a slow network. Reading goes on in the meantime, `write_buf_high_water_mark`
+    /// counts what is held back, and closing the pipe writes it at once.
+    /// `None` (the default) writes without delay
+    pub delay: Option<DelayConfig>,
+}
+
+/// Latency added by a pipe, see `PipeOptions::delay`.
+/// The unit of delay is what the pipe writes for one read from its source, usually a single
+/// packet, or for one short-circuited packet. With `probability` (from 0.0 to 1.0, for always)
+/// it is held back for `base` plus up to `jitter`. Order is kept, so anything written after
+/// held back output waits for it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DelayConfig {
+    pub base: Duration,
+    pub jitter: Duration,
+    pub probability: f64,
+}
+
+impl Default for DelayConfig {
+    fn default() -> DelayConfig {
+        DelayConfig {
+            base: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            probability: 1.0,
+        }
+    }
+}
+
+/// A token bucket of bytes, see `PipeOptions::byte_rate_limit`.
+/// Up to `burst` bytes can be read at once, after which reads average `bytes_per_second`.
+/// A single read can take more than is left, up to the pipe's read buffer size,
+/// the pipe then waits for the bucket to refill before reading again
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ByteRateLimit {
+    pub bytes_per_second: u64,
+    pub burst: u64,
+}
+
+impl PipeOptions {
+    /// Size of the read buffer of a pipe going in `direction`
+    pub fn read_buf_size(&self, direction: Direction) -> usize {
+        match direction {
+            Direction::Forward => self.forward_buf_size,
+            Direction::Backward => self.backward_buf_size,
+        }
+    }
+}
+
+impl Default for PipeOptions {
+    fn default() -> PipeOptions {
+        PipeOptions {
+            allow_ssl_passthrough: false,
+            forward_buf_size: 4096,
+            backward_buf_size: 4096,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            idle_timeout: None,
+            max_connection_lifetime: None,
+            metrics: None,
+            raw_tap: None,
+            log_sink: None,
+            write_buf_high_water_mark: 1024 * 1024,
+            write_buf_low_water_mark: 256 * 1024,
+            reassemble_packets: false,
+            handler_timeout: None,
+            query_events: None,
+            drain_timeout: Duration::from_secs(5),
+            slow_query_threshold: None,
+            write_linger: None,
+            init_commands: Vec::new(),
+            byte_rate_limit: None,
+            delay: None,
+        }
+    }
+}
+
+const SSL_NONE: u8 = 0;
+const SSL_REQUESTED: u8 = 1;
+const SSL_ESTABLISHED: u8 = 2;
+
+/// SSL negotiation state shared by the forward and backward pipes of a connection
+#[derive(Debug, Default)]
+pub struct SslState(AtomicU8);
+
+impl SslState {
+    pub fn new() -> SslState {
+        SslState(AtomicU8::new(SSL_NONE))
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == SSL_ESTABLISHED
+    }
+
+    fn set(&self, state: u8) {
+        self.0.store(state, Ordering::SeqCst);
+    }
+
+    fn get(&self) -> u8 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Output held back by `PipeOptions::delay`, in order, with when it is due
+struct DelayLine {
+    config: DelayConfig,
+    held: VecDeque<(Instant, Vec<u8>)>,
+    held_len: usize,
+}
+
+impl DelayLine {
+    fn new(config: DelayConfig) -> DelayLine {
+        DelayLine {
+            config,
+            held: VecDeque::new(),
+            held_len: 0,
+        }
+    }
+
+    /// Holds back what was appended to write_buf after `from`, if it is to be delayed,
+    /// or if earlier output still is
+    fn hold(&mut self, write_buf: &mut Vec<u8>, from: usize) {
+        if write_buf.len() <= from {
+            return;
+        }
+        let delayed = random_fraction() < self.config.probability;
+        if !delayed && self.held.is_empty() {
+            return;
+        }
+        let mut due = Instant::now();
+        if delayed {
+            due += self.config.base + self.config.jitter.mul_f64(random_fraction());
+        }
+        if let Some((last, _bytes)) = self.held.back() {
+            due = due.max(*last);
+        }
+        let bytes = write_buf.split_off(from);
+        self.held_len += bytes.len();
+        self.held.push_back((due, bytes));
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.held.front().map(|(due, _bytes)| *due)
+    }
+
+    /// Moves what is due by `now` to write_buf, or everything if `now` is `None`
+    fn release(&mut self, write_buf: &mut Vec<u8>, now: Option<Instant>) {
+        while let Some(due) = self.next_due() {
+            if now.is_some_and(|now| due > now) {
+                break;
+            }
+            if let Some((_due, bytes)) = self.held.pop_front() {
+                self.held_len -= bytes.len();
+                write_buf.extend_from_slice(&bytes);
+            }
+        }
+    }
+}
+
+/// Between 0.0 and 1.0. Every RandomState is seeded differently, which is random enough for jitter
+pub(crate) fn random_fraction() -> f64 {
+    let hash = RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+/// The bytes a pipe may still read, see `ByteRateLimit`
+struct TokenBucket {
+    limit: ByteRateLimit,
+    /// Negative after a read larger than what was left
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: ByteRateLimit) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// `None` if the pipe may read now, or when it may
+    fn ready_at(&mut self, now: Instant) -> Option<Instant> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.bytes_per_second as f64)
+            .min(self.limit.burst as f64);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            None
+        } else {
+            let wait = (1.0 - self.tokens) / self.limit.bytes_per_second.max(1) as f64;
+            Some(now + Duration::from_secs_f64(wait))
+        }
+    }
+
+    fn take(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+/// Which pipes of a connection have stopped reading from their source because their sink is
+/// behind, shared by the forward and backward pipes of a connection
+#[derive(Debug, Default)]
+pub struct Backpressure(AtomicU8);
+
+impl Backpressure {
+    pub fn new() -> Backpressure {
+        Backpressure::default()
+    }
+
+    /// Whether either pipe has backpressure engaged, see `PipeOptions::write_buf_high_water_mark`
+    pub fn is_engaged(&self) -> bool {
+        self.0.load(Ordering::SeqCst) != 0
+    }
+
+    fn set(&self, direction: Direction, engaged: bool) {
+        let bit = match direction {
+            Direction::Forward => 1,
+            Direction::Backward => 2,
+        };
+        if engaged {
+            self.0.fetch_or(bit, Ordering::SeqCst);
+        } else {
+            self.0.fetch_and(!bit, Ordering::SeqCst);
+        }
+    }
+}
+
+/// When a connection's pipes last read from their sources or passed each other a packet,
+/// shared by the forward and backward pipes of a connection, see `PipeOptions::idle_timeout`
+#[derive(Debug)]
+pub struct Activity(std::sync::Mutex<Instant>);
+
+impl Activity {
+    /// Starts out active now
+    pub fn new() -> Activity {
+        Activity(std::sync::Mutex::new(Instant::now()))
+    }
+
+    pub fn last(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+
+    fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Activity {
+        Activity::new()
+    }
+}
+
+/// Whether a connection's pipes log in detail, shared by its forward and backward pipes.
+/// While set, records the pipes would log at `Debug` or `Trace`, e.g. one per packet, are
+/// logged at `Info`, so one connection can be followed without turning on `trace` for all
+#[derive(Debug, Default)]
+pub struct Verbose(AtomicBool);
+
+impl Verbose {
+    pub fn new() -> Verbose {
+        Verbose::default()
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Takes effect from the pipes' next record on
+    pub fn set(&self, verbose: bool) {
+        self.0.store(verbose, Ordering::Relaxed);
+    }
+}
+
+/// Live counters of a pipe, readable from other tasks while the pipe runs
+#[derive(Debug, Default)]
+pub struct PipeStats {
+    packets_processed: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    malformed_packets: AtomicU64,
+    packet_buf_len: AtomicUsize,
+    write_buf_len: AtomicUsize,
+}
+
+impl PipeStats {
+    pub fn new() -> PipeStats {
+        PipeStats::default()
+    }
+
+    /// Packets read from the source and given to the pipe's logic
+    pub fn packets_processed(&self) -> u64 {
+        self.packets_processed.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read from the source
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written to the sink
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Packets that could not be framed, e.g. with an invalid or oversized length,
+    /// which close the connection, and partial packets left when the source closed
+    pub fn malformed_packets(&self) -> u64 {
+        self.malformed_packets.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read from the source that don't make a complete packet yet, as of the last
+    /// iteration of the pipe's loop. A backlog here means the source is slow to finish a packet
+    pub fn packet_buf_len(&self) -> usize {
+        self.packet_buf_len.load(Ordering::Relaxed)
+    }
+
+    /// Bytes waiting to be written to the sink, as of the last iteration of the pipe's loop.
+    /// A backlog here means the sink is slow
+    pub fn write_buf_len(&self) -> usize {
+        self.write_buf_len.load(Ordering::Relaxed)
+    }
+
+    fn sample_buffers(&self, packet_buf: &BytesMut, write_buf: &[u8]) {
+        self.packet_buf_len
+            .store(packet_buf.len(), Ordering::Relaxed);
+        self.write_buf_len.store(write_buf.len(), Ordering::Relaxed);
+    }
+}
+
+/// Builds a Pipe from its required parts, with everything else optional:
+/// ```ignore
+/// let pipe = PipeBuilder::new(name, db_type, handler, Direction::Forward, reader, writer)
+///     .with_idle_timeout(Duration::from_secs(600))
+///     .with_ssl_state(ssl_state.clone())
+///     .build();
+/// ```
+pub struct PipeBuilder<T: AsyncReadExt, U: AsyncWriteExt> {
     name: String,
     db_type: DatabaseType,
     packet_handler: Arc<Mutex<dyn PacketHandler + Send>>,
     direction: Direction,
-    source: T,
-    sink: U,
+    reader: T,
+    writer: U,
+    options: PipeOptions,
+    ssl_state: Option<Arc<SslState>>,
+    connection_id: u64,
+    session: Option<Arc<SessionTracker>>,
+    query_timer: Option<Arc<QueryTimer>>,
+    requests: Option<Arc<RequestTracker>>,
+    backpressure: Option<Arc<Backpressure>>,
+    activity: Option<Arc<Activity>>,
+    verbose: Option<Arc<Verbose>>,
+    proxy_header: Option<Vec<u8>>,
 }
 
-impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> Pipe<T, U> {
+impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> PipeBuilder<T, U> {
     pub fn new(
         name: String,
         db_type: DatabaseType,
@@ -38,211 +703,2923 @@ impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> Pipe<T, U> {
         direction: Direction,
         reader: T,
         writer: U,
-    ) -> Pipe<T, U> {
-        Pipe {
+    ) -> PipeBuilder<T, U> {
+        PipeBuilder {
             name,
             db_type,
             packet_handler,
             direction,
-            source: reader,
-            sink: writer,
+            reader,
+            writer,
+            options: PipeOptions::default(),
+            ssl_state: None,
+            connection_id: 0,
+            session: None,
+            query_timer: None,
+            requests: None,
+            backpressure: None,
+            activity: None,
+            verbose: None,
+            proxy_header: None,
         }
     }
 
-    pub async fn run(
-        &mut self,
-        mut other_pipe_sender: Sender<Packet>,
-        other_pipe_receiver: Receiver<Packet>,
-    ) -> Result<()> {
-        trace!("[{}]: Running {:?} pipe loop...", self.name, self.direction);
-        //let source = Arc::get_mut(&mut self.source).unwrap();
-        //let sink = Arc::get_mut(&mut self.sink).unwrap();
-        let mut other_pipe_receiver = other_pipe_receiver.into_future().fuse();
-        let mut read_buf: Vec<u8> = vec![0_u8; 4096];
-        let mut packet_buf: Vec<u8> = Vec::with_capacity(4096);
-        let mut write_buf: Vec<u8> = Vec::with_capacity(4096);
+    /// Replaces all options, including any set by the other `with_*` option setters so far
+    pub fn with_options(mut self, options: PipeOptions) -> PipeBuilder<T, U> {
+        self.options = options;
+        self
+    }
 
-        loop {
-            select! {
-                // Read from the source to read_buf, append to packet_buf
-                read_result = self.source.read(&mut read_buf[..]).fuse() => {
-                    //let n = self.source.read(&mut read_buf[..]).await?;
-                    self.process_read_buf(read_result, &read_buf, &mut packet_buf, &mut write_buf, &mut other_pipe_sender).await?;
-                },
-                // Support short-circuit
-                (packet, recv) = other_pipe_receiver => {
-                    self.process_short_circuit(packet, &mut write_buf)?;
-                    other_pipe_receiver = recv.into_future().fuse();
-                },
-            } // end select!
+    /// Sets the read buffer size for this pipe's direction,
+    /// see `PipeOptions::forward_buf_size` and `PipeOptions::backward_buf_size`
+    pub fn with_read_buf_size(mut self, size: usize) -> PipeBuilder<T, U> {
+        match self.direction {
+            Direction::Forward => self.options.forward_buf_size = size,
+            Direction::Backward => self.options.backward_buf_size = size,
+        }
+        self
+    }
 
-            // Write all to sink
-            while !write_buf.is_empty() {
-                let n = self.sink.write(&write_buf[..]).await?;
-                let _: Vec<u8> = write_buf.drain(0..n).collect();
-                self.trace(format!("{} bytes written to sink", n));
-            }
-        } // end loop
-    } // end fn run
+    /// See `PipeOptions::max_packet_size`
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> PipeBuilder<T, U> {
+        self.options.max_packet_size = max_packet_size;
+        self
+    }
 
-    async fn process_read_buf(
-        &self,
-        read_result: Result<usize>,
-        read_buf: &[u8],
-        mut packet_buf: &mut Vec<u8>,
-        write_buf: &mut Vec<u8>,
-        other_pipe_sender: &mut Sender<Packet>,
-    ) -> Result<()> {
-        if let Ok(n) = read_result {
-            if n == 0 {
-                let e = self.create_error(format!("Read {} bytes, closing pipe.", n));
-                warn!("{}", e.to_string());
-                return Err(e);
-            }
-            packet_buf.extend_from_slice(&read_buf[0..n]);
-            self.trace(format!(
-                "{} bytes read from source, {} bytes in packet_buf",
-                n,
-                packet_buf.len()
-            ));
+    /// See `PipeOptions::idle_timeout`
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> PipeBuilder<T, U> {
+        self.options.idle_timeout = Some(idle_timeout);
+        self
+    }
 
-            // Process all packets in packet_buf, put into write_buf
-            while let Some(packet) = get_packet(self.db_type, &mut packet_buf) {
-                self.trace("Processing packet".to_string());
-                // TODO: support SSL. For now, respond that we don't support SSL
-                // https://www.postgresql.org/docs/12/protocol-flow.html#id-1.10.5.7.11
-                if let Ok(PacketType::SSLRequest) = packet.get_packet_type() {
-                    self.debug("Got SSLRequest, responding no thanks".to_string());
-                    if let Err(_e) = other_pipe_sender
-                        .send(Packet::new(self.db_type, String::from("N").into_bytes()))
-                        .await
-                    {
-                        return Err(
-                            self.create_error("Error sending SSL response of no".to_string())
-                        );
-                    }
-                } else {
-                    let transformed_packet: Packet;
-                    {
-                        // Scope for self.packet_handler Mutex
-                        let mut h = self.packet_handler.lock().await;
-                        transformed_packet = match self.direction {
-                            Direction::Forward => h.handle_request(&packet).await,
-                            Direction::Backward => h.handle_response(&packet).await,
-                        };
-                    }
-                    write_buf.extend_from_slice(&transformed_packet.bytes);
-                }
-            } // end while
-            Ok(())
-        } else if let Err(e) = read_result {
-            warn!(
-                "[{}:{:?}]: Error reading from source",
-                self.name, self.direction
-            );
-            Err(e)
-        } else {
-            Err(Error::new(ErrorKind::Other, "This should never happen"))
-        }
+    /// See `PipeOptions::max_connection_lifetime`
+    pub fn with_max_connection_lifetime(mut self, lifetime: Duration) -> PipeBuilder<T, U> {
+        self.options.max_connection_lifetime = Some(lifetime);
+        self
     }
 
-    fn process_short_circuit(&self, packet: Option<Packet>, write_buf: &mut Vec<u8>) -> Result<()> {
-        if let Some(p) = packet {
-            self.trace(format!(
-                "Got short circuit packet of {} bytes",
-                p.get_size()
-            ));
-            write_buf.extend_from_slice(&p.bytes);
-            Ok(())
-        } else {
-            let e = self.create_error("other_pipe_receiver prematurely closed".to_string());
-            warn!("{}", e.to_string());
-            Err(e)
-        }
+    /// See `PipeOptions::handler_timeout`
+    pub fn with_handler_timeout(mut self, handler_timeout: Duration) -> PipeBuilder<T, U> {
+        self.options.handler_timeout = Some(handler_timeout);
+        self
     }
 
-    fn debug(&self, string: String) {
-        debug!("[{}:{:?}]: {}", self.name, self.direction, string);
+    /// See `PipeOptions::write_linger`
+    pub fn with_write_linger(mut self, write_linger: Duration) -> PipeBuilder<T, U> {
+        self.options.write_linger = Some(write_linger);
+        self
     }
 
-    fn trace(&self, string: String) {
-        trace!("[{}:{:?}]: {}", self.name, self.direction, string);
+    /// See `PipeOptions::init_commands`
+    pub fn with_init_commands(mut self, init_commands: Vec<String>) -> PipeBuilder<T, U> {
+        self.options.init_commands = init_commands;
+        self
     }
 
-    fn create_error(&self, string: String) -> Error {
-        Error::new(
-            ErrorKind::Other,
-            format!("[{}:{:?}]: {}", self.name, self.direction, string),
-        )
+    /// See `PipeOptions::byte_rate_limit`
+    pub fn with_byte_rate_limit(mut self, limit: ByteRateLimit) -> PipeBuilder<T, U> {
+        self.options.byte_rate_limit = Some(limit);
+        self
     }
-} // end impl
 
-fn get_packet(db_type: DatabaseType, packet_buf: &mut Vec<u8>) -> Option<Packet> {
-    match db_type {
-        DatabaseType::MariaDB => {
-            // Check for header
-            if packet_buf.len() < 4 {
-                return None;
-            }
-            let l: usize = (((packet_buf[2] as u32) << 16)
-                | ((packet_buf[1] as u32) << 8)
-                | packet_buf[0] as u32) as usize;
-            let s = 4 + l;
-            // Check for entire packet size
-            if packet_buf.len() < s {
-                return None;
-            }
-            Some(Packet::new(
-                DatabaseType::MariaDB,
-                packet_buf.drain(0..s).collect(),
-            ))
-        } // end MariaDB
-        DatabaseType::PostgresSQL => {
-            // Nothing in packet_buf
-            if packet_buf.is_empty() {
-                trace!(
-                    "get_packet(PostgresSQL): FAIL packet_buf(size={}) trying to read first byte",
-                    packet_buf.len()
-                );
-                return None;
-            }
-            let id = packet_buf[0] as char;
-            let mut size = 0;
-            if POSTGRES_IDS.contains(&id) {
-                size += 1;
-            }
+    /// See `PipeOptions::delay`
+    pub fn with_delay(mut self, delay: DelayConfig) -> PipeBuilder<T, U> {
+        self.options.delay = Some(delay);
+        self
+    }
 
-            // Check if I can read the length field
-            if packet_buf.len() < (size + 4) {
-                trace!(
-                    "get_packet(PostgresSQL): FAIL packet_buf(size={}) trying to read length, firstbyte={:#04x}={}, size={}",
-                    packet_buf.len(), packet_buf[0], id, size+4
-                );
-                return None;
-            }
-            let length = BigEndian::read_u32(&packet_buf[size..(size + 4)]) as usize; // read length
-            size += length;
+    /// See `PipeOptions::raw_tap`
+    pub fn with_raw_tap(mut self, raw_tap: Arc<dyn RawTap>) -> PipeBuilder<T, U> {
+        self.options.raw_tap = Some(raw_tap);
+        self
+    }
 
-            // Check if don't have entire packet
-            if packet_buf.len() < size {
-                trace!(
-                    "get_packet(PostgresSQL): FAIL packet_buf(size={}) too small, firstbyte={:#04x}={}, size={}, length={}",
-                    packet_buf.len(), packet_buf[0], id, size, length
-                );
-                return None;
-            }
-            trace!(
-                "get_packet(PostgresSQL): SUCCESS firstbyte={:#04x}={}, size={}, length={}",
-                packet_buf[0],
-                id,
-                size,
-                length
-            );
+    /// See `PipeOptions::log_sink`
+    pub fn with_log_sink(mut self, log_sink: Arc<dyn LogSink>) -> PipeBuilder<T, U> {
+        self.options.log_sink = Some(log_sink);
+        self
+    }
+
+    /// Both pipes of a connection must be given the same `ssl_state`.
+    /// By default each pipe has its own
+    pub fn with_ssl_state(mut self, ssl_state: Arc<SslState>) -> PipeBuilder<T, U> {
+        self.ssl_state = Some(ssl_state);
+        self
+    }
+
+    /// See `Pipe::with_connection_id`
+    pub fn with_connection_id(mut self, connection_id: u64) -> PipeBuilder<T, U> {
+        self.connection_id = connection_id;
+        self
+    }
+
+    /// See `Pipe::with_session`
+    pub fn with_session(mut self, session: Arc<SessionTracker>) -> PipeBuilder<T, U> {
+        self.session = Some(session);
+        self
+    }
+
+    /// See `Pipe::with_query_timer`
+    pub fn with_query_timer(mut self, query_timer: Arc<QueryTimer>) -> PipeBuilder<T, U> {
+        self.query_timer = Some(query_timer);
+        self
+    }
+
+    /// See `Pipe::with_request_tracker`
+    pub fn with_request_tracker(mut self, requests: Arc<RequestTracker>) -> PipeBuilder<T, U> {
+        self.requests = Some(requests);
+        self
+    }
+
+    /// See `Pipe::with_backpressure`
+    pub fn with_backpressure(mut self, backpressure: Arc<Backpressure>) -> PipeBuilder<T, U> {
+        self.backpressure = Some(backpressure);
+        self
+    }
+
+    /// See `Pipe::with_activity`
+    pub fn with_activity(mut self, activity: Arc<Activity>) -> PipeBuilder<T, U> {
+        self.activity = Some(activity);
+        self
+    }
 
-            Some(Packet::new(
-                DatabaseType::PostgresSQL,
-                packet_buf.drain(0..size).collect(),
-            ))
-        } // end PostgresSQL
-    } // end match
-} // end get_packet
+    /// See `Pipe::with_verbose`
+    pub fn with_verbose(mut self, verbose: Arc<Verbose>) -> PipeBuilder<T, U> {
+        self.verbose = Some(verbose);
+        self
+    }
+
+    /// See `Pipe::with_proxy_header`
+    pub fn with_proxy_header(mut self, header: Vec<u8>) -> PipeBuilder<T, U> {
+        self.proxy_header = Some(header);
+        self
+    }
+
+    pub fn build(self) -> Pipe<T, U> {
+        let mut pipe = Pipe::with_options(
+            self.name,
+            self.db_type,
+            self.packet_handler,
+            self.direction,
+            self.reader,
+            self.writer,
+            self.options,
+            self.ssl_state.unwrap_or_default(),
+        )
+        .with_connection_id(self.connection_id);
+        if let Some(session) = self.session {
+            pipe = pipe.with_session(session);
+        }
+        if let Some(query_timer) = self.query_timer {
+            pipe = pipe.with_query_timer(query_timer);
+        }
+        if let Some(requests) = self.requests {
+            pipe = pipe.with_request_tracker(requests);
+        }
+        if let Some(backpressure) = self.backpressure {
+            pipe = pipe.with_backpressure(backpressure);
+        }
+        if let Some(activity) = self.activity {
+            pipe = pipe.with_activity(activity);
+        }
+        if let Some(verbose) = self.verbose {
+            pipe = pipe.with_verbose(verbose);
+        }
+        pipe.proxy_header = self.proxy_header;
+        pipe
+    }
+}
+
+pub struct Pipe<T: AsyncReadExt, U: AsyncWriteExt> {
+    name: String,
+    db_type: DatabaseType,
+    packet_handler: Arc<Mutex<dyn PacketHandler + Send>>,
+    direction: Direction,
+    source: T,
+    sink: U,
+    options: PipeOptions,
+    ssl_state: Arc<SslState>,
+    connection_id: u64,
+    context: PacketContext,
+    stats: Arc<PipeStats>,
+    // try_send needs a mutable Sender
+    query_events: Option<std::sync::Mutex<Sender<QueryEvent>>>,
+    session: Arc<SessionTracker>,
+    query_timer: Arc<QueryTimer>,
+    requests: Arc<RequestTracker>,
+    backpressure: Arc<Backpressure>,
+    activity: Arc<Activity>,
+    verbose: Arc<Verbose>,
+    proxy_header: Option<Vec<u8>>,
+    init_commands: InitCommands,
+}
+
+impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> Pipe<T, U> {
+    pub fn new(
+        name: String,
+        db_type: DatabaseType,
+        packet_handler: Arc<Mutex<dyn PacketHandler + Send>>,
+        direction: Direction,
+        reader: T,
+        writer: U,
+    ) -> Pipe<T, U> {
+        Pipe::with_options(
+            name,
+            db_type,
+            packet_handler,
+            direction,
+            reader,
+            writer,
+            PipeOptions::default(),
+            Arc::new(SslState::new()),
+        )
+    }
+
+    /// Both pipes of a connection must be given the same `ssl_state`
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_options(
+        name: String,
+        db_type: DatabaseType,
+        packet_handler: Arc<Mutex<dyn PacketHandler + Send>>,
+        direction: Direction,
+        reader: T,
+        writer: U,
+        options: PipeOptions,
+        ssl_state: Arc<SslState>,
+    ) -> Pipe<T, U> {
+        let context = PacketContext {
+            db_type,
+            direction,
+            pipe_name: name.clone(),
+            connection_id: 0,
+            session: Default::default(),
+            backpressure: false,
+            write_buf_len: 0,
+            handshake_seen: false,
+            handshake_response: false,
+        };
+        let query_events = options.query_events.clone().map(std::sync::Mutex::new);
+        let init_commands = match direction {
+            Direction::Forward => InitCommands::new(Vec::new()),
+            Direction::Backward => InitCommands::new(options.init_commands.clone()),
+        };
+        Pipe {
+            name,
+            db_type,
+            packet_handler,
+            direction,
+            source: reader,
+            sink: writer,
+            options,
+            ssl_state,
+            connection_id: 0,
+            context,
+            stats: Arc::new(PipeStats::new()),
+            query_events,
+            session: Arc::new(SessionTracker::new()),
+            query_timer: Arc::new(QueryTimer::new()),
+            requests: Arc::new(RequestTracker::new()),
+            backpressure: Arc::new(Backpressure::new()),
+            activity: Arc::new(Activity::new()),
+            verbose: Arc::new(Verbose::new()),
+            proxy_header: None,
+            init_commands,
+        }
+    }
+
+    /// Identifies the connection in logs and in the PacketContext given to handlers.
+    /// Both pipes of a connection should have the same id
+    pub fn with_connection_id(mut self, connection_id: u64) -> Pipe<T, U> {
+        self.connection_id = connection_id;
+        self.context.connection_id = connection_id;
+        self
+    }
+
+    /// Both pipes of a connection should share a SessionTracker, which fills in
+    /// `PacketContext::session`. By default each pipe has its own
+    pub fn with_session(mut self, session: Arc<SessionTracker>) -> Pipe<T, U> {
+        self.session = session;
+        self
+    }
+
+    /// Both pipes of a connection must share a QueryTimer for `slow_query_threshold` to work.
+    /// By default each pipe has its own
+    pub fn with_query_timer(mut self, query_timer: Arc<QueryTimer>) -> Pipe<T, U> {
+        self.query_timer = query_timer;
+        self
+    }
+
+    /// Both pipes of a connection must share a RequestTracker for the handler's
+    /// `handle_response_for` to be called. By default each pipe has its own
+    pub fn with_request_tracker(mut self, requests: Arc<RequestTracker>) -> Pipe<T, U> {
+        self.requests = requests;
+        self
+    }
+
+    /// Both pipes of a connection should share a Backpressure, so that
+    /// `PacketContext::backpressure` covers the whole connection. By default each pipe has its own
+    pub fn with_backpressure(mut self, backpressure: Arc<Backpressure>) -> Pipe<T, U> {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Both pipes of a connection should share an Activity, so that neither times out while
+    /// the other is busy, see `PipeOptions::idle_timeout`. By default each pipe has its own
+    pub fn with_activity(mut self, activity: Arc<Activity>) -> Pipe<T, U> {
+        self.activity = activity;
+        self
+    }
+
+    /// Both pipes of a connection should share a Verbose, to turn their detailed logging on
+    /// and off together while they run. By default each pipe has its own, which is unset
+    pub fn with_verbose(mut self, verbose: Arc<Verbose>) -> Pipe<T, U> {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Writes a PROXY protocol header to the sink before anything else,
+    /// see `proxy_protocol::header`. Meant for the forward pipe, whose sink is the database
+    pub fn with_proxy_header(mut self, header: Vec<u8>) -> Pipe<T, U> {
+        self.proxy_header = Some(header);
+        self
+    }
+
+    /// Shared handle to this pipe's counters, for polling while `run` is in progress
+    pub fn stats(&self) -> Arc<PipeStats> {
+        self.stats.clone()
+    }
+
+    pub fn packets_processed(&self) -> u64 {
+        self.stats.packets_processed()
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.stats.bytes_in()
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.stats.bytes_out()
+    }
+
+    pub fn malformed_packets(&self) -> u64 {
+        self.stats.malformed_packets()
+    }
+
+    pub fn packet_buf_len(&self) -> usize {
+        self.stats.packet_buf_len()
+    }
+
+    pub fn write_buf_len(&self) -> usize {
+        self.stats.write_buf_len()
+    }
+
+    /// Runs until the source closes, an error occurs, or the kill switch fires.
+    /// On kill switch, anything already processed is written to the sink before returning `Ok`.
+    /// When the source closes, the same happens and the sink is shut down, so a client that
+    /// half-closes still gets its final responses from the other pipe.
+    /// The database closing before the other pipe has finished is an error.
+    /// When closing on an error, whatever was already processed is still written to the sink,
+    /// on a best-effort basis, see `PipeOptions::drain_timeout`
+    pub async fn run(
+        &mut self,
+        other_pipe_sender: Sender<Packet>,
+        other_pipe_receiver: Receiver<Packet>,
+        kill_switch_receiver: oneshot::Receiver<()>,
+    ) -> std::result::Result<CloseReason, CloseReason> {
+        let mut write_buf: Vec<u8> = Vec::with_capacity(4096);
+        let result = self
+            .run_loop(
+                other_pipe_sender,
+                other_pipe_receiver,
+                kill_switch_receiver,
+                &mut write_buf,
+            )
+            .await;
+        if result.is_err() && !write_buf.is_empty() {
+            if let Err(e) = self.drain(&mut write_buf).await {
+                self.debug(format!("Unable to flush the sink before closing: {}", e));
+            }
+        }
+        result
+    }
+
+    async fn run_loop(
+        &mut self,
+        mut other_pipe_sender: Sender<Packet>,
+        other_pipe_receiver: Receiver<Packet>,
+        kill_switch_receiver: oneshot::Receiver<()>,
+        write_buf: &mut Vec<u8>,
+    ) -> std::result::Result<CloseReason, CloseReason> {
+        self.trace("Running pipe loop...".to_string());
+        //let source = Arc::get_mut(&mut self.source).unwrap();
+        //let sink = Arc::get_mut(&mut self.sink).unwrap();
+        let mut other_pipe_receiver = other_pipe_receiver.into_future().fuse();
+        let mut kill_switch_receiver = kill_switch_receiver.fuse();
+        // Set when this pipe should flush and close
+        let mut closing: Option<CloseReason> = None;
+        // Set once the other pipe has finished, e.g. because its source half-closed
+        let mut peer_closed = false;
+        let mut read_buf: Vec<u8> = vec![0_u8; self.options.read_buf_size(self.direction)];
+        // Packets are split off packet_buf without copying
+        let mut packet_buf = BytesMut::with_capacity(4096);
+        let idle_timeout = self.options.idle_timeout;
+        let expires_at = self
+            .options
+            .max_connection_lifetime
+            .map(|d| Instant::now() + d);
+
+        let mut backpressure = false;
+        if self.init_commands.is_pending() && self.session.authenticated() {
+            self.warn(
+                "Client authenticated before the pipe started, skipping init commands".to_string(),
+            );
+        }
+        // When the current batch of writes may start, see `PipeOptions::write_linger`
+        let mut linger_until: Option<Instant> = None;
+        let mut bucket = self.options.byte_rate_limit.map(TokenBucket::new);
+        let mut delay_line = self.options.delay.map(DelayLine::new);
+
+        if let Some(header) = self.proxy_header.take() {
+            self.log(
+                Level::Trace,
+                "Writing PROXY header".to_string(),
+                Some(header.len()),
+            );
+            self.sink
+                .write_all(&header)
+                .await
+                .map_err(|e| self.sink_write_error(e))?;
+            self.sink
+                .flush()
+                .await
+                .map_err(|e| self.sink_write_error(e))?;
+        }
+
+        loop {
+            if let Some(delay_line) = delay_line.as_mut() {
+                delay_line.release(write_buf, Some(Instant::now()));
+            }
+            self.stats.sample_buffers(&packet_buf, write_buf);
+            // Stop reading from the source while the sink is behind
+            let pending = write_buf.len() + delay_line.as_ref().map_or(0, |d| d.held_len);
+            let was_engaged = backpressure;
+            if pending >= self.options.write_buf_high_water_mark {
+                backpressure = true;
+            } else if pending <= self.options.write_buf_low_water_mark {
+                backpressure = false;
+            }
+            if backpressure != was_engaged {
+                self.backpressure_changed(backpressure, pending);
+            }
+            self.backpressure.set(self.direction, backpressure);
+            if write_buf.is_empty() {
+                linger_until = None;
+            } else if linger_until.is_none() {
+                linger_until = self.options.write_linger.map(|d| Instant::now() + d);
+            }
+            let lingering = match linger_until {
+                Some(t) => Instant::now() < t && !backpressure,
+                None => false,
+            };
+            let throttled_until = bucket.as_mut().and_then(|b| b.ready_at(Instant::now()));
+            let idle_at = idle_timeout.map(|d| self.activity.last() + d);
+            let next_due = delay_line.as_ref().and_then(DelayLine::next_due);
+            let read_future = if backpressure || throttled_until.is_some() {
+                Fuse::terminated()
+            } else {
+                self.source.read(&mut read_buf[..]).fuse()
+            };
+            let write_future = if write_buf.is_empty() || lingering {
+                Fuse::terminated()
+            } else {
+                self.sink.write(&write_buf[..]).fuse()
+            };
+            pin_mut!(read_future, write_future);
+
+            // Biased so that pending writes always go out before the next read is processed
+            select_biased! {
+                // Write from write_buf to the sink
+                write_result = write_future => {
+                    let n = write_result.map_err(|e| self.sink_write_error(e))?;
+                    self.record_write(write_buf, n);
+                    // Buffered sinks, e.g. TLS streams, hold small writes until flushed
+                    if write_buf.is_empty() {
+                        self.sink.flush().await.map_err(|e| self.sink_write_error(e))?;
+                    }
+                },
+                // Read from the source to read_buf, append to packet_buf
+                read_result = read_future => {
+                    if let (Some(bucket), Ok(n)) = (bucket.as_mut(), &read_result) {
+                        bucket.take(*n);
+                    }
+                    self.activity.touch();
+                    //let n = self.source.read(&mut read_buf[..]).await?;
+                    if let Ok(0) = read_result {
+                        let client_quit = self.session.client_quit();
+                        // A database that closes on its own, rather than after the client
+                        // has gone, is reported to MariaDB clients
+                        if self.direction == Direction::Backward && !peer_closed && !client_quit {
+                            self.warn("Read 0 bytes, closing pipe.".to_string());
+                            let reason = CloseReason::DatabaseClosed;
+                            self.report_database_gone(&reason, write_buf).await;
+                            return Err(reason);
+                        }
+                        if !packet_buf.is_empty() {
+                            self.stats.malformed_packets.fetch_add(1, Ordering::Relaxed);
+                            self.log(
+                                Level::Warn,
+                                "Source closed mid-packet, dropping bytes".to_string(),
+                                Some(packet_buf.len()),
+                            );
+                        }
+                        self.debug("Source closed, flushing and closing the sink".to_string());
+                        closing = Some(if client_quit {
+                            CloseReason::ClientQuit
+                        } else {
+                            CloseReason::SourceClosed
+                        });
+                    } else {
+                        let written = write_buf.len();
+                        if let Err(e) = self.process_read_buf(read_result, &read_buf, &mut packet_buf, write_buf, &mut other_pipe_sender).await {
+                            self.report_database_gone(&e, write_buf).await;
+                            return Err(e);
+                        }
+                        if let Some(delay_line) = delay_line.as_mut() {
+                            delay_line.hold(write_buf, written);
+                        }
+                    }
+                },
+                // Support short-circuit
+                (packet, recv) = other_pipe_receiver => {
+                    if let Some(p) = packet {
+                        self.activity.touch();
+                        let written = write_buf.len();
+                        self.process_short_circuit(p, write_buf);
+                        if let Some(delay_line) = delay_line.as_mut() {
+                            delay_line.hold(write_buf, written);
+                        }
+                        other_pipe_receiver = recv.into_future().fuse();
+                    } else {
+                        // Leave other_pipe_receiver terminated
+                        self.debug("Other pipe closed".to_string());
+                        peer_closed = true;
+                        // Once the database side is done, nothing more can be sent to it.
+                        // The backward pipe keeps going until the database closes
+                        if self.direction == Direction::Forward {
+                            closing = Some(CloseReason::PeerClosed);
+                        }
+                    }
+                },
+                // Wakes the loop up to write the batch
+                _ = timer_until(if lingering { linger_until } else { None }).fuse() => {},
+                // Wakes the loop up once the source may be read again
+                _ = timer_until(throttled_until).fuse() => {},
+                // Wakes the loop up to release delayed output
+                _ = timer_until(next_due).fuse() => {},
+                _ = timer_until(expires_at).fuse() => {
+                    self.debug("Maximum connection lifetime exceeded, closing pipe".to_string());
+                    closing = Some(CloseReason::LifetimeExceeded);
+                },
+                // The other pipe may have been active since, then this only wakes the loop up
+                _ = timer_until(idle_at).fuse() => {
+                    let idle_timeout = idle_timeout.unwrap();
+                    if self.activity.last().elapsed() >= idle_timeout {
+                        let reason = CloseReason::IdleTimeout(idle_timeout);
+                        self.warn(format!("{}, closing pipe.", reason));
+                        return Err(reason);
+                    }
+                },
+                _ = kill_switch_receiver => {
+                    self.debug("Received kill switch, closing pipe".to_string());
+                    closing = Some(CloseReason::Killed);
+                },
+            } // end select_biased!
+            self.stats.sample_buffers(&packet_buf, write_buf);
+
+            if let Some(reason) = closing.take() {
+                // Delays end with the pipe
+                if let Some(delay_line) = delay_line.as_mut() {
+                    delay_line.release(write_buf, None);
+                }
+                // Write all to sink
+                if !write_buf.is_empty() {
+                    self.drain(write_buf)
+                        .await
+                        .map_err(|e| self.sink_write_error(e))?;
+                }
+                if !matches!(reason, CloseReason::Killed | CloseReason::LifetimeExceeded) {
+                    // Pass the half-close on, the other pipe keeps running until its source closes
+                    self.sink
+                        .shutdown()
+                        .await
+                        .map_err(|e| self.sink_write_error(e))?;
+                }
+                return Ok(reason);
+            }
+        } // end loop
+    } // end fn run_loop
+
+    /// Writes all of write_buf to the sink, giving up after `PipeOptions::drain_timeout`
+    async fn drain(&mut self, write_buf: &mut Vec<u8>) -> Result<()> {
+        let n = write_buf.len();
+        let sink = &mut self.sink;
+        let drained = timeout(self.options.drain_timeout, async {
+            sink.write_all(&write_buf[..]).await?;
+            sink.flush().await
+        })
+        .await;
+        match drained {
+            Ok(Ok(())) => {
+                self.record_write(write_buf, n);
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "Timed out after {:?} writing {} bytes to the sink",
+                    self.options.drain_timeout, n
+                ),
+            )),
+        }
+    }
+
+    /// When the database closes or can't be read from, give MariaDB clients a proper ERR
+    /// packet instead of a broken socket. Other errors, e.g. a malformed packet or a failing
+    /// handler, don't mean the database is gone. Nothing is sent in the clear into an
+    /// established SSL session. Best-effort, write errors are ignored, and the write gives up
+    /// after `PipeOptions::drain_timeout`
+    async fn report_database_gone(&mut self, reason: &CloseReason, write_buf: &mut Vec<u8>) {
+        let database_gone = matches!(
+            reason,
+            CloseReason::DatabaseClosed | CloseReason::SourceReadError { .. }
+        );
+        if self.direction != Direction::Backward
+            || self.db_type != DatabaseType::MariaDB
+            || !database_gone
+            || self.ssl_state.is_established()
+        {
+            return;
+        }
+        let err =
+            Packet::error_packet_mariadb(2006, *b"HY000", "MySQL server has gone away".to_string());
+        write_buf.extend_from_slice(&err.bytes);
+        self.debug("Sending 'server has gone away' to client".to_string());
+        if let Err(e) = self.drain(write_buf).await {
+            self.debug(format!("Unable to send error to client: {}", e));
+        }
+        write_buf.clear();
+    }
+
+    fn backpressure_changed(&self, engaged: bool, pending: usize) {
+        let event = if engaged {
+            "Sink is behind, backpressure engaged"
+        } else {
+            "Sink caught up, backpressure released"
+        };
+        self.log(Level::Debug, event.to_string(), Some(pending));
+        if let Some(m) = &self.options.metrics {
+            m.backpressure_changed(&self.name, self.connection_id, self.direction, engaged);
+        }
+    }
+
+    fn record_write(&self, write_buf: &mut Vec<u8>, n: usize) {
+        // Dropping the Drain removes the bytes without allocating
+        write_buf.drain(0..n);
+        self.stats.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        self.log(Level::Trace, "Written to sink".to_string(), Some(n));
+        if let Some(m) = &self.options.metrics {
+            m.bytes_written(&self.name, self.direction, n);
+        }
+    }
+
+    async fn process_read_buf(
+        &self,
+        read_result: Result<usize>,
+        read_buf: &[u8],
+        packet_buf: &mut BytesMut,
+        write_buf: &mut Vec<u8>,
+        other_pipe_sender: &mut Sender<Packet>,
+    ) -> std::result::Result<(), CloseReason> {
+        if let Ok(n) = read_result {
+            self.stats.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+            if let Some(m) = &self.options.metrics {
+                m.bytes_read(&self.name, self.direction, n);
+            }
+            if let Some(tap) = &self.options.raw_tap {
+                tap.tap(self.direction, &read_buf[0..n]);
+            }
+            packet_buf.extend_from_slice(&read_buf[0..n]);
+            self.log(
+                Level::Trace,
+                format!("Read from source, {} bytes in packet_buf", packet_buf.len()),
+                Some(n),
+            );
+
+            // Once SSL is established end-to-end, the stream is opaque to us
+            if self.ssl_state.is_established() {
+                write_buf.extend_from_slice(packet_buf);
+                packet_buf.clear();
+                return Ok(());
+            }
+
+            // The database answers an SSLRequest with a single unframed byte
+            // https://www.postgresql.org/docs/12/protocol-flow.html#id-1.10.5.7.11
+            if self.direction == Direction::Backward && self.ssl_state.get() == SSL_REQUESTED {
+                let answer = packet_buf.split_to(1)[0];
+                write_buf.push(answer);
+                if answer == b'S' {
+                    self.debug("Database accepted SSLRequest, passing through".to_string());
+                    self.ssl_state.set(SSL_ESTABLISHED);
+                    write_buf.extend_from_slice(packet_buf);
+                    packet_buf.clear();
+                    return Ok(());
+                }
+                self.debug("Database refused SSLRequest".to_string());
+                self.ssl_state.set(SSL_NONE);
+            }
+
+            // Process all packets in packet_buf, put into write_buf
+            loop {
+                let packet = match get_packet(
+                    self.db_type,
+                    packet_buf,
+                    self.options.max_packet_size,
+                    self.options.reassemble_packets,
+                ) {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => break,
+                    Err(e) => {
+                        let reason = CloseReason::from(e);
+                        // There is no telling where the next packet starts, so give up
+                        self.stats.malformed_packets.fetch_add(1, Ordering::Relaxed);
+                        self.warn(reason.to_string());
+                        return Err(reason);
+                    }
+                };
+                self.stats.packets_processed.fetch_add(1, Ordering::Relaxed);
+                self.log(
+                    Level::Trace,
+                    "Processing packet".to_string(),
+                    Some(packet.get_size()),
+                );
+                if self.init_commands.is_running() {
+                    self.consume_init_response(&packet, write_buf);
+                    continue;
+                }
+                self.emit_query_event(&packet);
+                self.time_query(&packet);
+                let mut packet = packet;
+                if self.is_mariadb_handshake(&packet) {
+                    // The packets of the compressed protocol can't be followed, so never offer it
+                    let mut hidden = CLIENT_COMPRESS;
+                    if self.ssl_decision().await != SslDecision::AllowPassthrough {
+                        // Tell the client not to ask for SSL, MariaDB has no way to refuse it later
+                        hidden |= CLIENT_SSL;
+                    }
+                    // A handshake too short to edit is passed on as-is
+                    let _ = packet.clear_handshake_capabilities(hidden);
+                }
+                if self.is_compressed_handshake_response(&packet) {
+                    let reason = CloseReason::UnsupportedProtocol(
+                        "Client asked for the compressed protocol (CLIENT_COMPRESS), \
+                         which the proxy doesn't support"
+                            .to_string(),
+                    );
+                    self.warn(reason.to_string());
+                    let mut err = Packet::error_packet_mariadb(
+                        1158, // ER_NET_READ_ERROR
+                        *b"08S01",
+                        reason.to_string(),
+                    );
+                    let _ = err.set_sequence_id(packet.get_sequence_id()?.wrapping_add(1));
+                    // Best-effort, the connection is closing
+                    let _ = other_pipe_sender.send(err).await;
+                    return Err(reason);
+                }
+                let packet_type = packet.get_packet_type().ok();
+                // Whatever is written for the end of authentication is held back, see InitCommands
+                let authenticated = self.session.authenticated();
+                let written = write_buf.len();
+                if self.is_mariadb_ssl_request(&packet)
+                    && self.ssl_decision().await == SslDecision::AllowPassthrough
+                {
+                    // The TLS handshake follows right away, possibly in this same read
+                    self.debug("Got SSLRequest, passing through".to_string());
+                    self.ssl_state.set(SSL_ESTABLISHED);
+                    self.write_packet(write_buf, &packet);
+                    write_buf.extend_from_slice(packet_buf);
+                    packet_buf.clear();
+                    break;
+                } else if packet_type == Some(PacketType::SSLRequest)
+                    && self.ssl_decision().await == SslDecision::AllowPassthrough
+                {
+                    self.debug("Got SSLRequest, forwarding to database".to_string());
+                    self.ssl_state.set(SSL_REQUESTED);
+                    self.write_packet(write_buf, &packet);
+                } else if packet_type == Some(PacketType::SSLRequest) {
+                    // Respond that we don't support SSL
+                    self.debug("Got SSLRequest, responding no thanks".to_string());
+                    if let Err(_e) = other_pipe_sender
+                        .send(Packet::new(self.db_type, String::from("N").into_bytes()))
+                        .await
+                    {
+                        return Err(CloseReason::Io(
+                            self.create_error("Error sending SSL response of no".to_string()),
+                        ));
+                    }
+                } else if packet_type == Some(PacketType::CancelRequest) {
+                    // The database needs the process id and secret key as-is to find the query
+                    self.debug("Got CancelRequest, forwarding to database".to_string());
+                    self.write_packet(write_buf, &packet);
+                } else {
+                    let action = self.call_handler(&packet).await?;
+                    match action {
+                        HandlerAction::Forward => {
+                            if self.is_mariadb_quit(&packet) {
+                                // The database closes the connection once it gets this
+                                self.debug("Got COM_QUIT, forwarding to database".to_string());
+                                self.session.set_client_quit();
+                            }
+                            self.write_packet(write_buf, &packet);
+                        }
+                        HandlerAction::Replace(packets) => {
+                            for p in packets.iter() {
+                                self.write_packet(write_buf, p);
+                            }
+                        }
+                        HandlerAction::Drop => self.trace("Dropping packet".to_string()),
+                        HandlerAction::Respond(p) => {
+                            self.trace("Short-circuiting handler response".to_string());
+                            if let Err(_e) = other_pipe_sender.send(p).await {
+                                return Err(CloseReason::Io(self.create_error(
+                                    "Error sending short circuit response".to_string(),
+                                )));
+                            }
+                        }
+                    }
+                }
+                if !authenticated && self.session.authenticated() && self.init_commands.is_pending()
+                {
+                    let held = write_buf.split_off(written);
+                    for command in self.init_commands.start(self.db_type, held) {
+                        self.debug("Running init command".to_string());
+                        if let Err(_e) = other_pipe_sender.send(command).await {
+                            return Err(CloseReason::Io(
+                                self.create_error("Error sending init command".to_string()),
+                            ));
+                        }
+                    }
+                }
+            } // end loop
+            Ok(())
+        } else if let Err(e) = read_result {
+            self.warn("Error reading from source".to_string());
+            Err(CloseReason::SourceReadError {
+                name: self.name.clone(),
+                direction: self.direction,
+                error: e,
+            })
+        } else {
+            Err(CloseReason::Io(Error::other("This should never happen")))
+        }
+    }
+
+    /// Passes on what was held back once the last init command is done
+    fn consume_init_response(&self, packet: &Packet, write_buf: &mut Vec<u8>) {
+        let error = match self.db_type {
+            DatabaseType::MariaDB => match packet.get_response_type() {
+                Ok(PacketType::ComErr) => packet.get_mariadb_error().ok().map(|(_code, msg)| msg),
+                _ => None,
+            },
+            DatabaseType::PostgresSQL => packet.get_postgres_error().map(|fields| {
+                fields
+                    .into_iter()
+                    .find(|(field, _value)| *field == 'M')
+                    .map_or_else(String::new, |(_field, message)| message)
+            }),
+        };
+        if let Some(message) = error {
+            self.warn(format!("Init command failed: {}", message));
+        }
+        if let Some(held) = self.init_commands.consume(packet) {
+            self.debug("Init commands done".to_string());
+            write_buf.extend_from_slice(&held);
+        }
+    }
+
+    /// `PipeOptions::allow_ssl_passthrough` overrides the handler
+    async fn ssl_decision(&self) -> SslDecision {
+        if self.options.allow_ssl_passthrough {
+            return SslDecision::AllowPassthrough;
+        }
+        match self
+            .packet_handler
+            .lock()
+            .await
+            .on_ssl_request(self.db_type)
+        {
+            SslDecision::TerminateTls => {
+                self.warn(
+                    "Handler asked to terminate TLS, which needs Server::with_tls, denying"
+                        .to_string(),
+                );
+                SslDecision::Deny
+            }
+            decision => decision,
+        }
+    }
+
+    /// The initial handshake is the first packet from a MariaDB database, protocol version 10
+    fn is_mariadb_handshake(&self, packet: &Packet) -> bool {
+        self.direction == Direction::Backward
+            && matches!(
+                packet.get_response_type_with_handshake(self.session.handshake_seen()),
+                Ok(PacketType::Handshake)
+            )
+    }
+
+    /// Compression starts after authentication, so a handshake response asking for it is the
+    /// last chance to notice, before the packets stop making sense. Clients only ask if the
+    /// handshake offered it, which the pipe prevents, unless it didn't see the handshake
+    fn is_compressed_handshake_response(&self, packet: &Packet) -> bool {
+        self.db_type == DatabaseType::MariaDB
+            && self.direction == Direction::Forward
+            && self.stats.packets_processed() == 1
+            && packet.bytes.len() >= 8
+            && LittleEndian::read_u32(&packet.bytes[4..8]) & CLIENT_COMPRESS != 0
+    }
+
+    /// A COM_QUIT from the client, after which the database closing the connection is expected
+    fn is_mariadb_quit(&self, packet: &Packet) -> bool {
+        self.db_type == DatabaseType::MariaDB
+            && self.direction == Direction::Forward
+            && packet.bytes.len() == 5
+            && packet.get_packet_type().ok() == Some(PacketType::ComQuit)
+    }
+
+    /// A MariaDB client asking for SSL does so in its first packet, instead of the handshake response
+    fn is_mariadb_ssl_request(&self, packet: &Packet) -> bool {
+        self.db_type == DatabaseType::MariaDB
+            && self.direction == Direction::Forward
+            && self.stats.packets_processed() == 1
+            && tls::is_mariadb_ssl_request(packet)
+    }
+
+    fn emit_query_event(&self, packet: &Packet) {
+        let events = match &self.query_events {
+            Some(events) => events,
+            None => return,
+        };
+        // MariaDB responses reuse command bytes, e.g. the column count of a 3-column result set
+        if self.direction != Direction::Forward {
+            return;
+        }
+        if let Ok(query) = packet.get_query() {
+            let event = QueryEvent {
+                timestamp: SystemTime::now(),
+                direction: self.direction,
+                connection_id: self.connection_id,
+                query,
+            };
+            let mut events = events.lock().unwrap();
+            if let Err(e) = events.try_send(event) {
+                if e.is_full() {
+                    self.trace("Query event channel full, dropping event".to_string());
+                }
+            }
+        }
+    }
+
+    fn time_query(&self, packet: &Packet) {
+        let threshold = match self.options.slow_query_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        if let Some((query, elapsed)) = self.query_timer.observe(packet, self.direction) {
+            if elapsed > threshold {
+                self.warn(format!("Slow query took {:?}: {}", elapsed, query));
+            }
+        }
+    }
+
+    /// A panicking handler is an error, which closes the connection.
+    /// The handler's lock is released as the panic unwinds, and it keeps serving
+    /// other connections, so handlers should not rely on state a panic could leave half-updated
+    async fn call_handler(
+        &self,
+        packet: &Packet,
+    ) -> std::result::Result<HandlerAction, CloseReason> {
+        let ctx = PacketContext {
+            // Read before observing the packet, which may be the handshake
+            handshake_seen: self.session.handshake_seen(),
+            handshake_response: self.db_type == DatabaseType::MariaDB
+                && self.direction == Direction::Forward
+                && !self.session.seen_request(),
+            // A request only changes the session once it is on its way to the database, below
+            session: match self.direction {
+                Direction::Forward => self.session.preview(packet, self.direction),
+                Direction::Backward => self.session.observe(packet, self.direction),
+            },
+            backpressure: self.backpressure.is_engaged(),
+            write_buf_len: self.stats.write_buf_len(),
+            ..self.context.clone()
+        };
+        let handle = AssertUnwindSafe(async {
+            // Scope for self.packet_handler Mutex
+            let mut h = self.packet_handler.lock().await;
+            match self.direction {
+                Direction::Forward => {
+                    let action = h.handle_request(packet, &ctx).await;
+                    // Only requests that reach the database get a response from it
+                    if let HandlerAction::Forward | HandlerAction::Replace(_) = action {
+                        self.requests.start(packet);
+                        self.requests.set_state(h.correlate(packet, &ctx).await);
+                    }
+                    action
+                }
+                Direction::Backward => match self.requests.current() {
+                    Some(request) => h.handle_response_for(packet, &ctx, &request).await,
+                    None => h.handle_response(packet, &ctx).await,
+                },
+            }
+        })
+        .catch_unwind();
+        let result = match self.options.handler_timeout {
+            Some(d) => match timeout(d, handle).await {
+                Ok(result) => result,
+                Err(_) => {
+                    self.warn(format!(
+                        "Handler timed out after {:?}, forwarding packet unchanged",
+                        d
+                    ));
+                    Ok(HandlerAction::Forward)
+                }
+            },
+            None => handle.await,
+        };
+        let action = result.map_err(|_panic| {
+            self.log(
+                Level::Error,
+                "Handler panicked, closing connection".to_string(),
+                None,
+            );
+            CloseReason::HandlerPanicked
+        })?;
+        if self.direction == Direction::Forward {
+            // What the database sees, e.g. the `USE` a handler rewrote a query to
+            match &action {
+                HandlerAction::Forward => {
+                    self.session.observe(packet, self.direction);
+                }
+                HandlerAction::Replace(packets) => {
+                    for p in packets {
+                        self.session.observe(p, self.direction);
+                    }
+                }
+                HandlerAction::Drop | HandlerAction::Respond(_) => {}
+            }
+        }
+        Ok(action)
+    }
+
+    /// Appends the packet to write_buf, splitting logical packets if reassembly is enabled
+    fn write_packet(&self, write_buf: &mut Vec<u8>, packet: &Packet) {
+        if self.options.reassemble_packets && self.db_type == DatabaseType::MariaDB {
+            split_mariadb_packet(write_buf, &packet.bytes);
+        } else {
+            write_buf.extend_from_slice(&packet.bytes);
+        }
+    }
+
+    fn process_short_circuit(&self, p: Packet, write_buf: &mut Vec<u8>) {
+        self.log(
+            Level::Trace,
+            "Got short circuit packet".to_string(),
+            Some(p.get_size()),
+        );
+        self.write_packet(write_buf, &p);
+    }
+
+    /// Sends a record to `PipeOptions::log_sink`, or the `log` macros if there is none.
+    /// Detailed records are raised to `Info` while the connection is verbose
+    fn log(&self, level: Level, event: String, bytes: Option<usize>) {
+        let level = if level > Level::Info && self.verbose.is_set() {
+            Level::Info
+        } else {
+            level
+        };
+        if let Some(sink) = &self.options.log_sink {
+            sink.log(LogEvent {
+                level,
+                pipe_name: self.name.clone(),
+                connection_id: self.connection_id,
+                direction: self.direction,
+                event,
+                bytes,
+            });
+            return;
+        }
+        match bytes {
+            Some(n) => log!(
+                level,
+                "[{}#{}:{:?}]: {} ({} bytes)",
+                self.name,
+                self.connection_id,
+                self.direction,
+                event,
+                n
+            ),
+            None => log!(
+                level,
+                "[{}#{}:{:?}]: {}",
+                self.name,
+                self.connection_id,
+                self.direction,
+                event
+            ),
+        }
+    }
+
+    fn warn(&self, string: String) {
+        self.log(Level::Warn, string, None);
+    }
+
+    fn debug(&self, string: String) {
+        self.log(Level::Debug, string, None);
+    }
+
+    fn trace(&self, string: String) {
+        self.log(Level::Trace, string, None);
+    }
+
+    fn sink_write_error(&self, error: Error) -> CloseReason {
+        CloseReason::SinkWriteError {
+            name: self.name.clone(),
+            direction: self.direction,
+            error,
+        }
+    }
+
+    fn create_error(&self, string: String) -> Error {
+        Error::other(format!(
+            "[{}#{}:{:?}]: {}",
+            self.name, self.connection_id, self.direction, string
+        ))
+    }
+} // end impl
+
+/// Resolves at `deadline`, or never if there is none
+async fn timer_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(t) => delay_until(t).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Writes a (possibly logical) MariaDB packet to write_buf,
+/// splitting payloads of 0xFFFFFF bytes or more into consecutive packets
+fn split_mariadb_packet(write_buf: &mut Vec<u8>, bytes: &[u8]) {
+    if bytes.len() < 4 || bytes.len() - 4 < MARIADB_MAX_PAYLOAD {
+        write_buf.extend_from_slice(bytes);
+        return;
+    }
+    let mut sequence_id = bytes[3];
+    let mut payload = &bytes[4..];
+    loop {
+        let l = std::cmp::min(payload.len(), MARIADB_MAX_PAYLOAD);
+        write_buf.extend_from_slice(&[l as u8, (l >> 8) as u8, (l >> 16) as u8, sequence_id]);
+        write_buf.extend_from_slice(&payload[..l]);
+        payload = &payload[l..];
+        sequence_id = sequence_id.wrapping_add(1);
+        // A payload that is an exact multiple of 0xFFFFFF ends with an empty packet
+        if l < MARIADB_MAX_PAYLOAD {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet_handler::{CorrelationState, PassthroughHandler, RequestContext},
+        testing::RecordingHandler,
+    };
+    use tokio::time::delay_for;
+
+    type PipeResult = std::result::Result<CloseReason, CloseReason>;
+    use futures::channel::mpsc;
+
+    /// Prepends a ping to every request
+    struct PrependHandler {}
+
+    #[async_trait::async_trait]
+    impl PacketHandler for PrependHandler {
+        async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            let ping = Packet::new(DatabaseType::MariaDB, vec![1, 0, 0, 0, 0x0e]);
+            HandlerAction::Replace(vec![ping, p.clone()])
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+    }
+
+    /// Maps the user of a PostgresSQL StartupMessage to a real role
+    struct RewriteUserHandler {}
+
+    #[async_trait::async_trait]
+    impl PacketHandler for RewriteUserHandler {
+        async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            let mut p = p.clone();
+            match p.set_postgres_startup_param("user", "tenant_role") {
+                Ok(()) => HandlerAction::Replace(vec![p]),
+                Err(_e) => HandlerAction::Forward,
+            }
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+    }
+
+    /// Drops every COM_QUERY
+    struct DropQueryHandler {}
+
+    #[async_trait::async_trait]
+    impl PacketHandler for DropQueryHandler {
+        async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            match p.get_packet_type() {
+                Ok(PacketType::ComQuery) => HandlerAction::Drop,
+                _ => HandlerAction::Forward,
+            }
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+    }
+
+    /// Answers COM_PING locally
+    struct PingHandler {}
+
+    #[async_trait::async_trait]
+    impl PacketHandler for PingHandler {
+        async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            match p.get_packet_type() {
+                Ok(PacketType::ComPing) => {
+                    let mut ok = Packet::new(DatabaseType::MariaDB, vec![1, 0, 0, 0, 0x00]);
+                    ok.set_sequence_id(p.get_sequence_id().unwrap() + 1)
+                        .unwrap();
+                    HandlerAction::Respond(ok)
+                }
+                _ => HandlerAction::Forward,
+            }
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingMetrics {
+        read: std::sync::atomic::AtomicUsize,
+        written: std::sync::atomic::AtomicUsize,
+        backpressure: std::sync::Mutex<Vec<(u64, bool)>>,
+    }
+
+    impl PipeMetrics for CountingMetrics {
+        fn bytes_read(&self, _name: &str, _direction: Direction, n: usize) {
+            self.read.fetch_add(n, Ordering::SeqCst);
+        }
+
+        fn bytes_written(&self, _name: &str, _direction: Direction, n: usize) {
+            self.written.fetch_add(n, Ordering::SeqCst);
+        }
+
+        fn backpressure_changed(
+            &self,
+            _name: &str,
+            connection_id: u64,
+            _direction: Direction,
+            engaged: bool,
+        ) {
+            self.backpressure
+                .lock()
+                .unwrap()
+                .push((connection_id, engaged));
+        }
+    }
+
+    /// A sink that never accepts any bytes
+    struct StalledSink {}
+
+    impl tokio::io::AsyncWrite for StalledSink {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &[u8],
+        ) -> std::task::Poll<Result<usize>> {
+            std::task::Poll::Pending
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn pipe_writes_proxy_header_first() {
+        let query = Packet::mariadb(0, b"\x03SELECT 1");
+        let input: &[u8] = &query.bytes;
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            input,
+            &mut sink,
+        )
+        .with_proxy_header(b"PROXY UNKNOWN\r\n".to_vec());
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        assert!(pipe.run(tx, rx, kill_rx).await.is_ok());
+        drop(pipe);
+        let mut expected = b"PROXY UNKNOWN\r\n".to_vec();
+        expected.extend_from_slice(&query.bytes);
+        assert_eq!(sink, expected);
+    }
+
+    #[tokio::test]
+    async fn backward_pipe_reports_database_gone() {
+        let input: &[u8] = &[];
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Backward,
+            input,
+            &mut sink,
+        );
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        assert!(pipe.run(tx, rx, kill_rx).await.is_err());
+        drop(pipe);
+        let err = Packet::new(DatabaseType::MariaDB, sink);
+        assert_eq!(err.get_mariadb_error().unwrap().0, 2006);
+    }
+
+    #[tokio::test]
+    async fn backward_pipe_reports_database_gone_only_when_it_is() {
+        // A malformed packet is the pipe giving up, not the database going away
+        let input: &[u8] = &[0xff, 0xff, 0xff, 0, 0x03];
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Backward,
+            input,
+            &mut sink,
+        )
+        .with_max_packet_size(16)
+        .build();
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        assert!(pipe.run(tx, rx, kill_rx).await.is_err());
+        drop(pipe);
+        assert!(sink.is_empty());
+
+        // Plaintext would corrupt the client's SSL session
+        let ssl_state = Arc::new(SslState::new());
+        ssl_state.set(SSL_ESTABLISHED);
+        let input: &[u8] = &[];
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Backward,
+            input,
+            &mut sink,
+        )
+        .with_ssl_state(ssl_state)
+        .build();
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        assert!(matches!(
+            pipe.run(tx, rx, kill_rx).await,
+            Err(CloseReason::DatabaseClosed)
+        ));
+        drop(pipe);
+        assert!(sink.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pipe_runs_init_commands_after_authentication() {
+        let mut input = Packet::postgres(b'R', &[0, 0, 0, 0]).bytes.to_vec();
+        input.extend_from_slice(b"Z\0\0\0\x05I");
+        // The responses to the init commands
+        input.extend_from_slice(&Packet::postgres(b'C', b"SET\0").bytes);
+        input.extend_from_slice(b"Z\0\0\0\x05I");
+        input
+            .extend_from_slice(&Packet::postgres(b'E', b"SERROR\0C42601\0Msyntax error\0\0").bytes);
+        input.extend_from_slice(b"Z\0\0\0\x05I");
+        // The response to the client's first query
+        input.extend_from_slice(&Packet::postgres(b'C', b"SELECT 1\0").bytes);
+
+        let recorder = Arc::new(Mutex::new(RecordingHandler::new()));
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::PostgresSQL,
+            recorder.clone(),
+            Direction::Backward,
+            &input[..],
+            &mut sink,
+        )
+        .with_init_commands(vec!["SET TIME ZONE 'UTC'".to_string(), "BOGUS".to_string()])
+        .build();
+        let (tx, mut other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        // The database closing first is an error
+        assert!(pipe.run(tx, rx, kill_rx).await.is_err());
+        drop(pipe);
+
+        let commands = [other_rx.try_recv().unwrap(), other_rx.try_recv().unwrap()];
+        assert_eq!(commands[0].get_query().unwrap(), "SET TIME ZONE 'UTC'");
+        assert_eq!(commands[1].get_query().unwrap(), "BOGUS");
+        let mut expected = Packet::postgres(b'R', &[0, 0, 0, 0]).bytes.to_vec();
+        expected.extend_from_slice(b"Z\0\0\0\x05I");
+        expected.extend_from_slice(&Packet::postgres(b'C', b"SELECT 1\0").bytes);
+        assert_eq!(sink, expected);
+        // The handler never sees the responses to init commands
+        assert_eq!(recorder.lock().await.responses().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn pipe_holds_mariadb_ok_until_init_commands_are_done() {
+        let session = Arc::new(SessionTracker::new());
+        let mut response = vec![0_u8; 32];
+        response.extend_from_slice(b"root\0\0");
+        session.observe(&Packet::mariadb(1, &response), Direction::Forward);
+        let auth_ok = Packet::mariadb(2, &[0, 0, 0, 2, 0, 0, 0]);
+        let mut input = auth_ok.bytes.to_vec();
+        input.extend_from_slice(&Packet::mariadb(1, &[0, 0, 0, 2, 0, 0, 0]).bytes);
+
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Backward,
+            &input[..],
+            &mut sink,
+        )
+        .with_session(session.clone())
+        .with_init_commands(vec!["SET NAMES utf8mb4".to_string()])
+        .build();
+        let (tx, mut other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let _ = pipe.run(tx, rx, kill_rx).await;
+        drop(pipe);
+
+        let command = other_rx.try_recv().unwrap();
+        assert_eq!(command.get_query().unwrap(), "SET NAMES utf8mb4");
+        assert!(session.authenticated());
+        // Followed by the "server has gone away" error, as the database closed
+        assert_eq!(sink[..auth_ok.bytes.len()], auth_ok.bytes[..]);
+        assert_eq!(sink[auth_ok.bytes.len() + 4], 0xff);
+    }
+
+    /// Points `USE a` at database b instead, and drops COM_CHANGE_USER
+    struct SwitchingHandler {}
+
+    #[async_trait::async_trait]
+    impl PacketHandler for SwitchingHandler {
+        async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            match p.get_packet_type() {
+                Ok(PacketType::ComChangeUser) => HandlerAction::Drop,
+                Ok(PacketType::ComQuery) if p.get_query().unwrap() == "USE a" => {
+                    HandlerAction::Replace(vec![Packet::mariadb(0, b"\x03USE b")])
+                }
+                _ => HandlerAction::Forward,
+            }
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+    }
+
+    #[tokio::test]
+    async fn session_follows_what_the_database_sees() {
+        let session = Arc::new(SessionTracker::new());
+        session.skip_handshake();
+        let mut input = Packet::mariadb(0, b"\x03USE a").bytes.to_vec();
+        input.extend_from_slice(&Packet::mariadb(0, b"\x11bob\0").bytes);
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(SwitchingHandler {})),
+            Direction::Forward,
+            &input[..],
+            &mut sink,
+        )
+        .with_session(session.clone())
+        .build();
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let _ = pipe.run(tx, rx, kill_rx).await;
+        drop(pipe);
+        assert_eq!(sink, Packet::mariadb(0, b"\x03USE b").bytes.to_vec());
+
+        // The dropped COM_CHANGE_USER neither reset the session nor undid the USE
+        assert!(session.authenticated());
+        let ok = Packet::mariadb(1, &[0, 0, 0, 2, 0, 0, 0]);
+        let state = session.observe(&ok, Direction::Backward);
+        assert_eq!(state.database, Some("b".to_string()));
+    }
+
+    #[tokio::test]
+    async fn pipes_close_cleanly_after_quit() {
+        let session = Arc::new(SessionTracker::new());
+        session.skip_handshake();
+        let quit = [1, 0, 0, 0, 0x01];
+        let mut forward_sink: Vec<u8> = Vec::new();
+        let mut forward = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            &quit[..],
+            &mut forward_sink,
+        )
+        .with_session(session.clone());
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let result = forward.run(tx, rx, kill_rx).await;
+        assert!(matches!(result, Ok(CloseReason::ClientQuit)));
+        drop(forward);
+        assert_eq!(forward_sink, quit);
+        assert!(session.client_quit());
+
+        // The database then closes, which is not reported to the client
+        let input: &[u8] = &[];
+        let mut backward_sink: Vec<u8> = Vec::new();
+        let mut backward = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Backward,
+            input,
+            &mut backward_sink,
+        )
+        .with_session(session);
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let result = backward.run(tx, rx, kill_rx).await;
+        assert!(matches!(result, Ok(CloseReason::ClientQuit)));
+        drop(backward);
+        assert!(backward_sink.is_empty());
+    }
+
+    /// Drops every packet
+    struct DropAllHandler {}
+
+    #[async_trait::async_trait]
+    impl PacketHandler for DropAllHandler {
+        async fn handle_request(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Drop
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Drop
+        }
+    }
+
+    /// Lets clients negotiate SSL with the database
+    struct AllowSslHandler {}
+
+    #[async_trait::async_trait]
+    impl PacketHandler for AllowSslHandler {
+        async fn handle_request(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+
+        fn on_ssl_request(&self, _db_type: DatabaseType) -> SslDecision {
+            SslDecision::AllowPassthrough
+        }
+    }
+
+    /// Takes too long to drop anything
+    struct SlowDropHandler {}
+
+    #[async_trait::async_trait]
+    impl PacketHandler for SlowDropHandler {
+        async fn handle_request(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            delay_for(Duration::from_secs(10)).await;
+            HandlerAction::Drop
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+    }
+
+    /// Panics on every COM_QUERY
+    struct PanicHandler {}
+
+    #[async_trait::async_trait]
+    impl PacketHandler for PanicHandler {
+        async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            if p.get_packet_type().ok() == Some(PacketType::ComQuery) {
+                panic!("PanicHandler got a query");
+            }
+            HandlerAction::Forward
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+    }
+
+    /// Runs a forward pipe over `input` until it closes, returning the error, the sink contents,
+    /// and any packets short-circuited back to the source
+    async fn run_pipe<H: PacketHandler + Send + 'static>(
+        handler: H,
+        options: PipeOptions,
+        input: &[u8],
+    ) -> (PipeResult, Vec<u8>, Vec<Packet>) {
+        run_db_pipe(DatabaseType::MariaDB, handler, options, input).await
+    }
+
+    /// Same as `run_pipe`, for any database type
+    async fn run_db_pipe<H: PacketHandler + Send + 'static>(
+        db_type: DatabaseType,
+        handler: H,
+        options: PipeOptions,
+        input: &[u8],
+    ) -> (PipeResult, Vec<u8>, Vec<Packet>) {
+        run_directed_pipe(db_type, Direction::Forward, handler, options, input).await
+    }
+
+    /// Same as `run_db_pipe`, in either direction
+    async fn run_directed_pipe<H: PacketHandler + Send + 'static>(
+        db_type: DatabaseType,
+        direction: Direction,
+        handler: H,
+        options: PipeOptions,
+        input: &[u8],
+    ) -> (PipeResult, Vec<u8>, Vec<Packet>) {
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = Pipe::with_options(
+            "test".to_string(),
+            db_type,
+            Arc::new(Mutex::new(handler)),
+            direction,
+            input,
+            &mut sink,
+            options,
+            Arc::new(SslState::new()),
+        );
+        let (tx, mut other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let result = pipe.run(tx, rx, kill_rx).await;
+        drop(pipe);
+        let mut responses = Vec::new();
+        while let Ok(p) = other_rx.try_recv() {
+            responses.push(p);
+        }
+        (result, sink, responses)
+    }
+
+    #[test]
+    fn get_packet_reassembles_split_mariadb_packets() {
+        // 0xFFFFFF + 2 byte payload, followed by an unrelated ping
+        let mut split = vec![0xff, 0xff, 0xff, 0x00];
+        split.extend(vec![b'a'; MARIADB_MAX_PAYLOAD]);
+        split.extend_from_slice(&[2, 0, 0, 1, b'b', b'c']);
+        let mut packet_buf = BytesMut::from(&split[..]);
+        packet_buf.extend_from_slice(&[1, 0, 0, 0, 0x0e]);
+
+        let packet = get_packet(
+            DatabaseType::MariaDB,
+            &mut packet_buf,
+            64 * 1024 * 1024,
+            true,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(packet.get_size(), 4 + MARIADB_MAX_PAYLOAD + 2);
+        assert_eq!(&packet.bytes[packet.get_size() - 3..], b"abc");
+        assert_eq!(packet_buf, vec![1, 0, 0, 0, 0x0e]);
+
+        let mut write_buf = Vec::new();
+        split_mariadb_packet(&mut write_buf, &packet.bytes);
+        assert_eq!(write_buf, split);
+    }
+
+    #[tokio::test]
+    async fn pipe_closes_on_oversized_packet() {
+        let options = PipeOptions {
+            max_packet_size: 1024,
+            ..PipeOptions::default()
+        };
+        let input = [0xff, 0xff, 0xff, 0x00, 0x03, b'S', b'E', b'L'];
+        let (result, sink, _) = run_pipe(PassthroughHandler {}, options, &input).await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("exceeds max_packet_size"));
+        assert!(sink.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pipe_drains_write_buf_on_error() {
+        let options = PipeOptions {
+            max_packet_size: 1024,
+            ..PipeOptions::default()
+        };
+        // A ping, then an oversized packet in the same read
+        let input = [1, 0, 0, 0, 0x0e, 0xff, 0xff, 0xff, 0x00, 0x03];
+        let (result, sink, _) = run_pipe(PassthroughHandler {}, options, &input).await;
+        assert!(matches!(result, Err(CloseReason::PacketTooLarge { .. })));
+        assert_eq!(sink, vec![1, 0, 0, 0, 0x0e]);
+    }
+
+    #[tokio::test]
+    async fn builder_applies_options() {
+        let input = [0xff, 0xff, 0xff, 0x00, 0x03, b'S', b'E', b'L'];
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Backward,
+            &input[..],
+            &mut sink,
+        )
+        .with_read_buf_size(16)
+        .with_max_packet_size(1024)
+        .with_connection_id(7)
+        .build();
+        assert_eq!(pipe.options.backward_buf_size, 16);
+        assert_eq!(pipe.options.forward_buf_size, 4096);
+        assert_eq!(pipe.context.connection_id, 7);
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let result = pipe.run(tx, rx, kill_rx).await;
+        assert!(matches!(result, Err(CloseReason::PacketTooLarge { .. })));
+    }
+
+    #[tokio::test]
+    async fn pipe_writes_every_returned_packet() {
+        let input = [1, 0, 0, 0, 0x01];
+        let (_result, sink, _) = run_pipe(PrependHandler {}, PipeOptions::default(), &input).await;
+        assert_eq!(sink, vec![1, 0, 0, 0, 0x0e, 1, 0, 0, 0, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn pipe_never_writes_dropped_packets() {
+        let input = [2, 0, 0, 0, 0x03, b';', 1, 0, 0, 0, 0x0e];
+        let (_result, sink, _) =
+            run_pipe(DropQueryHandler {}, PipeOptions::default(), &input).await;
+        assert_eq!(sink, vec![1, 0, 0, 0, 0x0e]);
+    }
+
+    #[tokio::test]
+    async fn pipe_short_circuits_responses() {
+        let input = [1, 0, 0, 0, 0x0e];
+        let (_result, sink, responses) =
+            run_pipe(PingHandler {}, PipeOptions::default(), &input).await;
+        assert!(sink.is_empty());
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].bytes, vec![1, 0, 0, 1, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn pipe_closes_when_idle() {
+        // Holding the other end open means reads never complete
+        let (source, _client) = tokio::net::UnixStream::pair().unwrap();
+        let options = PipeOptions {
+            idle_timeout: Some(Duration::from_millis(20)),
+            ..PipeOptions::default()
+        };
+        let mut pipe = Pipe::with_options(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            source,
+            Vec::new(),
+            options,
+            Arc::new(SslState::new()),
+        );
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let e = pipe.run(tx, rx, kill_rx).await.unwrap_err();
+        assert!(e.to_string().contains("Idle"));
+    }
+
+    #[tokio::test]
+    async fn pipes_are_idle_together() {
+        let options = PipeOptions {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..PipeOptions::default()
+        };
+        let activity = Arc::new(Activity::new());
+        let pipe = |direction, source| {
+            PipeBuilder::new(
+                "test".to_string(),
+                DatabaseType::MariaDB,
+                Arc::new(Mutex::new(PassthroughHandler {})),
+                direction,
+                source,
+                Vec::new(),
+            )
+            .with_options(options.clone())
+            .with_activity(activity.clone())
+            .build()
+        };
+        // The client waits on its query, reads never complete
+        let (client_source, _client) = tokio::net::UnixStream::pair().unwrap();
+        let (db_source, mut db) = tokio::net::UnixStream::pair().unwrap();
+        let mut forward = pipe(Direction::Forward, client_source);
+        let mut backward = pipe(Direction::Backward, db_source);
+        let (fb_tx, fb_rx) = mpsc::channel::<Packet>(16);
+        let (bf_tx, bf_rx) = mpsc::channel::<Packet>(16);
+        let (_forward_kill, forward_kill_rx) = oneshot::channel();
+        let (_backward_kill, backward_kill_rx) = oneshot::channel();
+        let start = Instant::now();
+        // A response trickling in for four times the timeout
+        let database = async {
+            for _ in 0..8 {
+                delay_for(Duration::from_millis(25)).await;
+                db.write_all(&[1, 0, 0, 1, 0x00]).await.unwrap();
+            }
+            db
+        };
+        let forward = async {
+            let result = forward.run(fb_tx, bf_rx, forward_kill_rx).await;
+            (result, start.elapsed())
+        };
+        let ((forward_result, forward_elapsed), backward_result, _db) = futures::join!(
+            forward,
+            backward.run(bf_tx, fb_rx, backward_kill_rx),
+            database
+        );
+        // Not before the response has stopped, then both time out
+        assert!(forward_elapsed >= Duration::from_millis(200));
+        assert!(matches!(forward_result, Err(CloseReason::IdleTimeout(_))));
+        assert!(matches!(backward_result, Err(CloseReason::IdleTimeout(_))));
+    }
+
+    #[tokio::test]
+    async fn pipe_returns_ok_on_kill_switch() {
+        let (source, _client) = tokio::net::UnixStream::pair().unwrap();
+        let (sink, mut sink_peer) = tokio::net::UnixStream::pair().unwrap();
+        let mut pipe = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            source,
+            sink,
+        );
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (mut other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let handle = tokio::spawn(async move { pipe.run(tx, rx, kill_rx).await });
+
+        let ping = Packet::new(DatabaseType::MariaDB, vec![1, 0, 0, 0, 0x0e]);
+        other_tx.send(ping).await.unwrap();
+        let mut written = [0_u8; 5];
+        sink_peer.read_exact(&mut written).await.unwrap();
+        assert_eq!(written, [1, 0, 0, 0, 0x0e]);
+
+        kill_tx.send(()).unwrap();
+        assert!(handle.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn pipe_reports_byte_counts() {
+        let metrics = Arc::new(CountingMetrics::default());
+        let options = PipeOptions {
+            metrics: Some(metrics.clone()),
+            ..PipeOptions::default()
+        };
+        let input = [2, 0, 0, 0, 0x03, b';', 1, 0, 0, 0, 0x0e];
+        let (_result, _sink, _) = run_pipe(DropQueryHandler {}, options, &input).await;
+        assert_eq!(metrics.read.load(Ordering::SeqCst), 11);
+        assert_eq!(metrics.written.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn pipe_emits_query_events() {
+        let (events_tx, mut events_rx) = mpsc::channel::<QueryEvent>(16);
+        let options = PipeOptions {
+            query_events: Some(events_tx),
+            ..PipeOptions::default()
+        };
+        let input = b"\x09\x00\x00\x00\x03SELECT 1\x01\x00\x00\x00\x0e";
+        let _ = run_pipe(PassthroughHandler {}, options, &input[..]).await;
+        let event = events_rx.try_recv().unwrap();
+        assert_eq!(event.query, "SELECT 1");
+        assert_eq!(event.direction, Direction::Forward);
+        // The ping isn't a query
+        assert!(events_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn pipe_marks_mariadb_handshake_response() {
+        let flags = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = flags.clone();
+        let handler = RecordingHandler::with_action(move |_p, ctx| {
+            seen.lock().unwrap().push(ctx.handshake_response);
+            HandlerAction::Forward
+        });
+        let mut input = Packet::mariadb(1, &[0; 34]).bytes.to_vec();
+        input.extend_from_slice(&Packet::mariadb(0, b"\x03SELECT 1").bytes);
+        let _ = run_pipe(handler, PipeOptions::default(), &input).await;
+        assert_eq!(*flags.lock().unwrap(), vec![true, false]);
+    }
+
+    #[tokio::test]
+    async fn pipe_emits_no_query_events_for_responses() {
+        let (events_tx, mut events_rx) = mpsc::channel::<QueryEvent>(16);
+        let options = PipeOptions {
+            query_events: Some(events_tx),
+            ..PipeOptions::default()
+        };
+        // The column count of a 3-column result set, and a row whose first value is 3 bytes
+        let mut input = Packet::mariadb(1, &[0x03]).bytes.to_vec();
+        input.extend_from_slice(&Packet::mariadb(5, b"\x03abc").bytes);
+        let _ = run_directed_pipe(
+            DatabaseType::MariaDB,
+            Direction::Backward,
+            PassthroughHandler {},
+            options,
+            &input,
+        )
+        .await;
+        assert!(events_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn pipe_counts_packets_and_bytes() {
+        let input = [2, 0, 0, 0, 0x03, b';', 1, 0, 0, 0, 0x0e];
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(DropQueryHandler {})),
+            Direction::Forward,
+            &input[..],
+            &mut sink,
+        );
+        let stats = pipe.stats();
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let _ = pipe.run(tx, rx, kill_rx).await;
+        assert_eq!(pipe.packets_processed(), 2);
+        assert_eq!(pipe.bytes_in(), 11);
+        assert_eq!(pipe.bytes_out(), 5);
+        drop(pipe);
+        assert_eq!(stats.packets_processed(), 2);
+    }
+
+    #[tokio::test]
+    async fn pipe_counts_malformed_packets() {
+        let options = PipeOptions {
+            max_packet_size: 1024,
+            ..PipeOptions::default()
+        };
+        // A ping, then what a misaligned stream would look like: the middle of a query
+        let corrupt = [1, 0, 0, 0, 0x0e, b'E', b'C', b'T', b' ', b'1'];
+        // A query cut short
+        let truncated = [1, 0, 0, 0, 0x0e, 9, 0, 0, 0, 0x03, b'S'];
+        for (input, fails) in &[(&corrupt[..], true), (&truncated[..], false)] {
+            let mut sink: Vec<u8> = Vec::new();
+            let mut pipe = Pipe::with_options(
+                "test".to_string(),
+                DatabaseType::MariaDB,
+                Arc::new(Mutex::new(PassthroughHandler {})),
+                Direction::Forward,
+                *input,
+                &mut sink,
+                options.clone(),
+                Arc::new(SslState::new()),
+            );
+            let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+            let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+            let (_kill_tx, kill_rx) = oneshot::channel();
+            let result = pipe.run(tx, rx, kill_rx).await;
+            assert_eq!(result.is_err(), *fails);
+            assert_eq!(pipe.malformed_packets(), 1);
+            if !fails {
+                // The rest of the query never came
+                assert_eq!(pipe.packet_buf_len(), 6);
+            }
+            drop(pipe);
+            if !fails {
+                assert_eq!(sink, vec![1, 0, 0, 0, 0x0e]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pipe_stops_reading_when_sink_is_slow() {
+        let metrics = Arc::new(CountingMetrics::default());
+        let options = PipeOptions {
+            forward_buf_size: 16,
+            // Unused by this forward pipe, and too large for the read count below
+            backward_buf_size: 1024,
+            write_buf_high_water_mark: 64,
+            write_buf_low_water_mark: 16,
+            idle_timeout: Some(Duration::from_millis(20)),
+            drain_timeout: Duration::from_millis(20),
+            metrics: Some(metrics.clone()),
+            ..PipeOptions::default()
+        };
+        // An endless stream of empty MariaDB packets
+        let mut pipe = Pipe::with_options(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            tokio::io::repeat(0),
+            StalledSink {},
+            options,
+            Arc::new(SslState::new()),
+        );
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let e = pipe.run(tx, rx, kill_rx).await.unwrap_err();
+        assert!(e.to_string().contains("Idle"));
+        assert!(metrics.read.load(Ordering::SeqCst) <= 64 + 16);
+        assert!(pipe.write_buf_len() >= 64);
+    }
+
+    #[tokio::test]
+    async fn pipe_reports_backpressure_changes() {
+        let metrics = Arc::new(CountingMetrics::default());
+        let options = PipeOptions {
+            forward_buf_size: 16,
+            write_buf_high_water_mark: 64,
+            write_buf_low_water_mark: 16,
+            // Holds writes back long enough for reads to get ahead
+            delay: Some(DelayConfig {
+                base: Duration::from_millis(20),
+                ..DelayConfig::default()
+            }),
+            metrics: Some(metrics.clone()),
+            ..PipeOptions::default()
+        };
+        let input = [1, 0, 0, 0, 0x0e].repeat(20);
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            &input[..],
+            &mut sink,
+        )
+        .with_options(options)
+        .with_connection_id(7)
+        .build();
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        assert!(pipe.run(tx, rx, kill_rx).await.is_ok());
+        drop(pipe);
+        assert_eq!(sink, input);
+        let changes = metrics.backpressure.lock().unwrap();
+        assert!(changes.len() >= 2);
+        assert_eq!(changes[0], (7, true));
+        assert_eq!(changes[1], (7, false));
+    }
+
+    /// Keeps the query of each request, and records it with the responses.
+    /// Answers `SELECT 2` itself
+    #[derive(Default)]
+    struct CorrelatingHandler {
+        responses: Vec<(u64, Option<String>)>,
+    }
+
+    #[async_trait::async_trait]
+    impl PacketHandler for CorrelatingHandler {
+        async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            match p.get_query() {
+                Ok(query) if query == "SELECT 2" => {
+                    HandlerAction::Respond(Packet::mariadb(1, &[0x00, 0, 0, 2, 0]))
+                }
+                _ => HandlerAction::Forward,
+            }
+        }
+
+        async fn correlate(
+            &mut self,
+            request: &Packet,
+            _ctx: &PacketContext,
+        ) -> Option<CorrelationState> {
+            Some(Arc::new(request.get_query().ok()?))
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            panic!("Response without a request");
+        }
+
+        async fn handle_response_for(
+            &mut self,
+            _p: &Packet,
+            _ctx: &PacketContext,
+            request: &RequestContext,
+        ) -> HandlerAction {
+            let query = request
+                .state
+                .as_ref()
+                .and_then(|s| s.downcast_ref::<String>());
+            self.responses.push((request.id, query.cloned()));
+            HandlerAction::Forward
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_correlates_responses_with_requests() {
+        let handler = Arc::new(Mutex::new(CorrelatingHandler::default()));
+        let requests = Arc::new(RequestTracker::new());
+        let exchanges: [(&[u8], &[u8]); 2] = [
+            (b"\x03SELECT 1", &[0xfe, 0, 0, 2, 0]),
+            (&[0x0e], &[0x00, 0, 0, 2, 0]),
+        ];
+        for (request, response) in exchanges.iter() {
+            for (direction, packet) in [
+                (Direction::Forward, request),
+                (Direction::Backward, response),
+            ]
+            .iter()
+            {
+                let input = Packet::mariadb(0, packet).bytes;
+                let mut sink: Vec<u8> = Vec::new();
+                let mut pipe = PipeBuilder::new(
+                    "test".to_string(),
+                    DatabaseType::MariaDB,
+                    handler.clone(),
+                    *direction,
+                    &input[..],
+                    &mut sink,
+                )
+                .with_request_tracker(requests.clone())
+                .build();
+                let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+                let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+                let (_kill_tx, kill_rx) = oneshot::channel();
+                let _ = pipe.run(tx, rx, kill_rx).await;
+            }
+        }
+        assert_eq!(
+            handler.lock().await.responses,
+            vec![(0, Some("SELECT 1".to_string())), (1, None)]
+        );
+    }
+
+    #[tokio::test]
+    async fn answered_requests_are_not_correlated() {
+        let handler = Arc::new(Mutex::new(CorrelatingHandler::default()));
+        let requests = Arc::new(RequestTracker::new());
+        // The handler answers the second query while the first is still running
+        let mut queries = Packet::mariadb(0, b"\x03SELECT 1").bytes.to_vec();
+        queries.extend_from_slice(&Packet::mariadb(0, b"\x03SELECT 2").bytes);
+        let response = Packet::mariadb(1, &[0xfe, 0, 0, 2, 0]).bytes;
+        for (direction, input) in [
+            (Direction::Forward, &queries[..]),
+            (Direction::Backward, &response[..]),
+        ]
+        .iter()
+        {
+            let mut sink: Vec<u8> = Vec::new();
+            let mut pipe = PipeBuilder::new(
+                "test".to_string(),
+                DatabaseType::MariaDB,
+                handler.clone(),
+                *direction,
+                *input,
+                &mut sink,
+            )
+            .with_request_tracker(requests.clone())
+            .build();
+            let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+            let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+            let (_kill_tx, kill_rx) = oneshot::channel();
+            let _ = pipe.run(tx, rx, kill_rx).await;
+        }
+        assert_eq!(
+            handler.lock().await.responses,
+            vec![(0, Some("SELECT 1".to_string()))]
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_sees_connection_backpressure() {
+        let backpressure = Arc::new(Backpressure::new());
+        let shed_load = |_p: &Packet, ctx: &PacketContext| {
+            if ctx.backpressure {
+                HandlerAction::Drop
+            } else {
+                HandlerAction::Forward
+            }
+        };
+        let ping = [1, 0, 0, 0, 0x0e];
+        for engaged in [false, true].iter() {
+            // As if the backward pipe couldn't keep up with the client
+            backpressure.set(Direction::Backward, *engaged);
+            let mut sink: Vec<u8> = Vec::new();
+            let mut pipe = PipeBuilder::new(
+                "test".to_string(),
+                DatabaseType::MariaDB,
+                Arc::new(Mutex::new(RecordingHandler::with_action(shed_load))),
+                Direction::Forward,
+                &ping[..],
+                &mut sink,
+            )
+            .with_backpressure(backpressure.clone())
+            .build();
+            let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+            let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+            let (_kill_tx, kill_rx) = oneshot::channel();
+            assert!(pipe.run(tx, rx, kill_rx).await.is_ok());
+            drop(pipe);
+            assert_eq!(sink.is_empty(), *engaged);
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_tap_sees_reads_before_framing() {
+        let tapped = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let tap = tapped.clone();
+        let options = PipeOptions {
+            raw_tap: Some(Arc::new(move |direction: Direction, bytes: &[u8]| {
+                assert_eq!(direction, Direction::Forward);
+                tap.lock().unwrap().extend_from_slice(bytes);
+            })),
+            ..PipeOptions::default()
+        };
+        // A complete packet followed by half of one
+        let input = [1, 0, 0, 0, 0x0e, 5, 0, 0];
+        let (_result, sink, _) = run_pipe(PassthroughHandler {}, options, &input).await;
+        assert_eq!(sink, input[..5].to_vec());
+        assert_eq!(*tapped.lock().unwrap(), input.to_vec());
+    }
+
+    #[tokio::test]
+    async fn log_sink_gets_structured_records() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = events.clone();
+        let options = PipeOptions {
+            log_sink: Some(Arc::new(move |event: LogEvent| {
+                log.lock().unwrap().push(event);
+            })),
+            ..PipeOptions::default()
+        };
+        let input = [1, 0, 0, 0, 0x0e, 5, 0, 0];
+        let _ = run_pipe(PassthroughHandler {}, options, &input).await;
+        let events = events.lock().unwrap();
+        assert!(events
+            .iter()
+            .all(|e| e.pipe_name == "test" && e.direction == Direction::Forward));
+        let read = events.iter().find(|e| e.event.starts_with("Read")).unwrap();
+        assert_eq!((read.level, read.bytes), (Level::Trace, Some(8)));
+        let dropped = events.iter().find(|e| e.level == Level::Warn).unwrap();
+        assert_eq!(dropped.bytes, Some(3));
+    }
+
+    #[tokio::test]
+    async fn verbose_pipe_raises_detailed_records() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = events.clone();
+        let options = PipeOptions {
+            log_sink: Some(Arc::new(move |event: LogEvent| {
+                log.lock().unwrap().push(event);
+            })),
+            ..PipeOptions::default()
+        };
+        let verbose = Arc::new(Verbose::new());
+        verbose.set(true);
+        let input = [1, 0, 0, 0, 0x0e];
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            &input[..],
+            &mut sink,
+        )
+        .with_options(options)
+        .with_verbose(verbose)
+        .build();
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        assert!(pipe.run(tx, rx, kill_rx).await.is_ok());
+        let events = events.lock().unwrap();
+        let processed = events
+            .iter()
+            .find(|e| e.event == "Processing packet")
+            .unwrap();
+        assert_eq!((processed.level, processed.bytes), (Level::Info, Some(5)));
+        assert!(events.iter().all(|e| e.level <= Level::Info));
+    }
+
+    #[tokio::test]
+    async fn passthrough_pipe_leaves_bytes_unchanged() {
+        let input = [2, 0, 0, 0, 0x03, b';', 1, 0, 0, 0, 0x0e];
+        let (_result, sink, _) =
+            run_pipe(PassthroughHandler {}, PipeOptions::default(), &input).await;
+        assert_eq!(sink, input.to_vec());
+    }
+
+    #[tokio::test]
+    async fn pipe_reports_close_reasons() {
+        let input = [1, 0, 0, 0, 0x0e];
+        let (result, _, _) = run_pipe(PassthroughHandler {}, PipeOptions::default(), &input).await;
+        assert!(matches!(result, Ok(CloseReason::SourceClosed)));
+
+        let options = PipeOptions {
+            max_packet_size: 8,
+            ..PipeOptions::default()
+        };
+        let input = [16, 0, 0, 0, 0x03];
+        let (result, _, _) = run_pipe(PassthroughHandler {}, options, &input).await;
+        let reason = result.unwrap_err();
+        assert!(matches!(
+            reason,
+            CloseReason::PacketTooLarge {
+                size: 20,
+                max_packet_size: 8
+            }
+        ));
+        let e: Error = reason.into();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    /// A source and sink that always fail
+    struct BrokenStream;
+
+    impl tokio::io::AsyncRead for BrokenStream {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut [u8],
+        ) -> std::task::Poll<Result<usize>> {
+            std::task::Poll::Ready(Err(ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    impl tokio::io::AsyncWrite for BrokenStream {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &[u8],
+        ) -> std::task::Poll<Result<usize>> {
+            std::task::Poll::Ready(Err(ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn pipe_tells_read_errors_from_write_errors() {
+        let input = [1, 0, 0, 0, 0x0e];
+        let mut pipe = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            &input[..],
+            BrokenStream,
+        );
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let reason = pipe.run(tx, rx, kill_rx).await.unwrap_err();
+        assert!(matches!(
+            reason,
+            CloseReason::SinkWriteError {
+                direction: Direction::Forward,
+                ..
+            }
+        ));
+        assert!(reason.to_string().starts_with("[test:Forward]: Writing"));
+        let e: Error = reason.into();
+        assert_eq!(e.kind(), ErrorKind::BrokenPipe);
+
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Backward,
+            BrokenStream,
+            &mut sink,
+        );
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let reason = pipe.run(tx, rx, kill_rx).await.unwrap_err();
+        match reason {
+            CloseReason::SourceReadError {
+                name,
+                direction,
+                error,
+            } => {
+                assert_eq!(name, "test");
+                assert_eq!(direction, Direction::Backward);
+                assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+            }
+            reason => panic!("Unexpected close reason {}", reason),
+        }
+    }
+
+    #[tokio::test]
+    async fn pipe_closes_after_max_connection_lifetime() {
+        let (source, mut client) = tokio::net::UnixStream::pair().unwrap();
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            source,
+            &mut sink,
+        )
+        .with_max_connection_lifetime(Duration::from_millis(50))
+        .build();
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let ping = [1, 0, 0, 0, 0x0e];
+        // Busy connections are closed too
+        let busy = async {
+            for _ in 0..20 {
+                if client.write_all(&ping).await.is_err() {
+                    break;
+                }
+                delay_for(Duration::from_millis(5)).await;
+            }
+        };
+        let started = std::time::Instant::now();
+        let (result, ()) = futures::join!(pipe.run(tx, rx, kill_rx), busy);
+        assert!(matches!(result, Ok(CloseReason::LifetimeExceeded)));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        drop(pipe);
+        assert!(!sink.is_empty());
+        assert_eq!(sink.len() % ping.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn pipe_closes_when_handler_panics() {
+        let handler = Arc::new(Mutex::new(PanicHandler {}));
+        let input = [2, 0, 0, 0, 0x03, b';'];
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            handler.clone(),
+            Direction::Forward,
+            &input[..],
+            &mut sink,
+        );
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let result = pipe.run(tx, rx, kill_rx).await;
+        assert!(result.unwrap_err().to_string().contains("Handler panicked"));
+        // Other connections can still use the handler
+        assert!(handler.try_lock().is_some());
+    }
+
+    #[tokio::test]
+    async fn pipe_forwards_when_handler_times_out() {
+        let options = PipeOptions {
+            handler_timeout: Some(Duration::from_millis(10)),
+            ..PipeOptions::default()
+        };
+        let input = [1, 0, 0, 0, 0x0e];
+        let (_result, sink, _) = run_pipe(SlowDropHandler {}, options, &input).await;
+        assert_eq!(sink, input.to_vec());
+    }
+
+    #[tokio::test]
+    async fn pipe_forwards_cancel_request_and_refuses_ssl() {
+        let cancel = [0, 0, 0, 16, 0x04, 0xd2, 0x16, 0x2e, 0, 0, 0, 42, 1, 2, 3, 4];
+        let ssl = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+        let mut input = cancel.to_vec();
+        input.extend_from_slice(&ssl);
+        let (_result, sink, responses) = run_db_pipe(
+            DatabaseType::PostgresSQL,
+            DropAllHandler {},
+            PipeOptions::default(),
+            &input,
+        )
+        .await;
+        assert_eq!(sink, cancel.to_vec());
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].bytes, b"N".to_vec());
+    }
+
+    #[tokio::test]
+    async fn handler_allows_postgres_ssl_passthrough() {
+        let ssl = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+        let (_result, sink, responses) = run_db_pipe(
+            DatabaseType::PostgresSQL,
+            AllowSslHandler {},
+            PipeOptions::default(),
+            &ssl,
+        )
+        .await;
+        assert_eq!(sink, ssl.to_vec());
+        assert!(responses.is_empty());
+    }
+
+    fn mariadb_handshake() -> Packet {
+        let mut payload = vec![10];
+        payload.extend_from_slice(b"10.4.12-MariaDB\0");
+        payload.extend_from_slice(&[1, 0, 0, 0]); // connection id
+        payload.extend_from_slice(b"12345678\0"); // auth data and filler
+        payload.extend_from_slice(&[0xff, 0xff]); // capabilities with CLIENT_SSL and CLIENT_COMPRESS
+        payload.extend_from_slice(&[8, 2, 0]);
+        Packet::mariadb(0, &payload)
+    }
+
+    #[tokio::test]
+    async fn mariadb_handshake_hides_ssl_unless_allowed() {
+        let handshake = mariadb_handshake();
+        let (_result, sink, _) = run_directed_pipe(
+            DatabaseType::MariaDB,
+            Direction::Backward,
+            PassthroughHandler {},
+            PipeOptions::default(),
+            &handshake.bytes,
+        )
+        .await;
+        let offset = handshake.get_size() - 5;
+        assert_eq!(sink[offset..(offset + 2)], [0xdf, 0xf7]);
+
+        let (_result, sink, _) = run_directed_pipe(
+            DatabaseType::MariaDB,
+            Direction::Backward,
+            AllowSslHandler {},
+            PipeOptions::default(),
+            &handshake.bytes,
+        )
+        .await;
+        // Compression is never offered
+        assert_eq!(sink[offset..(offset + 2)], [0xdf, 0xff]);
+        assert_eq!(sink[..offset], handshake.bytes[..offset]);
+    }
+
+    #[tokio::test]
+    async fn pipe_refuses_compressed_protocol() {
+        let mut payload = vec![0_u8; 32];
+        payload[0..4].copy_from_slice(&[0xa5, 0xa2, 0x0a, 0x00]); // CLIENT_COMPRESS among others
+        payload.extend_from_slice(b"root\0");
+        let response = Packet::mariadb(1, &payload);
+        let (result, sink, responses) = run_pipe(
+            PassthroughHandler {},
+            PipeOptions::default(),
+            &response.bytes,
+        )
+        .await;
+        assert!(matches!(result, Err(CloseReason::UnsupportedProtocol(_))));
+        assert!(sink.is_empty());
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].get_sequence_id().unwrap(), 2);
+        assert_eq!(responses[0].get_mariadb_error().unwrap().0, 1158);
+    }
+
+    #[tokio::test]
+    async fn pipe_passes_mariadb_tls_through() {
+        let mut payload = vec![0_u8; 32];
+        payload[0..4].copy_from_slice(&[0x85, 0xaa, 0x0a, 0x00]); // CLIENT_SSL among others
+        let mut input = Packet::mariadb(1, &payload).bytes.to_vec();
+        // A TLS ClientHello record, which is not a MariaDB packet
+        input.extend_from_slice(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01]);
+        let (result, sink, _) = run_pipe(AllowSslHandler {}, PipeOptions::default(), &input).await;
+        assert!(result.is_ok());
+        assert_eq!(sink, input);
+    }
+
+    #[tokio::test]
+    async fn pipe_forwards_rewritten_startup_message() {
+        let mut input = Packet::postgres_startup(&[("user", "tenant")])
+            .bytes
+            .to_vec();
+        let query = Packet::postgres(b'Q', b"SELECT 1\0");
+        input.extend_from_slice(&query.bytes);
+        let (_result, sink, _) = run_db_pipe(
+            DatabaseType::PostgresSQL,
+            RewriteUserHandler {},
+            PipeOptions::default(),
+            &input,
+        )
+        .await;
+        let mut expected = Packet::postgres_startup(&[("user", "tenant_role")])
+            .bytes
+            .to_vec();
+        expected.extend_from_slice(&query.bytes);
+        assert_eq!(sink, expected);
+    }
+
+    #[tokio::test]
+    async fn pipe_flushes_buffered_sink() {
+        let (source, mut client) = tokio::net::UnixStream::pair().unwrap();
+        let (sink, mut db) = tokio::net::UnixStream::pair().unwrap();
+        let mut pipe = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            source,
+            tokio::io::BufWriter::new(sink),
+        );
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let ping = [1, 0, 0, 0, 0x0e];
+        let check = async {
+            client.write_all(&ping).await.unwrap();
+            let mut received = [0_u8; 5];
+            // The source stays open, so only a flush gets the ping out of the BufWriter
+            timeout(Duration::from_secs(5), db.read_exact(&mut received))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(received, ping);
+            kill_tx.send(()).unwrap();
+        };
+        let (result, ()) = futures::join!(pipe.run(tx, rx, kill_rx), check);
+        assert!(result.is_ok());
+    }
+
+    /// A sink that records each write separately
+    #[derive(Clone, Default)]
+    struct RecordingSink {
+        writes: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl tokio::io::AsyncWrite for RecordingSink {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<Result<usize>> {
+            self.writes.lock().unwrap().push(buf.to_vec());
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn pipe_limits_read_rate() {
+        let input = [1, 0, 0, 0, 0x0e].repeat(60);
+        let options = PipeOptions {
+            forward_buf_size: 50,
+            byte_rate_limit: Some(ByteRateLimit {
+                bytes_per_second: 1000,
+                burst: 100,
+            }),
+            ..PipeOptions::default()
+        };
+        let started = std::time::Instant::now();
+        let (result, sink, _) = run_pipe(PassthroughHandler {}, options, &input).await;
+        assert!(result.is_ok());
+        assert_eq!(sink, input);
+        // The burst goes at once, the other 200 bytes take 200ms
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_burst() {
+        let mut bucket = TokenBucket::new(ByteRateLimit {
+            bytes_per_second: 100,
+            burst: 10,
+        });
+        let start = bucket.updated;
+        assert_eq!(bucket.ready_at(start), None);
+        bucket.take(30);
+        // 21 bytes short of 1
+        assert_eq!(
+            bucket.ready_at(start),
+            Some(start + Duration::from_millis(210))
+        );
+        assert_eq!(bucket.ready_at(start + Duration::from_millis(210)), None);
+        bucket.ready_at(start + Duration::from_secs(10));
+        assert!((bucket.tokens - 10.0).abs() < f64::EPSILON);
+    }
+
+    #[tokio::test]
+    async fn pipe_delays_writes() {
+        let (source, mut client) = tokio::net::UnixStream::pair().unwrap();
+        let sink = RecordingSink::default();
+        let writes = sink.writes.clone();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            source,
+            sink,
+        )
+        .with_delay(DelayConfig {
+            base: Duration::from_millis(100),
+            jitter: Duration::from_millis(20),
+            ..DelayConfig::default()
+        })
+        .build();
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let ping = [1, 0, 0, 0, 0x0e];
+        let check = async {
+            client.write_all(&ping).await.unwrap();
+            delay_for(Duration::from_millis(50)).await;
+            assert!(writes.lock().unwrap().is_empty());
+            delay_for(Duration::from_millis(150)).await;
+            assert_eq!(writes.lock().unwrap().concat(), ping);
+            kill_tx.send(()).unwrap();
+        };
+        let (result, ()) = futures::join!(pipe.run(tx, rx, kill_rx), check);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn delay_line_keeps_order() {
+        let mut delay_line = DelayLine::new(DelayConfig {
+            base: Duration::from_secs(10),
+            ..DelayConfig::default()
+        });
+        let mut write_buf = vec![1, 2];
+        delay_line.hold(&mut write_buf, 1);
+        assert_eq!(write_buf, [1]);
+        // Not delayed itself, but behind held back output
+        delay_line.config.probability = 0.0;
+        write_buf.push(3);
+        delay_line.hold(&mut write_buf, 1);
+        assert_eq!(write_buf, [1]);
+        assert_eq!(delay_line.held_len, 2);
+        delay_line.release(&mut write_buf, Some(Instant::now()));
+        assert_eq!(write_buf, [1]);
+        delay_line.release(&mut write_buf, None);
+        assert_eq!(write_buf, [1, 2, 3]);
+        assert_eq!(delay_line.next_due(), None);
+        // Nothing held back and not delayed
+        write_buf.push(4);
+        delay_line.hold(&mut write_buf, 3);
+        assert_eq!(write_buf, [1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn pipe_batches_writes_while_lingering() {
+        let (source, mut client) = tokio::net::UnixStream::pair().unwrap();
+        let sink = RecordingSink::default();
+        let writes = sink.writes.clone();
+        let mut pipe = PipeBuilder::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            source,
+            sink,
+        )
+        .with_write_linger(Duration::from_millis(100))
+        .build();
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let ping = [1, 0, 0, 0, 0x0e];
+        let check = async {
+            for _ in 0..3 {
+                client.write_all(&ping).await.unwrap();
+                delay_for(Duration::from_millis(5)).await;
+            }
+            assert!(writes.lock().unwrap().is_empty());
+            delay_for(Duration::from_millis(200)).await;
+            assert_eq!(*writes.lock().unwrap(), vec![ping.repeat(3)]);
+            kill_tx.send(()).unwrap();
+        };
+        let (result, ()) = futures::join!(pipe.run(tx, rx, kill_rx), check);
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn pipe_half_closes_sink_on_eof() {
+        let input = [1, 0, 0, 0, 0x01];
+        let (sink, mut peer) = tokio::net::UnixStream::pair().unwrap();
+        let mut pipe = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Forward,
+            &input[..],
+            sink,
+        );
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        let (_other_tx, rx) = mpsc::channel::<Packet>(16);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        assert!(pipe.run(tx, rx, kill_rx).await.is_ok());
+        // The peer sees the COM_QUIT followed by EOF, while the pipe's sink is still open
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, input.to_vec());
+    }
+
+    #[tokio::test]
+    async fn backward_pipe_closes_cleanly_after_client() {
+        let ok = [7, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0];
+        let (source, mut source_peer) = tokio::net::UnixStream::pair().unwrap();
+        source_peer.write_all(&ok).await.unwrap();
+        let mut sink: Vec<u8> = Vec::new();
+        let mut pipe = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            Arc::new(Mutex::new(PassthroughHandler {})),
+            Direction::Backward,
+            source,
+            &mut sink,
+        );
+        let (tx, _other_rx) = mpsc::channel::<Packet>(16);
+        // The forward pipe has already finished
+        let (other_tx, rx) = mpsc::channel::<Packet>(16);
+        drop(other_tx);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let (result, _) = futures::join!(pipe.run(tx, rx, kill_rx), async move {
+            // Then the database closes
+            delay_for(Duration::from_millis(50)).await;
+            drop(source_peer);
+        });
+        assert!(result.is_ok());
+        drop(pipe);
+        assert_eq!(sink, ok.to_vec());
+    }
+}
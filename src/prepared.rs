@@ -0,0 +1,169 @@
+//! Tracking the SQL of MariaDB prepared statements
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashMap;
+
+use crate::{
+    packet::{DatabaseType, Packet, PacketType},
+    packet_handler::{HandlerAction, PacketContext, PacketHandler},
+};
+
+#[derive(Debug, Default)]
+struct Statements {
+    /// SQL of a COM_STMT_PREPARE waiting for its response
+    preparing: Option<String>,
+    queries: HashMap<u32, String>,
+}
+
+/// Maps the statement ids of MariaDB prepared statements to their SQL, per connection.
+/// The SQL comes from the COM_STMT_PREPARE, and the id from its COM_STMT_PREPARE_OK.
+/// Statements are forgotten on COM_STMT_CLOSE, COM_RESET_CONNECTION and COM_CHANGE_USER,
+/// and connections on COM_QUIT or when they close.
+///
+/// To report the SQL of executes from another handler, put the tracker first in a
+/// `ChainHandler` and give the other handler a clone of its `Arc<Mutex<_>>`, which it can
+/// ask with `tracker.lock().await.executed_query(p, ctx)`
+#[derive(Debug, Default)]
+pub struct PreparedStatementTracker {
+    connections: HashMap<u64, Statements>,
+}
+
+impl PreparedStatementTracker {
+    pub fn new() -> PreparedStatementTracker {
+        PreparedStatementTracker::default()
+    }
+
+    pub fn query(&self, connection_id: u64, statement_id: u32) -> Option<&str> {
+        self.connections
+            .get(&connection_id)
+            .and_then(|c| c.queries.get(&statement_id))
+            .map(|q| q.as_str())
+    }
+
+    /// The SQL of the statement a COM_STMT_EXECUTE runs
+    pub fn executed_query(&self, p: &Packet, ctx: &PacketContext) -> Option<&str> {
+        self.query(ctx.connection_id, p.get_stmt_execute_id()?)
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketHandler for PreparedStatementTracker {
+    async fn handle_request(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        let packet_type = match p.get_packet_type() {
+            Ok(packet_type) if ctx.db_type == DatabaseType::MariaDB => packet_type,
+            _ => return HandlerAction::Forward,
+        };
+        if packet_type == PacketType::ComQuit {
+            self.connections.remove(&ctx.connection_id);
+            return HandlerAction::Forward;
+        }
+        let statements = self.connections.entry(ctx.connection_id).or_default();
+        match packet_type {
+            PacketType::ComStmtPrepare => {
+                statements.preparing = Some(String::from_utf8_lossy(&p.bytes[5..]).into_owned());
+            }
+            PacketType::ComStmtExecute => {
+                if let Some(id) = p.get_stmt_execute_id() {
+                    debug!(
+                        "PreparedStatementTracker: connection #{} executing statement {}: {:?}",
+                        ctx.connection_id,
+                        id,
+                        statements.queries.get(&id)
+                    );
+                }
+            }
+            PacketType::ComStmtClose if p.bytes.len() >= 9 => {
+                let id = LittleEndian::read_u32(&p.bytes[5..9]);
+                statements.queries.remove(&id);
+            }
+            PacketType::ComResetConnection | PacketType::ComChangeUser => {
+                statements.queries.clear();
+            }
+            _ => {}
+        }
+        HandlerAction::Forward
+    }
+
+    async fn handle_response(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        if let Some(statements) = self.connections.get_mut(&ctx.connection_id) {
+            // Only the first packet of the response says whether the prepare succeeded
+            if let Some(query) = statements.preparing.take() {
+                if let Some(ok) = p.get_stmt_prepare_ok() {
+                    statements.queries.insert(ok.statement_id, query);
+                }
+            }
+        }
+        HandlerAction::Forward
+    }
+
+    async fn connection_closed(&mut self, connection_id: u64) {
+        self.connections.remove(&connection_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet_handler::Direction, testing};
+
+    fn context(connection_id: u64) -> PacketContext {
+        PacketContext {
+            connection_id,
+            ..testing::context(DatabaseType::MariaDB, Direction::Forward)
+        }
+    }
+
+    fn prepare_ok(statement_id: u8) -> Packet {
+        Packet::mariadb(1, &[0x00, statement_id, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0])
+    }
+
+    #[tokio::test]
+    async fn tracks_prepared_statements() {
+        let mut tracker = PreparedStatementTracker::new();
+        let ctx = context(1);
+        let prepare = Packet::mariadb(0, b"\x16SELECT * FROM t WHERE id = ?");
+        tracker.handle_request(&prepare, &ctx).await;
+        tracker.handle_response(&prepare_ok(5), &ctx).await;
+        // A parameter definition, which is not a COM_STMT_PREPARE_OK
+        tracker
+            .handle_response(&Packet::mariadb(2, b"\x03def"), &ctx)
+            .await;
+
+        let execute = Packet::mariadb(0, &[0x17, 5, 0, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(
+            tracker.executed_query(&execute, &ctx),
+            Some("SELECT * FROM t WHERE id = ?")
+        );
+        assert_eq!(tracker.executed_query(&execute, &context(2)), None);
+
+        // A failed prepare leaves nothing behind
+        let prepare = Packet::mariadb(0, b"\x16SELEC");
+        tracker.handle_request(&prepare, &ctx).await;
+        let err = Packet::error_packet_mariadb(1064, *b"42000", "Syntax error".to_string());
+        tracker.handle_response(&err, &ctx).await;
+        // An OK that is not for a prepare
+        tracker.handle_response(&prepare_ok(6), &ctx).await;
+        assert_eq!(tracker.query(1, 6), None);
+
+        let close = Packet::mariadb(0, &[0x19, 5, 0, 0, 0]);
+        tracker.handle_request(&close, &ctx).await;
+        assert_eq!(tracker.query(1, 5), None);
+    }
+
+    #[tokio::test]
+    async fn forgets_closed_connections() {
+        let mut tracker = PreparedStatementTracker::new();
+        let prepare = Packet::mariadb(0, b"\x16SELECT ?");
+        for connection_id in 1..=2 {
+            tracker
+                .handle_request(&prepare, &context(connection_id))
+                .await;
+            tracker
+                .handle_response(&prepare_ok(5), &context(connection_id))
+                .await;
+        }
+        // Closed without COM_QUIT
+        tracker.connection_closed(1).await;
+        assert!(!tracker.connections.contains_key(&1));
+        assert_eq!(tracker.query(2, 5), Some("SELECT ?"));
+    }
+}
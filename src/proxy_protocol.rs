@@ -0,0 +1,146 @@
+//! PROXY protocol headers, telling the database the address of the client behind the proxy
+//! https://www.haproxy.org/download/2.2/doc/proxy-protocol.txt
+use byteorder::{BigEndian, WriteBytesExt};
+use std::net::{IpAddr, SocketAddr};
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProxyProtocolVersion {
+    /// Human readable, e.g. `PROXY TCP4 192.0.2.1 192.0.2.2 56324 3306\r\n`
+    V1,
+    /// Binary
+    V2,
+}
+
+/// Header for a client connected from `source` to the proxy's `destination`.
+/// `None` for connections without addresses, e.g. over Unix sockets, which the
+/// database should treat as coming from the proxy itself
+pub fn header(version: ProxyProtocolVersion, addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    // Mixed address families are sent as IPv6, with IPv4 addresses mapped
+    let addrs = addrs.map(
+        |(source, destination)| match (source.ip(), destination.ip()) {
+            (IpAddr::V4(_), IpAddr::V6(_)) => (to_ipv6(source), destination),
+            (IpAddr::V6(_), IpAddr::V4(_)) => (source, to_ipv6(destination)),
+            _ => (source, destination),
+        },
+    );
+    match version {
+        ProxyProtocolVersion::V1 => header_v1(addrs),
+        ProxyProtocolVersion::V2 => header_v2(addrs),
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+fn header_v1(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    match addrs {
+        Some((source, destination)) => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        None => b"PROXY UNKNOWN\r\n".to_vec(),
+    }
+}
+
+fn header_v2(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let (source, destination) = match addrs {
+        Some(addrs) => addrs,
+        None => {
+            // LOCAL command, with no addresses
+            header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+            return header;
+        }
+    };
+    let mut addresses = Vec::with_capacity(36);
+    let family = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            addresses.extend_from_slice(&s.octets());
+            addresses.extend_from_slice(&d.octets());
+            0x11 // TCP over IPv4
+        }
+        (s, d) => {
+            addresses.extend_from_slice(&to_ipv6_octets(s));
+            addresses.extend_from_slice(&to_ipv6_octets(d));
+            0x21 // TCP over IPv6
+        }
+    };
+    addresses.write_u16::<BigEndian>(source.port()).unwrap();
+    addresses
+        .write_u16::<BigEndian>(destination.port())
+        .unwrap();
+    header.push(0x21); // version 2, PROXY command
+    header.push(family);
+    header
+        .write_u16::<BigEndian>(addresses.len() as u16)
+        .unwrap();
+    header.extend_from_slice(&addresses);
+    header
+}
+
+fn to_ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_v1_headers() {
+        let source = "192.0.2.1:56324".parse().unwrap();
+        let destination = "192.0.2.2:3306".parse().unwrap();
+        assert_eq!(
+            header(ProxyProtocolVersion::V1, Some((source, destination))),
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 3306\r\n".to_vec()
+        );
+        let destination = "[2001:db8::2]:3306".parse().unwrap();
+        assert_eq!(
+            header(ProxyProtocolVersion::V1, Some((source, destination))),
+            b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 56324 3306\r\n".to_vec()
+        );
+        assert_eq!(
+            header(ProxyProtocolVersion::V1, None),
+            b"PROXY UNKNOWN\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn builds_v2_headers() {
+        let source = "192.0.2.1:56324".parse().unwrap();
+        let destination = "192.0.2.2:3306".parse().unwrap();
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 0, 2, 1, 192, 0, 2, 2]);
+        expected.extend_from_slice(&[0xdc, 0x04, 0x0c, 0xea]);
+        assert_eq!(
+            header(ProxyProtocolVersion::V2, Some((source, destination))),
+            expected
+        );
+
+        let destination = "[2001:db8::2]:3306".parse().unwrap();
+        let v6 = header(ProxyProtocolVersion::V2, Some((source, destination)));
+        assert_eq!(v6[12..16], [0x21, 0x21, 0, 36]);
+        assert_eq!(v6.len(), 16 + 36);
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(header(ProxyProtocolVersion::V2, None), expected);
+    }
+}
@@ -0,0 +1,184 @@
+//! Timing queries from the request to the end of their response
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    packet::{DatabaseType, Packet, PacketType},
+    packet_handler::Direction,
+    session::{ok_status_flags, read_lenenc_int},
+};
+
+/// MariaDB status flag for a response followed by another result set
+const SERVER_MORE_RESULTS_EXISTS: u16 = 0x0008;
+
+/// Where a MariaDB response is at
+#[derive(Debug)]
+pub(crate) enum Response {
+    /// Waiting for an OK, ERR or the column count of a result set
+    First,
+    /// Column definitions left to read
+    Columns(u64),
+    /// Followed by an EOF, unless the client set CLIENT_DEPRECATE_EOF
+    ColumnsDone,
+    Rows,
+}
+
+#[derive(Debug)]
+struct Running {
+    query: String,
+    started: Instant,
+    response: Response,
+}
+
+/// The query a connection is waiting on, shared by its forward and backward pipes.
+///
+/// A MariaDB response is complete after an OK or ERR, or the EOF that ends a result set,
+/// unless more result sets follow. A PostgresSQL one is complete at ReadyForQuery.
+/// Only MariaDB COM_QUERY and PostgresSQL Query ('Q') are timed
+#[derive(Debug, Default)]
+pub struct QueryTimer(Mutex<Option<Running>>);
+
+impl QueryTimer {
+    pub fn new() -> QueryTimer {
+        QueryTimer::default()
+    }
+
+    /// Updates the timer from a packet read by a pipe.
+    /// Returns the query and how long it took once its response is complete
+    pub(crate) fn observe(&self, p: &Packet, direction: Direction) -> Option<(String, Duration)> {
+        let mut running = self.0.lock().unwrap();
+        if direction == Direction::Forward {
+            match p.get_query() {
+                Ok(query) => {
+                    *running = Some(Running {
+                        query,
+                        started: Instant::now(),
+                        response: Response::First,
+                    })
+                }
+                // Any other MariaDB command gets the next response
+                Err(_e) if p.get_db_type() == DatabaseType::MariaDB => *running = None,
+                // e.g. CopyData for a COPY FROM STDIN
+                Err(_e) => {}
+            }
+            return None;
+        }
+        let complete = match (p.get_db_type(), running.as_mut()) {
+            (_, None) => false,
+            (DatabaseType::MariaDB, Some(r)) => mariadb_response_complete(&mut r.response, p),
+            (DatabaseType::PostgresSQL, Some(_r)) => {
+                p.get_packet_type().ok() == Some(PacketType::ReadyForQuery)
+            }
+        };
+        if complete {
+            running.take().map(|r| (r.query, r.started.elapsed()))
+        } else {
+            None
+        }
+    }
+}
+
+/// https://mariadb.com/kb/en/result-set-packets/
+pub(crate) fn mariadb_response_complete(response: &mut Response, p: &Packet) -> bool {
+    let response_type = p.get_response_type().ok();
+    match response {
+        Response::First => match response_type {
+            Some(PacketType::ComOk) => !more_results(p),
+            Some(PacketType::ComErr) => true,
+            _ => {
+                if let Some((columns, _size)) = p.bytes.get(4..).and_then(read_lenenc_int) {
+                    *response = Response::Columns(columns);
+                }
+                false
+            }
+        },
+        Response::Columns(left) => {
+            *left = left.saturating_sub(1);
+            if *left == 0 {
+                *response = Response::ColumnsDone;
+            }
+            false
+        }
+        Response::ColumnsDone => {
+            *response = Response::Rows;
+            match response_type {
+                Some(PacketType::ComEof) => false,
+                _ => mariadb_response_complete(response, p),
+            }
+        }
+        Response::Rows => match response_type {
+            Some(PacketType::ComEof) if more_results(p) => {
+                *response = Response::First;
+                false
+            }
+            Some(PacketType::ComEof) | Some(PacketType::ComErr) => true,
+            _ => false,
+        },
+    }
+}
+
+/// Whether an OK or EOF packet says another result set follows
+fn more_results(p: &Packet) -> bool {
+    let status = if p.bytes.len() == 4 + 5 {
+        // EOF: header, warnings, status flags
+        Some(u16::from_le_bytes([p.bytes[7], p.bytes[8]]))
+    } else {
+        ok_status_flags(&p.bytes[..])
+    };
+    status.is_some_and(|s| s & SERVER_MORE_RESULTS_EXISTS != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eof(status: u16) -> Packet {
+        let status = status.to_le_bytes();
+        Packet::mariadb(0, &[0xfe, 0, 0, status[0], status[1]])
+    }
+
+    #[test]
+    fn times_mariadb_result_set() {
+        let timer = QueryTimer::new();
+        let query = Packet::mariadb(0, b"\x03SELECT 1, 2");
+        assert_eq!(timer.observe(&query, Direction::Forward), None);
+        let response = [
+            Packet::mariadb(1, &[2]),
+            Packet::mariadb(2, b"\x03def..."),
+            Packet::mariadb(3, b"\x03def..."),
+            eof(0),
+            Packet::mariadb(5, b"\x011\x012"),
+            // Another result set follows
+            eof(SERVER_MORE_RESULTS_EXISTS),
+            Packet::mariadb(7, &[0, 0, 0, 0, 0, 0, 0]),
+        ];
+        for p in response[..6].iter() {
+            assert_eq!(timer.observe(p, Direction::Backward), None);
+        }
+        let (text, _elapsed) = timer.observe(&response[6], Direction::Backward).unwrap();
+        assert_eq!(text, "SELECT 1, 2");
+        // Nothing is running anymore
+        assert_eq!(timer.observe(&eof(0), Direction::Backward), None);
+
+        // A COM_PING's OK is not the query's
+        timer.observe(&query, Direction::Forward);
+        timer.observe(&Packet::mariadb(0, &[0x0e]), Direction::Forward);
+        let ok = Packet::mariadb(1, &[0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(timer.observe(&ok, Direction::Backward), None);
+    }
+
+    #[test]
+    fn times_postgres_query() {
+        let timer = QueryTimer::new();
+        timer.observe(&Packet::postgres(b'Q', b"SELECT 1\0"), Direction::Forward);
+        let row = Packet::postgres(b'D', &[0, 1, 0, 0, 0, 1, b'1']);
+        assert_eq!(timer.observe(&row, Direction::Backward), None);
+        let complete = Packet::postgres(b'C', b"SELECT 1\0");
+        assert_eq!(timer.observe(&complete, Direction::Backward), None);
+        let ready = Packet::postgres(b'Z', b"I");
+        let (query, _elapsed) = timer.observe(&ready, Direction::Backward).unwrap();
+        assert_eq!(query, "SELECT 1");
+    }
+}
@@ -0,0 +1,95 @@
+//! Pairing responses with the requests they answer
+use std::sync::Mutex;
+
+use crate::{
+    packet::{DatabaseType, Packet, PacketType},
+    packet_handler::{CorrelationState, RequestContext},
+};
+
+#[derive(Debug, Default)]
+struct Tracked {
+    current: Option<RequestContext>,
+    next_id: u64,
+}
+
+/// The request a connection's database is answering, shared by its forward and backward
+/// pipes, see `PacketHandler::handle_response_for`.
+///
+/// A response is paired with the latest request the forward pipe passed on to the database
+/// before it. Requests the handler drops or answers itself are skipped, as the database never
+/// answers them. MariaDB
+/// clients wait for the response to a command before sending the next one, as PostgresSQL
+/// clients do with simple queries, so these are paired exactly. A PostgresSQL Sync or Flush
+/// isn't a request of its own, so the responses to a pipelined Parse, Bind, Execute and Sync
+/// are paired with the latest of those the forward pipe has read, usually the Execute
+#[derive(Debug, Default)]
+pub struct RequestTracker(Mutex<Tracked>);
+
+impl RequestTracker {
+    pub fn new() -> RequestTracker {
+        RequestTracker::default()
+    }
+
+    /// The request responses are paired with, if the client has sent one
+    pub fn current(&self) -> Option<RequestContext> {
+        self.0.lock().unwrap().current.clone()
+    }
+
+    /// Called by the forward pipe with each request the handler forwards or replaces
+    pub(crate) fn start(&self, p: &Packet) {
+        if p.get_db_type() == DatabaseType::PostgresSQL {
+            if let Ok(PacketType::Sync) | Ok(PacketType::Flush) = p.get_packet_type() {
+                return;
+            }
+        }
+        let mut tracked = self.0.lock().unwrap();
+        tracked.current = Some(RequestContext {
+            id: tracked.next_id,
+            packet: p.clone(),
+            state: None,
+        });
+        tracked.next_id += 1;
+    }
+
+    /// Keeps what the handler's `correlate` returned for the current request
+    pub(crate) fn set_state(&self, state: Option<CorrelationState>) {
+        if let Some(request) = self.0.lock().unwrap().current.as_mut() {
+            request.state = state;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn pairs_responses_with_latest_request() {
+        let requests = RequestTracker::new();
+        assert!(requests.current().is_none());
+        let query = Packet::mariadb(0, b"\x03SELECT 1");
+        requests.start(&query);
+        requests.set_state(Some(Arc::new(42_u32)));
+        let request = requests.current().unwrap();
+        assert_eq!((request.id, request.packet), (0, query));
+        let state = request.state.unwrap();
+        assert_eq!(state.downcast_ref::<u32>(), Some(&42));
+
+        requests.start(&Packet::mariadb(0, &[0x0e]));
+        let request = requests.current().unwrap();
+        assert_eq!(request.id, 1);
+        assert!(request.state.is_none());
+    }
+
+    #[test]
+    fn skips_postgres_sync() {
+        let requests = RequestTracker::new();
+        let execute = Packet::postgres(b'E', b"\0\0\0\0\0");
+        requests.start(&execute);
+        requests.start(&Packet::postgres(b'S', b""));
+        requests.start(&Packet::postgres(b'H', b""));
+        let request = requests.current().unwrap();
+        assert_eq!((request.id, request.packet), (0, execute));
+    }
+}
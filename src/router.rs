@@ -0,0 +1,317 @@
+//! Choosing the database a connection is proxied to
+use byteorder::{BigEndian, ByteOrder};
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Result};
+
+use crate::{
+    packet::{DatabaseType, Packet, PacketType},
+    packet_handler::{HandlerAction, PacketContext, PacketHandler},
+    pipe::PipeOptions,
+};
+
+#[async_trait::async_trait]
+pub trait BackendRouter: Send + Sync {
+    /// Called with the first packet from the client, before connecting to the database.
+    /// For PostgresSQL, this is the StartupMessage, or an SSLRequest if
+    /// `allow_ssl_passthrough` is set and the client asks for SSL.
+    /// MariaDB databases speak first, so MariaDB connections are never routed
+    async fn select(&self, first_packet: &Packet) -> SocketAddr;
+}
+
+/// Which database a statement can run on
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Route {
+    Primary,
+    Replica,
+}
+
+/// SELECTs can run on a replica, everything else needs the primary.
+/// Leading whitespace and comments are skipped, and the keyword is case-insensitive
+pub fn query_route(query: &str) -> Route {
+    if first_keyword(query).eq_ignore_ascii_case("SELECT") {
+        Route::Replica
+    } else {
+        Route::Primary
+    }
+}
+
+/// The first word of a statement, after whitespace and comments
+pub(crate) fn first_keyword(query: &str) -> &str {
+    let rest = skip_comments(query);
+    let end = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    &rest[..end]
+}
+
+/// What follows the leading whitespace and comments of a statement
+pub(crate) fn skip_comments(query: &str) -> &str {
+    let mut rest = query;
+    loop {
+        rest = rest.trim_start();
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.find('\n').map_or("", |i| &comment[i..]);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.find("*/").map_or("", |i| &comment[(i + 2)..]);
+        } else {
+            return rest;
+        }
+    }
+}
+
+/// Whether a PostgresSQL StartupMessage asks for a read-only session,
+/// with `default_transaction_read_only=on` as a parameter or in `options`
+pub fn is_read_only_session(startup: &Packet) -> bool {
+    let params = match startup.get_postgres_startup() {
+        Ok(params) => params,
+        Err(_e) => return false,
+    };
+    let is_on = |v: &str| matches!(v, "on" | "true" | "yes" | "1");
+    params.parameters.iter().any(|(k, v)| match k.as_str() {
+        "default_transaction_read_only" => is_on(v),
+        // e.g. "-c default_transaction_read_only=on" or "--default_transaction_read_only=on"
+        "options" => v.split_whitespace().any(|o| {
+            let o = o.trim_start_matches('-');
+            let o = o.strip_prefix("c").unwrap_or(o);
+            o.strip_prefix("default_transaction_read_only=")
+                .is_some_and(is_on)
+        }),
+        _ => false,
+    })
+}
+
+/// Sends read-only PostgresSQL sessions (see `is_read_only_session`) to `replica`,
+/// and everything else to `primary`.
+///
+/// This is session-level routing: the database is chosen from the StartupMessage, before
+/// any statement is sent, and a connection can't move between databases once it has
+/// authenticated. SELECTs on a read-write session stay on the primary.
+/// Pair with `StatementClassifier` to see which statements needed the primary
+#[derive(Clone, Debug)]
+pub struct ReadOnlySessionRouter {
+    primary: SocketAddr,
+    replica: SocketAddr,
+}
+
+impl ReadOnlySessionRouter {
+    pub fn new(primary: SocketAddr, replica: SocketAddr) -> ReadOnlySessionRouter {
+        ReadOnlySessionRouter { primary, replica }
+    }
+}
+
+#[async_trait::async_trait]
+impl BackendRouter for ReadOnlySessionRouter {
+    async fn select(&self, first_packet: &Packet) -> SocketAddr {
+        if is_read_only_session(first_packet) {
+            self.replica
+        } else {
+            self.primary
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Session {
+    replica: bool,
+    in_transaction: bool,
+    last_route: Option<Route>,
+}
+
+/// Classifies every statement with `query_route`, per connection, without changing where
+/// it goes: that was decided by the `BackendRouter` when the connection was made.
+/// Once a transaction starts (BEGIN / START TRANSACTION), everything needs the primary
+/// until it ends (COMMIT / ROLLBACK / END / ABORT).
+/// Statements that need the primary on a session `ReadOnlySessionRouter` sent to the replica
+/// are logged, the replica will refuse them.
+/// A connection is forgotten on Terminate or COM_QUIT, or when it closes
+#[derive(Debug, Default)]
+pub struct StatementClassifier {
+    sessions: HashMap<u64, Session>,
+}
+
+impl StatementClassifier {
+    pub fn new() -> StatementClassifier {
+        StatementClassifier::default()
+    }
+
+    /// Route the connection's most recent statement would need, see `query_route`
+    pub fn last_route(&self, connection_id: u64) -> Option<Route> {
+        self.sessions.get(&connection_id).and_then(|s| s.last_route)
+    }
+
+    pub fn in_transaction(&self, connection_id: u64) -> bool {
+        self.sessions
+            .get(&connection_id)
+            .is_some_and(|s| s.in_transaction)
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketHandler for StatementClassifier {
+    async fn handle_request(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        match p.get_packet_type() {
+            Ok(PacketType::StartupMessage) => {
+                let session = self.sessions.entry(ctx.connection_id).or_default();
+                session.replica = is_read_only_session(p);
+            }
+            Ok(PacketType::Terminate) | Ok(PacketType::ComQuit) => {
+                self.sessions.remove(&ctx.connection_id);
+            }
+            _ => {
+                if let Ok(query) = p.get_query() {
+                    let session = self.sessions.entry(ctx.connection_id).or_default();
+                    let keyword = first_keyword(&query).to_ascii_uppercase();
+                    let is_transaction_control = match keyword.as_str() {
+                        "BEGIN" | "START" => {
+                            session.in_transaction = true;
+                            true
+                        }
+                        "COMMIT" | "ROLLBACK" | "END" | "ABORT" => {
+                            session.in_transaction = false;
+                            true
+                        }
+                        _ => false,
+                    };
+                    let route = query_route(&query);
+                    if session.replica && route == Route::Primary && !is_transaction_control {
+                        warn!(
+                            "StatementClassifier: connection #{} sent {} to the replica",
+                            ctx.connection_id, keyword
+                        );
+                    }
+                    session.last_route = if session.in_transaction || is_transaction_control {
+                        Some(Route::Primary)
+                    } else {
+                        Some(route)
+                    };
+                }
+            }
+        }
+        HandlerAction::Forward
+    }
+
+    async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+        HandlerAction::Forward
+    }
+
+    async fn connection_closed(&mut self, connection_id: u64) {
+        self.sessions.remove(&connection_id);
+    }
+}
+
+/// Reads the first PostgresSQL message the database needs to see.
+/// SSLRequests (unless `allow_ssl_passthrough`) and GSSENCRequests are refused with an 'N',
+/// after which the client continues with another request or its StartupMessage
+pub(crate) async fn read_postgres_first_packet<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    options: &PipeOptions,
+) -> Result<Packet>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let mut length = [0_u8; 4];
+        reader.read_exact(&mut length).await?;
+        let l = BigEndian::read_u32(&length) as usize;
+        if l < 8 || l > options.max_packet_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid PostgresSQL startup packet length {}", l),
+            ));
+        }
+        let mut bytes = vec![0_u8; l];
+        bytes[0..4].copy_from_slice(&length);
+        reader.read_exact(&mut bytes[4..]).await?;
+        let packet = Packet::new(DatabaseType::PostgresSQL, bytes);
+        match packet.get_packet_type() {
+            Ok(PacketType::SSLRequest) if options.allow_ssl_passthrough => return Ok(packet),
+            Ok(PacketType::SSLRequest) | Ok(PacketType::GSSENCRequest) => {
+                writer.write_all(b"N").await?;
+            }
+            _ => return Ok(packet),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet_handler::Direction, testing};
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn routes_selects_to_replica() {
+        assert_eq!(query_route("SELECT 1"), Route::Replica);
+        assert_eq!(query_route("  \n\tselect * from t"), Route::Replica);
+        assert_eq!(query_route("/* app */ -- note\n Select 1"), Route::Replica);
+        assert_eq!(query_route("SELECTED"), Route::Primary);
+        assert_eq!(query_route("INSERT INTO t VALUES (1)"), Route::Primary);
+        assert_eq!(query_route("-- SELECT\nDELETE FROM t"), Route::Primary);
+    }
+
+    #[test]
+    fn detects_read_only_sessions() {
+        assert!(is_read_only_session(&Packet::postgres_startup(&[
+            ("user", "root"),
+            ("default_transaction_read_only", "on"),
+        ])));
+        assert!(is_read_only_session(&Packet::postgres_startup(&[
+            ("user", "root"),
+            ("options", "-c default_transaction_read_only=on"),
+        ])));
+        assert!(!is_read_only_session(&Packet::postgres_startup(&[(
+            "user", "root"
+        )])));
+    }
+
+    #[tokio::test]
+    async fn classifies_transactions_as_primary() {
+        let ctx = PacketContext {
+            connection_id: 7,
+            ..testing::context(DatabaseType::PostgresSQL, Direction::Forward)
+        };
+        let query = |q: &str| {
+            let mut payload = q.as_bytes().to_vec();
+            payload.push(0);
+            Packet::postgres(b'Q', &payload)
+        };
+        let mut h = StatementClassifier::new();
+        h.handle_request(&query("SELECT 1"), &ctx).await;
+        assert_eq!(h.last_route(7), Some(Route::Replica));
+        h.handle_request(&query("BEGIN"), &ctx).await;
+        h.handle_request(&query("SELECT 1"), &ctx).await;
+        assert_eq!(h.last_route(7), Some(Route::Primary));
+        assert!(h.in_transaction(7));
+        h.handle_request(&query("COMMIT"), &ctx).await;
+        h.handle_request(&query("select 1"), &ctx).await;
+        assert_eq!(h.last_route(7), Some(Route::Replica));
+        assert_eq!(h.last_route(8), None);
+        // Closed without Terminate
+        h.connection_closed(7).await;
+        assert!(h.sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_ssl_before_startup() {
+        let (mut client, mut peer) = UnixStream::pair().unwrap();
+        let startup = [0, 0, 0, 9, 0, 3, 0, 0, 0];
+        peer.write_all(&[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f])
+            .await
+            .unwrap();
+        peer.write_all(&startup).await.unwrap();
+        let (mut reader, mut writer) = client.split();
+        let packet = read_postgres_first_packet(&mut reader, &mut writer, &PipeOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(packet.bytes, startup.to_vec());
+        let mut answer = [0_u8; 1];
+        peer.read_exact(&mut answer).await.unwrap();
+        assert_eq!(&answer, b"N");
+    }
+}
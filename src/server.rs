@@ -2,76 +2,993 @@ use futures::{
     channel::{mpsc, oneshot},
     future::FutureExt,
     lock::Mutex,
-    select,
+    pin_mut, select,
     stream::StreamExt,
 };
-use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{Cursor, Error, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{split, AsyncReadExt, AsyncWrite, AsyncWriteExt, Result},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{delay_for, timeout},
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 use crate::{
     packet::{DatabaseType, Packet},
-    packet_handler::{Direction, PacketHandler},
-    pipe::Pipe,
+    packet_handler::{Direction, HandlerFactory, PacketHandler},
+    pipe::{
+        random_fraction, Activity, Backpressure, CloseReason, PipeBuilder, PipeOptions, SslState,
+        Verbose,
+    },
+    proxy_protocol::{self, ProxyProtocolVersion},
+    query_timer::QueryTimer,
+    request_tracker::RequestTracker,
+    router::{self, BackendRouter},
+    session::SessionTracker,
+    stream::{Listener, Stream},
+    tls::{self, BackendTlsConfig, ClientReader, ClientWriter, ServerReader, ServerWriter},
 };
 
-#[derive(Debug)]
 pub struct Server {
     db_type: DatabaseType,
     db_addr: String,
-    listener: TcpListener,
-    kill_switches: Vec<oneshot::Sender<()>>,
+    listeners: Vec<Listener>,
+    kill_switches: KillSwitches,
+    verbose: VerboseFlags,
+    verbose_clients: Option<Arc<ClientMatcher>>,
+    pipe_options: PipeOptions,
+    next_connection_id: u64,
+    tls_acceptor: Option<TlsAcceptor>,
+    backend_tls: Option<Arc<BackendTls>>,
+    router: Option<Arc<dyn BackendRouter>>,
+    max_connections: Option<usize>,
+    stats: Arc<ServerStats>,
+    tcp_options: TcpOptions,
+    connect_options: ConnectOptions,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    db_type_detection: Option<DbTypeDetection>,
+}
+
+/// How to connect to the database over TLS, see `Server::with_backend_tls`
+struct BackendTls {
+    connector: TlsConnector,
+    config: BackendTlsConfig,
+}
+
+/// The database of each type, see `Server::with_db_type_detection`
+#[derive(Clone, Debug)]
+struct DbTypeDetection {
+    mariadb_addr: String,
+    postgres_addr: String,
+    wait: Duration,
+}
+
+impl DbTypeDetection {
+    /// Peeks at what the client sends first, without consuming it.
+    /// Falls back to `db_type` when that is inconclusive, or the client is on a Unix socket,
+    /// which can't be peeked
+    async fn detect(
+        &self,
+        client_socket: &mut Stream,
+        db_type: DatabaseType,
+    ) -> (DatabaseType, String) {
+        let detected = match client_socket.as_tcp_mut() {
+            Some(socket) => {
+                let mut first_bytes = [0_u8; 8];
+                let mut peeked = 0;
+                let peek = async {
+                    // Peeking returns as soon as anything is there, wait for the rest
+                    loop {
+                        peeked = socket.peek(&mut first_bytes).await?;
+                        if peeked == 0 || peeked == first_bytes.len() {
+                            return Ok::<(), Error>(());
+                        }
+                        delay_for(Duration::from_millis(1)).await;
+                    }
+                };
+                match timeout(self.wait, peek).await {
+                    Ok(Ok(())) => DatabaseType::detect(&first_bytes[..peeked]),
+                    // Waiting for the database to speak first
+                    Err(_) if peeked == 0 => Some(DatabaseType::MariaDB),
+                    _ => None,
+                }
+            }
+            None => None,
+        };
+        let db_type = detected.unwrap_or(db_type);
+        let db_addr = match db_type {
+            DatabaseType::MariaDB => self.mariadb_addr.clone(),
+            DatabaseType::PostgresSQL => self.postgres_addr.clone(),
+        };
+        (db_type, db_addr)
+    }
+}
+
+/// How to connect to the database
+#[derive(Clone, Debug)]
+struct ConnectOptions {
+    retry_policy: RetryPolicy,
+    /// Limit on each attempt
+    timeout: Duration,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Tried in order after the database fails, see `Server::with_failover`
+    failover: Vec<String>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> ConnectOptions {
+        ConnectOptions {
+            retry_policy: RetryPolicy::default(),
+            timeout: Duration::from_secs(5),
+            circuit_breaker: None,
+            failover: Vec::new(),
+        }
+    }
+}
+
+/// When to stop trying a database that keeps failing, see `Server::with_circuit_breaker`
+#[derive(Copy, Clone, Debug)]
+pub struct CircuitBreakerPolicy {
+    /// Failed connections in a row, each after its retries, that open the breaker (default 5)
+    pub failure_threshold: u32,
+    /// Failures further apart than this don't add up (default 10s)
+    pub window: Duration,
+    /// How long clients are turned away once the breaker opens (default 30s).
+    /// After that, one client is let through to test the database
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> CircuitBreakerPolicy {
+        CircuitBreakerPolicy {
+            failure_threshold: 5,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    first_failure: Option<Instant>,
+    opened: Option<Instant>,
+    /// Whether the one connection let through after the cooldown is in progress
+    probing: bool,
+}
+
+/// The breaker state of each database address
+#[derive(Debug)]
+struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    backends: std::sync::Mutex<HashMap<String, BreakerState>>,
+}
+
+impl CircuitBreaker {
+    fn new(policy: CircuitBreakerPolicy) -> CircuitBreaker {
+        CircuitBreaker {
+            policy,
+            backends: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether to try connecting to `db_addr`
+    fn allow(&self, db_addr: &str, now: Instant) -> bool {
+        let mut backends = self.backends.lock().unwrap();
+        let state = match backends.get_mut(db_addr) {
+            Some(state) => state,
+            None => return true,
+        };
+        match state.opened {
+            None => true,
+            Some(opened) if now.saturating_duration_since(opened) < self.policy.cooldown => false,
+            // Half-open, a single connection tests the database
+            Some(_) if state.probing => false,
+            Some(_) => {
+                state.probing = true;
+                true
+            }
+        }
+    }
+
+    fn record_success(&self, db_addr: &str) {
+        self.backends.lock().unwrap().remove(db_addr);
+    }
+
+    fn record_failure(&self, db_addr: &str, now: Instant) {
+        let mut backends = self.backends.lock().unwrap();
+        let state = backends.entry(db_addr.to_string()).or_default();
+        if state.probing {
+            state.probing = false;
+            state.opened = Some(now);
+            return;
+        }
+        let in_window = state
+            .first_failure
+            .is_some_and(|first| now.saturating_duration_since(first) <= self.policy.window);
+        if !in_window {
+            state.failures = 0;
+            state.first_failure = Some(now);
+        }
+        state.failures += 1;
+        if state.failures >= self.policy.failure_threshold {
+            warn!(
+                "Server: {} failures connecting to {}, turning clients away for {:?}",
+                state.failures, db_addr, self.policy.cooldown
+            );
+            state.failures = 0;
+            state.first_failure = None;
+            state.opened = Some(now);
+        }
+    }
+}
+
+/// How often, and how patiently, to retry connecting to the database,
+/// e.g. while it restarts or fails over. The client connection is held open meanwhile
+#[derive(Copy, Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts after the first one. The default, 0, never retries
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each one after that (default 100ms)
+    pub base_delay: Duration,
+    /// Longest delay between two attempts (default 5s)
+    pub max_delay: Duration,
+    /// Fraction of each delay, between 0.0 and 1.0, that is randomly taken off it,
+    /// so clients that failed together don't retry together (default 0.2)
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `retry`, counting from 0
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .checked_mul(2_u32.saturating_pow(retry))
+            .map_or(self.max_delay, |d| d.min(self.max_delay));
+        delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random_fraction())
+    }
+}
+
+/// Socket options for both the client and database connections
+#[derive(Copy, Clone, Debug)]
+struct TcpOptions {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> TcpOptions {
+        TcpOptions {
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    /// Failures are logged, the connection works without the options
+    fn apply(&self, socket: &Stream) {
+        let socket = match socket.as_tcp() {
+            Some(socket) => socket,
+            None => return,
+        };
+        if let Err(e) = socket.set_nodelay(self.nodelay) {
+            warn!("Server: Unable to set TCP_NODELAY: {}", e);
+        }
+        if let Err(e) = socket.set_keepalive(self.keepalive) {
+            warn!("Server: Unable to set SO_KEEPALIVE: {}", e);
+        }
+    }
+}
+
+/// The kill switches of the forward and backward pipes of each open connection, by id
+type KillSwitches = Arc<std::sync::Mutex<HashMap<u64, (oneshot::Sender<()>, oneshot::Sender<()>)>>>;
+
+/// Closes connections of a running server, which `Server::run` keeps borrowed.
+/// See `Server::connection_killer`
+#[derive(Clone, Debug)]
+pub struct ConnectionKiller {
+    kill_switches: KillSwitches,
+}
+
+impl ConnectionKiller {
+    /// Signals both pipes of connection `connection_id` to flush and close, like the server's
+    /// kill switch does for every connection. Returns false if no such connection is open
+    pub fn kill_connection(&self, connection_id: u64) -> bool {
+        let kill_switches = self.kill_switches.lock().unwrap().remove(&connection_id);
+        match kill_switches {
+            Some((forward, backward)) => {
+                info!("Killing connection #{}", connection_id);
+                let _ = forward.send(());
+                let _ = backward.send(());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// See `Server::with_verbose_clients`
+type ClientMatcher = dyn Fn(&str) -> bool + Send + Sync;
+
+/// The `Verbose` flag shared by the pipes of each open connection, by id
+type VerboseFlags = Arc<std::sync::Mutex<HashMap<u64, Arc<Verbose>>>>;
+
+/// Turns detailed logging on and off for connections of a running server, which
+/// `Server::run` keeps borrowed. See `Server::connection_logging`
+#[derive(Clone, Debug)]
+pub struct ConnectionLogging {
+    verbose: VerboseFlags,
+}
+
+impl ConnectionLogging {
+    /// Makes the pipes of connection `connection_id` log in detail, or stop doing so,
+    /// see `Verbose`. Returns false if no such connection is open
+    pub fn set_verbose(&self, connection_id: u64, verbose: bool) -> bool {
+        match self.verbose.lock().unwrap().get(&connection_id) {
+            Some(flag) => {
+                info!(
+                    "Turning verbose logging {} for connection #{}",
+                    if verbose { "on" } else { "off" },
+                    connection_id
+                );
+                flag.set(verbose);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Live connection counters of a server, readable from other tasks while it runs
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    connections_accepted: AtomicU64,
+    connections_active: AtomicUsize,
+    connections_closed: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        ServerStats::default()
+    }
+
+    /// Connections accepted since the server started, including those that failed to
+    /// reach the database
+    pub fn connections_accepted(&self) -> u64 {
+        self.connections_accepted.load(Ordering::SeqCst)
+    }
+
+    /// Connections whose pipes are still running
+    pub fn connections_active(&self) -> usize {
+        self.connections_active.load(Ordering::SeqCst)
+    }
+
+    /// Connections that are done, once both of their pipes have closed
+    pub fn connections_closed(&self) -> u64 {
+        self.connections_closed.load(Ordering::SeqCst)
+    }
+}
+
+/// Held by a connection's task until both of its pipes have closed
+struct ConnectionGuard {
+    connection_id: u64,
+    _drain: mpsc::Sender<()>,
+    _permit: Option<OwnedSemaphorePermit>,
+    stats: Arc<ServerStats>,
+    kill_switches: KillSwitches,
+    verbose: VerboseFlags,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.kill_switches
+            .lock()
+            .unwrap()
+            .remove(&self.connection_id);
+        self.verbose.lock().unwrap().remove(&self.connection_id);
+        self.stats.connections_active.fetch_sub(1, Ordering::SeqCst);
+        self.stats.connections_closed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("db_type", &self.db_type)
+            .field("db_addr", &self.db_addr)
+            .field("listeners", &self.listeners)
+            .field("kill_switches", &self.kill_switches)
+            .field("verbose", &self.verbose)
+            .field("verbose_clients", &self.verbose_clients.is_some())
+            .field("pipe_options", &self.pipe_options)
+            .field("next_connection_id", &self.next_connection_id)
+            .field("tls", &self.tls_acceptor.is_some())
+            .field("backend_tls", &self.backend_tls.is_some())
+            .field("router", &self.router.is_some())
+            .field("max_connections", &self.max_connections)
+            .field("stats", &self.stats)
+            .field("tcp_options", &self.tcp_options)
+            .field("connect_options", &self.connect_options)
+            .field("proxy_protocol", &self.proxy_protocol)
+            .field("db_type_detection", &self.db_type_detection)
+            .finish()
+    }
 }
 
 impl Server {
+    /// `bind_addr` and `db_addr` are TCP addresses, or Unix socket paths
+    /// prefixed with `unix:`, e.g. `unix:/var/run/mysqld/mysqld.sock`
     pub async fn new(bind_addr: String, db_type: DatabaseType, db_addr: String) -> Server {
+        Server::with_options(bind_addr, db_type, db_addr, PipeOptions::default()).await
+    }
+
+    /// Same as `new`, but every connection's pipes are created with `pipe_options`
+    pub async fn with_options(
+        bind_addr: String,
+        db_type: DatabaseType,
+        db_addr: String,
+        pipe_options: PipeOptions,
+    ) -> Server {
         Server {
             db_type,
             db_addr,
-            listener: TcpListener::bind(bind_addr)
+            listeners: vec![Listener::bind(&bind_addr)
                 .await
-                .expect("Unable to bind to bind_addr"),
-            kill_switches: Vec::new(),
+                .expect("Unable to bind to bind_addr")],
+            kill_switches: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            verbose: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            verbose_clients: None,
+            pipe_options,
+            next_connection_id: 0,
+            tls_acceptor: None,
+            backend_tls: None,
+            router: None,
+            max_connections: None,
+            stats: Arc::new(ServerStats::new()),
+            tcp_options: TcpOptions::default(),
+            connect_options: ConnectOptions::default(),
+            proxy_protocol: None,
+            db_type_detection: None,
         }
     }
 
-    async fn create_pipes<T: PacketHandler + Send + Sync + 'static>(
+    /// Serve both databases on one address, telling TCP clients apart by their first bytes,
+    /// see `DatabaseType::detect`. PostgresSQL clients speak first, so a client that has sent
+    /// nothing after `wait` is taken for MariaDB, which delays every MariaDB connection by
+    /// `wait`. Clients that can't be told apart, including all Unix socket clients, are taken
+    /// for the `db_type` the server was created with. The server's `db_addr` is unused
+    pub fn with_db_type_detection(
+        mut self,
+        mariadb_addr: String,
+        postgres_addr: String,
+        wait: Duration,
+    ) -> Server {
+        self.db_type_detection = Some(DbTypeDetection {
+            mariadb_addr,
+            postgres_addr,
+            wait,
+        });
+        self
+    }
+
+    /// Start every database connection with a PROXY protocol header carrying the client's
+    /// address, for databases configured to expect one. `None` (the default) sends none
+    pub fn with_proxy_protocol(mut self, proxy_protocol: Option<ProxyProtocolVersion>) -> Server {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// Retry connecting to the database following `retry_policy`. If every attempt fails,
+    /// the client is sent an error before its connection is closed
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Server {
+        self.connect_options.retry_policy = retry_policy;
+        self
+    }
+
+    /// Gives up on an attempt to connect to the database after `connect_timeout` (default 5s),
+    /// instead of the OS default, which can be minutes for a firewalled host.
+    /// A timed out attempt is retried like any other failure, see `with_retry_policy`
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Server {
+        self.connect_options.timeout = connect_timeout;
+        self
+    }
+
+    /// Once connecting to a database fails `policy.failure_threshold` times in a row,
+    /// new clients of that database get an error right away, instead of waiting on more
+    /// attempts, until `policy.cooldown` has passed. Then the next client's connection
+    /// decides whether the database is back. Each address has its own breaker.
+    /// `None` (the default) always tries
+    pub fn with_circuit_breaker(mut self, policy: Option<CircuitBreakerPolicy>) -> Server {
+        self.connect_options.circuit_breaker = policy.map(|p| Arc::new(CircuitBreaker::new(p)));
+        self
+    }
+
+    /// Databases to connect to, in order, when `db_addr` can't be reached, e.g. a standby.
+    /// Each is tried following the retry policy, and skipped while its circuit breaker is open,
+    /// see `with_circuit_breaker`. Only new connections fail over: a connection whose database
+    /// goes down is closed, not moved to another one. Databases chosen by a router, or by
+    /// `with_db_type_detection`, have no failover
+    pub fn with_failover(mut self, failover: Vec<String>) -> Server {
+        self.connect_options.failover = failover;
+        self
+    }
+
+    /// Sets TCP_NODELAY on TCP client and database connections (default on)
+    pub fn with_tcp_nodelay(mut self, nodelay: bool) -> Server {
+        self.tcp_options.nodelay = nodelay;
+        self
+    }
+
+    /// Enables SO_KEEPALIVE on TCP client and database connections, sending the first probe
+    /// once a connection has been idle for `keepalive`. `None` (the default) disables it
+    pub fn with_tcp_keepalive(mut self, keepalive: Option<Duration>) -> Server {
+        self.tcp_options.keepalive = keepalive;
+        self
+    }
+
+    /// Once `max_connections` connections are open, wait for one to close
+    /// before accepting another
+    pub fn with_max_connections(mut self, max_connections: usize) -> Server {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Closes connection `connection_id`, the id handlers see in `PacketContext`.
+    /// Returns false if no such connection is open
+    pub fn kill_connection(&self, connection_id: u64) -> bool {
+        self.connection_killer().kill_connection(connection_id)
+    }
+
+    /// A handle to kill connections while `run` is in progress
+    pub fn connection_killer(&self) -> ConnectionKiller {
+        ConnectionKiller {
+            kill_switches: self.kill_switches.clone(),
+        }
+    }
+
+    /// Connections from clients `matcher` accepts start out verbose, see `Verbose`.
+    /// It is given the client's address, e.g. `10.0.0.7:51234`, or the peer of a Unix socket
+    pub fn with_verbose_clients<F>(mut self, matcher: F) -> Server
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.verbose_clients = Some(Arc::new(matcher));
+        self
+    }
+
+    /// Makes the pipes of connection `connection_id` log in detail, or stop doing so.
+    /// Returns false if no such connection is open
+    pub fn set_verbose(&self, connection_id: u64, verbose: bool) -> bool {
+        self.connection_logging()
+            .set_verbose(connection_id, verbose)
+    }
+
+    /// A handle to turn verbose logging on and off while `run` is in progress
+    pub fn connection_logging(&self) -> ConnectionLogging {
+        ConnectionLogging {
+            verbose: self.verbose.clone(),
+        }
+    }
+
+    /// Number of connections whose pipes are still running
+    pub fn active_connections(&self) -> usize {
+        self.stats.connections_active()
+    }
+
+    /// See `ServerStats::connections_accepted`
+    pub fn connections_accepted(&self) -> u64 {
+        self.stats.connections_accepted()
+    }
+
+    /// See `ServerStats::connections_closed`
+    pub fn connections_closed(&self) -> u64 {
+        self.stats.connections_closed()
+    }
+
+    /// Shared handle to the server's counters, for polling while `run` is in progress
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.stats.clone()
+    }
+
+    /// The address of the listener bound by `new`
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// The addresses of every TCP listener, in the order they were bound
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|l| l.local_addr().ok())
+            .collect()
+    }
+
+    /// Also accept connections on `bind_addr`, e.g. `[::1]:3306` next to `127.0.0.1:3306`,
+    /// or another port. Connections from every listener are handled alike
+    pub async fn with_listen_addr(mut self, bind_addr: String) -> Result<Server> {
+        self.listeners.push(Listener::bind(&bind_addr).await?);
+        Ok(self)
+    }
+
+    /// Let `router` choose the database of each connection, instead of `db_addr`.
+    /// Only PostgresSQL connections can be routed, see `BackendRouter::select`
+    pub fn with_router<R: BackendRouter + 'static>(mut self, router: R) -> Server {
+        if self.db_type == DatabaseType::MariaDB {
+            warn!("Server.with_router: MariaDB connections are never routed, using db_addr");
+        }
+        self.router = Some(Arc::new(router));
+        self
+    }
+
+    /// Offer TLS to clients using the PEM encoded certificate chain and private key.
+    /// The connection to the database stays plaintext.
+    /// Clients that don't ask for TLS are still accepted
+    pub fn with_tls(mut self, cert_path: &str, key_path: &str) -> Result<Server> {
+        self.tls_acceptor = Some(tls::load_acceptor(cert_path, key_path)?);
+        Ok(self)
+    }
+
+    /// Connect to the database over TLS, whether or not clients use TLS with the proxy.
+    /// Only PostgresSQL databases are supported. A client asking the handler to pass its own
+    /// TLS through to such a database breaks the connection, so don't allow it
+    pub fn with_backend_tls(mut self, config: BackendTlsConfig) -> Result<Server> {
+        if self.db_type == DatabaseType::MariaDB {
+            warn!(
+                "Server.with_backend_tls: MariaDB databases are always connected to in plaintext"
+            );
+        }
+        self.backend_tls = Some(Arc::new(BackendTls {
+            connector: tls::load_connector(&config)?,
+            config,
+        }));
+        Ok(self)
+    }
+
+    /// Negotiates TLS with the client, if enabled, and connects to the database
+    /// chosen by the router, or `db_addr`, over TLS if enabled
+    #[allow(clippy::too_many_arguments)]
+    async fn open_connection(
         db_addr: String,
         db_type: DatabaseType,
-        mut client_socket: TcpStream,
-        handler_ref: Arc<Mutex<T>>,
-        kill_switch_receiver: oneshot::Receiver<()>,
+        pipe_options: &PipeOptions,
+        (tls_acceptor, backend_tls): (Option<&TlsAcceptor>, Option<&BackendTls>),
+        router: Option<&dyn BackendRouter>,
+        tcp_options: TcpOptions,
+        connect_options: &ConnectOptions,
+        proxy_header: Option<&[u8]>,
+        mut client_socket: Stream,
+    ) -> Result<(ClientReader, ClientWriter, ServerReader, ServerWriter)> {
+        if db_type == DatabaseType::MariaDB {
+            // The database speaks first, so connect before anything else
+            let connection = Server::connect_with_failover(&db_addr, tcp_options, connect_options);
+            let mut server_socket = match connection.await {
+                Ok((socket, _db_addr)) => socket,
+                Err(e) => {
+                    Server::send_connect_error(db_type, &mut client_socket, &e).await;
+                    return Err(e);
+                }
+            };
+            let (client_reader, client_writer): (ClientReader, ClientWriter) = match tls_acceptor {
+                Some(acceptor) => {
+                    // The database waits for the PROXY header before its handshake,
+                    // which has to be relayed here, before the pipes start
+                    if let Some(header) = proxy_header {
+                        server_socket.write_all(header).await?;
+                    }
+                    tls::accept_mariadb(acceptor, client_socket, &mut server_socket).await?
+                }
+                None => {
+                    let (reader, writer) = split(client_socket);
+                    (Box::new(reader), Box::new(writer))
+                }
+            };
+            let (server_reader, server_writer) = split(server_socket);
+            return Ok((
+                client_reader,
+                client_writer,
+                Box::new(server_reader),
+                Box::new(server_writer),
+            ));
+        }
+
+        let (mut client_reader, mut client_writer): (ClientReader, ClientWriter) =
+            match tls_acceptor {
+                Some(acceptor) => tls::accept_postgres(acceptor, client_socket).await?,
+                None => {
+                    let (reader, writer) = split(client_socket);
+                    (Box::new(reader), Box::new(writer))
+                }
+            };
+        let server_socket = match router {
+            Some(router) => {
+                let first_packet = router::read_postgres_first_packet(
+                    &mut client_reader,
+                    &mut client_writer,
+                    pipe_options,
+                )
+                .await?;
+                let addr = router.select(&first_packet).await;
+                debug!("Server.open_connection: Routing to {}", addr);
+                // Put the packet back, so the pipes and handler see it as usual
+                client_reader = Box::new(Cursor::new(first_packet.bytes).chain(client_reader));
+                let addr = addr.to_string();
+                let socket = Server::connect(&addr, tcp_options, connect_options).await;
+                socket.map(|socket| (socket, addr))
+            }
+            None => Server::connect_with_failover(&db_addr, tcp_options, connect_options).await,
+        };
+        let server = match (server_socket, backend_tls) {
+            (Ok((socket, db_addr)), Some(backend_tls)) => {
+                let server_name = backend_tls.config.server_name_for(&db_addr);
+                tls::connect_postgres(&backend_tls.connector, server_name, socket).await
+            }
+            (Ok((socket, _db_addr)), None) => {
+                let (reader, writer) = split(socket);
+                Ok((
+                    Box::new(reader) as ServerReader,
+                    Box::new(writer) as ServerWriter,
+                ))
+            }
+            (Err(e), _) => Err(e),
+        };
+        match server {
+            Ok((server_reader, server_writer)) => {
+                Ok((client_reader, client_writer, server_reader, server_writer))
+            }
+            Err(e) => {
+                Server::send_connect_error(db_type, &mut client_writer, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Connects to `db_addr`, or else to each failover database in turn.
+    /// Returns the address of the database connected to
+    async fn connect_with_failover(
+        db_addr: &str,
+        tcp_options: TcpOptions,
+        connect_options: &ConnectOptions,
+    ) -> Result<(Stream, String)> {
+        let mut addr = db_addr;
+        let mut result = Server::connect(addr, tcp_options, connect_options).await;
+        for next in connect_options.failover.iter() {
+            match &result {
+                Ok(_) => break,
+                Err(e) => warn!("Server.connect: {}, failing over to {}", e, next),
+            }
+            addr = next;
+            result = Server::connect(addr, tcp_options, connect_options).await;
+        }
+        result.map(|socket| (socket, addr.to_string()))
+    }
+
+    async fn connect(
+        db_addr: &str,
+        tcp_options: TcpOptions,
+        connect_options: &ConnectOptions,
+    ) -> Result<Stream> {
+        let breaker = match &connect_options.circuit_breaker {
+            Some(breaker) => breaker,
+            None => {
+                return Server::connect_with_retries(db_addr, tcp_options, connect_options).await
+            }
+        };
+        if !breaker.allow(db_addr, Instant::now()) {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!(
+                    "SQL database ({}) is failing, not connecting for now",
+                    db_addr
+                ),
+            ));
+        }
+        let result = Server::connect_with_retries(db_addr, tcp_options, connect_options).await;
+        match &result {
+            Ok(_) => breaker.record_success(db_addr),
+            Err(_) => breaker.record_failure(db_addr, Instant::now()),
+        }
+        result
+    }
+
+    async fn connect_with_retries(
+        db_addr: &str,
+        tcp_options: TcpOptions,
+        connect_options: &ConnectOptions,
+    ) -> Result<Stream> {
+        let retry_policy = connect_options.retry_policy;
+        let mut retry = 0;
+        loop {
+            let connection = timeout(connect_options.timeout, Stream::connect(db_addr))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("Timed out after {:?}", connect_options.timeout),
+                    ))
+                });
+            match connection {
+                Ok(socket) => {
+                    tcp_options.apply(&socket);
+                    return Ok(socket);
+                }
+                Err(e) if retry < retry_policy.max_retries => {
+                    let delay = retry_policy.delay(retry);
+                    warn!(
+                        "Server.connect: Connecting to SQL database ({}) failed: {}, retrying in {:?}",
+                        db_addr, e, delay
+                    );
+                    delay_for(delay).await;
+                    retry += 1;
+                }
+                Err(e) => {
+                    return Err(Error::other(format!(
+                        "Connecting to SQL database ({}) failed: {}",
+                        db_addr, e
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Tells the client why its connection is about to close, in the database's own error format.
+    /// Failures are ignored, the connection is closed either way
+    async fn send_connect_error<W: AsyncWrite + Unpin + ?Sized>(
+        db_type: DatabaseType,
+        client_writer: &mut W,
+        e: &Error,
     ) {
-        let client_addr = match client_socket.peer_addr() {
-            Ok(addr) => addr.to_string(),
-            Err(_e) => String::from("Unknown"),
+        let packet = match db_type {
+            DatabaseType::MariaDB => {
+                // Sent in place of the handshake, before the client has said it speaks
+                // protocol 4.1, so without the SQL state
+                let mut payload = vec![0xff];
+                payload.extend_from_slice(&2003_u16.to_le_bytes()); // CR_CONN_HOST_ERROR
+                payload.extend_from_slice(e.to_string().as_bytes());
+                Packet::mariadb(0, &payload)
+            }
+            // sqlclient_unable_to_establish_sqlconnection
+            DatabaseType::PostgresSQL => Packet::error_packet_postgres(*b"08001", e.to_string()),
         };
+        let _ = client_writer.write_all(&packet.bytes).await;
+        let _ = client_writer.flush().await;
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn create_pipes(
+        connection_id: u64,
+        db_addr: String,
+        db_type: DatabaseType,
+        pipe_options: PipeOptions,
+        (tls_acceptor, backend_tls): (Option<TlsAcceptor>, Option<Arc<BackendTls>>),
+        router: Option<Arc<dyn BackendRouter>>,
+        (tcp_options, connect_options): (TcpOptions, ConnectOptions),
+        (proxy_protocol, db_type_detection): (
+            Option<ProxyProtocolVersion>,
+            Option<DbTypeDetection>,
+        ),
+        (mut client_socket, client_addr): (Stream, String),
+        (handler_ref, verbose): (Arc<Mutex<dyn PacketHandler + Send>>, Arc<Verbose>),
+        kill_switch_receivers: (oneshot::Receiver<()>, oneshot::Receiver<()>),
+        connection_guard: ConnectionGuard,
+    ) {
         tokio::spawn(async move {
+            let _connection_guard = connection_guard;
             debug!(
-                "Server.create_pipes: Spawning new task to manage connection from {}",
-                client_addr
+                "Server.create_pipes: Spawning new task to manage connection #{} from {}",
+                connection_id, client_addr
             );
-            // Create new connections to the server for each client socket
-            let mut server_socket = TcpStream::connect(db_addr.clone())
-                .await
-                .unwrap_or_else(|_| panic!("Connecting to SQL database ({}) failed", db_addr));
-            let (server_reader, server_writer) = server_socket.split();
-            let (client_reader, client_writer) = client_socket.split();
-            let mut forward_pipe = Pipe::new(
+            let (db_type, db_addr, connect_options) = match &db_type_detection {
+                Some(detection) => {
+                    let (db_type, db_addr) = detection.detect(&mut client_socket, db_type).await;
+                    // The failover databases are for db_addr's database type
+                    let connect_options = ConnectOptions {
+                        failover: Vec::new(),
+                        ..connect_options
+                    };
+                    (db_type, db_addr, connect_options)
+                }
+                None => (db_type, db_addr, connect_options),
+            };
+            let proxy_header = proxy_protocol.map(|version| {
+                let addrs = client_socket
+                    .as_tcp()
+                    .and_then(|s| Some((s.peer_addr().ok()?, s.local_addr().ok()?)));
+                proxy_protocol::header(version, addrs)
+            });
+            // tls::accept_mariadb relays the handshake before the pipes start
+            let relays_handshake = db_type == DatabaseType::MariaDB && tls_acceptor.is_some();
+            let connection = Server::open_connection(
+                db_addr,
+                db_type,
+                &pipe_options,
+                (tls_acceptor.as_ref(), backend_tls.as_deref()),
+                router.as_deref(),
+                tcp_options,
+                &connect_options,
+                proxy_header.as_deref().filter(|_| relays_handshake),
+                client_socket,
+            )
+            .await;
+            let (client_reader, client_writer, server_reader, server_writer) = match connection {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!(
+                        "Server.create_pipes: Unable to set up connection #{}: {}",
+                        connection_id, e
+                    );
+                    return;
+                }
+            };
+            let ssl_state = Arc::new(SslState::new());
+            let session = Arc::new(SessionTracker::new());
+            let query_timer = Arc::new(QueryTimer::new());
+            let requests = Arc::new(RequestTracker::new());
+            let backpressure = Arc::new(Backpressure::new());
+            let activity = Arc::new(Activity::new());
+            if relays_handshake {
+                session.skip_handshake();
+            }
+            let mut forward_pipe = PipeBuilder::new(
                 client_addr.clone(),
                 db_type,
                 handler_ref.clone(),
                 Direction::Forward,
                 client_reader,
                 server_writer,
-            );
-            let mut backward_pipe = Pipe::new(
+            )
+            .with_options(pipe_options.clone())
+            .with_ssl_state(ssl_state.clone())
+            .with_connection_id(connection_id)
+            .with_session(session.clone())
+            .with_query_timer(query_timer.clone())
+            .with_request_tracker(requests.clone())
+            .with_backpressure(backpressure.clone())
+            .with_activity(activity.clone())
+            .with_verbose(verbose.clone());
+            if let Some(header) = proxy_header.filter(|_| !relays_handshake) {
+                forward_pipe = forward_pipe.with_proxy_header(header);
+            }
+            let mut forward_pipe = forward_pipe.build();
+            let mut backward_pipe = PipeBuilder::new(
                 client_addr.clone(),
                 db_type,
                 handler_ref.clone(),
                 Direction::Backward,
                 server_reader,
                 client_writer,
-            );
+            )
+            .with_options(pipe_options)
+            .with_ssl_state(ssl_state)
+            .with_connection_id(connection_id)
+            .with_session(session)
+            .with_query_timer(query_timer)
+            .with_request_tracker(requests)
+            .with_backpressure(backpressure)
+            .with_activity(activity)
+            .with_verbose(verbose)
+            .build();
 
             // Create channels to short-circuit at the proxy
             // - tx: use to send directly to other's sink
@@ -79,72 +996,737 @@ impl Server {
             let (fb_tx, fb_rx) = mpsc::channel::<Packet>(128);
             let (bf_tx, bf_rx) = mpsc::channel::<Packet>(128);
             trace!("Server.create_pipes: starting forward/backwards pipes");
-            // select! will continuously run all futures until one returns
-            // - pipes are infinite loops, and never expect to exit unless error
-            // - any return will close this connection
-            select! {
-                _ = forward_pipe.run(fb_tx, bf_rx).fuse() => {
-                    trace!("Pipe closed via forward pipe");
-                },
-                _ = backward_pipe.run(bf_tx, fb_rx).fuse() => {
-                    trace!("Pipe closed via backward pipe");
-                },
-                _ = kill_switch_receiver.fuse() => {
-                    trace!("Pipe closed via kill switch");
+            // Run both pipes to completion
+            // - pipes are infinite loops, and never expect to exit unless error or kill switch
+            // - when one pipe returns, it drops its channel sender, which closes the other pipe
+            // - when one pipe fails, e.g. because its handler panicked, the other is dropped,
+            //   closing the connection in both directions
+            let (forward_kill_switch, backward_kill_switch) = kill_switch_receivers;
+            let (forward_result, backward_result) = {
+                let forward = forward_pipe.run(fb_tx, bf_rx, forward_kill_switch).fuse();
+                let backward = backward_pipe.run(bf_tx, fb_rx, backward_kill_switch).fuse();
+                pin_mut!(forward, backward);
+                select! {
+                    forward_result = forward => match forward_result {
+                        Ok(reason) => (Ok(reason), backward.await),
+                        Err(reason) => (Err(reason), Ok(CloseReason::PeerClosed)),
+                    },
+                    backward_result = backward => match backward_result {
+                        Ok(reason) => (forward.await, Ok(reason)),
+                        Err(reason) => (Ok(CloseReason::PeerClosed), Err(reason)),
+                    },
                 }
+                // A dropped pipe may have been holding the handler's lock, it's released here
+            };
+            trace!(
+                "Pipes closed: forward={:?}, backward={:?}",
+                forward_result,
+                backward_result
+            );
+            match (forward_result, backward_result) {
+                (Err(reason), _) | (_, Err(reason)) => info!(
+                    "Closing connection #{} from {}: {}",
+                    connection_id, client_addr, reason
+                ),
+                (Ok(reason), _) => debug!(
+                    "Closing connection #{} from {}: {}",
+                    connection_id, client_addr, reason
+                ),
             }
-            debug!("Closing connection from {:?}", client_socket.peer_addr());
+            handler_ref
+                .lock()
+                .await
+                .connection_closed(connection_id)
+                .await;
+            // _connection_guard is dropped here, letting Server.run() know we're done
         });
     }
 
+    fn kill_pipes(kill_switches: &KillSwitches) {
+        info!("Server.run(): Received a kill switch at the server");
+        // Kill all pipes
+        let mut i = 0;
+        for (_id, (forward, backward)) in kill_switches.lock().unwrap().drain() {
+            let _ = forward.send(());
+            let _ = backward.send(());
+            i += 2;
+        }
+        debug!("Server.run(): killed {} pipes", i);
+    }
+
+    /// Accepts connections until `kill_switch_receiver` fires.
+    /// Then no new connections are accepted, every open connection's pipes are signalled
+    /// to flush and close, and this returns once all of them have finished.
+    /// Every connection shares `packet_handler`, see `run_with_factory` for one per connection
     pub async fn run<T: PacketHandler + Send + Sync + 'static>(
         &mut self,
         packet_handler: T,
         kill_switch_receiver: oneshot::Receiver<()>,
     ) {
+        let packet_handler: Arc<Mutex<dyn PacketHandler + Send>> =
+            Arc::new(Mutex::new(packet_handler));
+        self.serve(
+            |_id, _client_addr| packet_handler.clone(),
+            kill_switch_receiver,
+        )
+        .await;
+    }
+
+    /// Same as `run`, but each connection gets its own handler from `factory`,
+    /// so connections don't wait on each other for a shared handler's lock
+    pub async fn run_with_factory<F: HandlerFactory>(
+        &mut self,
+        factory: F,
+        kill_switch_receiver: oneshot::Receiver<()>,
+    ) {
+        self.serve(
+            |connection_id, client_addr| {
+                Arc::new(Mutex::new(factory.new_handler(connection_id, client_addr)))
+            },
+            kill_switch_receiver,
+        )
+        .await;
+    }
+
+    /// Accepts connections, handling each one with the handler `handler_for` returns for it
+    async fn serve<H>(&mut self, handler_for: H, kill_switch_receiver: oneshot::Receiver<()>)
+    where
+        H: Fn(u64, &str) -> Arc<Mutex<dyn PacketHandler + Send>>,
+    {
         trace!("Server.run(): enter");
         let db_addr = self.db_addr.clone();
         let db_type = self.db_type;
-        let packet_handler = Arc::new(Mutex::new(packet_handler));
-        let mut incoming = self.listener.incoming().fuse();
+        let pipe_options = self.pipe_options.clone();
+        let tls_acceptor = self.tls_acceptor.clone();
+        let backend_tls = self.backend_tls.clone();
+        let router = self.router.clone();
+        let tcp_options = self.tcp_options;
+        let connect_options = self.connect_options.clone();
+        let proxy_protocol = self.proxy_protocol;
+        let db_type_detection = self.db_type_detection.clone();
         let mut kill_switch_receiver = kill_switch_receiver.fuse();
+        // Every connection task holds a clone of connection_guard,
+        // so connection_drain completes once all of them have exited
+        let (connection_guard, mut connection_drain) = mpsc::channel::<()>(1);
+        let connection_limit = self.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        // Accept errors in a row, see `accept_backoff`
+        let mut accept_errors = 0;
         loop {
             //while let Some(conn) = incoming.next().await {
             trace!("Server.run(): loop starts");
+            // Wait for a free slot before accepting
+            let mut permit = None;
+            if let Some(limit) = &connection_limit {
+                select! {
+                    p = limit.clone().acquire_owned().fuse() => permit = Some(p),
+                    _ = kill_switch_receiver => {
+                        Server::kill_pipes(&self.kill_switches);
+                        break;
+                    },
+                }
+            }
+            // Accept from whichever listener has a connection first
+            let accepts = self.listeners.iter_mut().map(|l| Box::pin(l.accept()));
+            let accept = futures::future::select_all(accepts).map(|(conn, _i, _rest)| conn);
             select! {
-                some_conn = incoming.next() => {
+                conn = accept.fuse() => {
                     trace!("Server.run(): new incoming connection");
-                    if let Some(conn) = some_conn {
-                        match conn {
-                            Ok(mut client_socket) => {
-                                trace!("Server.run(): got the client_socket");
-                                let (tx, rx) = oneshot::channel();
-                                self.kill_switches.push(tx);
-                                Server::create_pipes(db_addr.clone(), db_type, client_socket, packet_handler.clone(), rx).await;
-                            },
-                            Err(err) => {
-                                // Handle error by printing to STDOUT.
-                                error!("Server.run() accept error = {:?}", err);
-                            },
-                        };
-                    } else {
-                        debug!("Server.run() accept completed. no more incoming connections");
-                        break;
-                    }
+                    match conn {
+                        Ok((client_socket, client_addr)) => {
+                            trace!("Server.run(): got the client_socket");
+                            accept_errors = 0;
+                            tcp_options.apply(&client_socket);
+                            let (forward_tx, forward_rx) = oneshot::channel();
+                            let (backward_tx, backward_rx) = oneshot::channel();
+                            let connection_id = self.next_connection_id;
+                            self.next_connection_id += 1;
+                            self.kill_switches.lock().unwrap().insert(connection_id, (forward_tx, backward_tx));
+                            let verbose = Arc::new(Verbose::new());
+                            if self.verbose_clients.as_ref().is_some_and(|matcher| matcher(&client_addr)) {
+                                verbose.set(true);
+                            }
+                            self.verbose.lock().unwrap().insert(connection_id, verbose.clone());
+                            self.stats.connections_accepted.fetch_add(1, Ordering::SeqCst);
+                            self.stats.connections_active.fetch_add(1, Ordering::SeqCst);
+                            info!("Server.run(): accepted connection #{} from {}", connection_id, client_addr);
+                            let guard = ConnectionGuard {
+                                connection_id,
+                                _drain: connection_guard.clone(),
+                                _permit: permit,
+                                stats: self.stats.clone(),
+                                kill_switches: self.kill_switches.clone(),
+                                verbose: self.verbose.clone(),
+                            };
+                            Server::create_pipes(connection_id, db_addr.clone(), db_type, pipe_options.clone(), (tls_acceptor.clone(), backend_tls.clone()), router.clone(), (tcp_options, connect_options.clone()), (proxy_protocol, db_type_detection.clone()), (client_socket, client_addr.clone()), (handler_for(connection_id, &client_addr), verbose), (forward_rx, backward_rx), guard).await;
+                        },
+                        Err(err) => {
+                            // Keep serving, the error may go away as connections close
+                            accept_errors += 1;
+                            match accept_backoff(&err, accept_errors) {
+                                Some(delay) => {
+                                    error!("Server.run() accept error = {:?}, backing off for {:?}", err, delay);
+                                    select! {
+                                        _ = delay_for(delay).fuse() => {},
+                                        _ = kill_switch_receiver => {
+                                            Server::kill_pipes(&self.kill_switches);
+                                            break;
+                                        },
+                                    }
+                                },
+                                None => warn!("Server.run() accept error = {:?}", err),
+                            }
+                        },
+                    };
                 },
                 _ = kill_switch_receiver => {
-                    info!("Server.run(): Received a kill switch at the server");
-                    // Kill all pipes
-                    let mut i = 0;
-                    while let Some(s) = self.kill_switches.pop() {
-                        let _ = s.send(());
-                        i += 1;
-                    }
-                    debug!("Server.run(): killed {} pipes", i);
+                    Server::kill_pipes(&self.kill_switches);
                     break;
                 },
             }
         } // end loop
+
+        // Wait for in-flight connections to finish
+        drop(connection_guard);
+        while connection_drain.next().await.is_some() {}
         info!("Server.run() complete");
     }
 }
+
+/// How long to wait before accepting again after `accept_errors` errors in a row, the last
+/// being `err`. Errors that only affect the connection being accepted, e.g. a client that
+/// reset before it was accepted, are retried at once. Others, e.g. running out of file
+/// descriptors (EMFILE), would fail again straight away, so the delay grows from 5ms to 1s
+fn accept_backoff(err: &Error, accept_errors: u32) -> Option<Duration> {
+    match err.kind() {
+        ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionReset
+        | ErrorKind::Interrupted
+        | ErrorKind::WouldBlock => None,
+        _ => {
+            let delay =
+                Duration::from_millis(5) * 2_u32.pow(accept_errors.saturating_sub(1).min(8));
+            Some(std::cmp::min(delay, Duration::from_secs(1)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::{HandlerAction, PacketContext, PassthroughHandler};
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    };
+
+    #[tokio::test]
+    async fn applies_tcp_options() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let _peer = listener.accept().await.unwrap();
+        let stream = Stream::Tcp(socket);
+        let socket = stream.as_tcp().unwrap();
+        TcpOptions::default().apply(&stream);
+        assert!(socket.nodelay().unwrap());
+        assert_eq!(socket.keepalive().unwrap(), None);
+        let options = TcpOptions {
+            nodelay: false,
+            keepalive: Some(Duration::from_secs(30)),
+        };
+        options.apply(&stream);
+        assert!(!socket.nodelay().unwrap());
+        assert_eq!(socket.keepalive().unwrap(), Some(Duration::from_secs(30)));
+    }
+
+    #[tokio::test]
+    async fn proxies_unix_sockets() {
+        let dir = std::env::temp_dir().join(format!("sql-proxy-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db.sock");
+        let proxy_path = dir.join("proxy.sock");
+        let _ = std::fs::remove_file(&db_path);
+        let _ = std::fs::remove_file(&proxy_path);
+        let mut backend = UnixListener::bind(&db_path).unwrap();
+        let mut server = Server::new(
+            format!("unix:{}", proxy_path.display()),
+            DatabaseType::MariaDB,
+            format!("unix:{}", db_path.display()),
+        )
+        .await;
+        assert!(server.local_addr().is_err());
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let proxy = tokio::spawn(async move {
+            server.run(PassthroughHandler {}, kill_rx).await;
+        });
+
+        let mut client = UnixStream::connect(&proxy_path).await.unwrap();
+        let (mut db, _) = backend.accept().await.unwrap();
+        let handshake = Packet::mariadb(0, b"\x0a10.4.12-MariaDB\0");
+        db.write_all(&handshake.bytes).await.unwrap();
+        let mut received = vec![0_u8; handshake.get_size()];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, handshake.bytes);
+
+        drop(client);
+        drop(db);
+        kill_tx.send(()).unwrap();
+        proxy.await.unwrap();
+        assert!(!proxy_path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retry_delay_backs_off() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: 0.0,
+        };
+        let delays: Vec<u64> = (0..5).map(|r| policy.delay(r).as_millis() as u64).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000]);
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
+
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay > Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn circuit_breaker_opens_and_half_opens() {
+        let breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 2,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        });
+        let db = "db:3306";
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        // Failures too far apart don't add up
+        breaker.record_failure(db, at(0));
+        breaker.record_failure(db, at(20));
+        assert!(breaker.allow(db, at(20)));
+        breaker.record_failure(db, at(25));
+        assert!(!breaker.allow(db, at(25)));
+        // Other databases are unaffected
+        assert!(breaker.allow("other:3306", at(25)));
+
+        // After the cooldown, one connection tests the database
+        assert!(breaker.allow(db, at(60)));
+        assert!(!breaker.allow(db, at(60)));
+        breaker.record_failure(db, at(61));
+        assert!(!breaker.allow(db, at(90)));
+        assert!(breaker.allow(db, at(95)));
+        breaker.record_success(db);
+        assert!(breaker.allow(db, at(95)));
+        assert!(breaker.allow(db, at(95)));
+    }
+
+    #[test]
+    fn accept_backs_off_on_repeated_errors() {
+        let reset = Error::new(ErrorKind::ConnectionAborted, "aborted");
+        assert_eq!(accept_backoff(&reset, 10), None);
+        // EMFILE
+        let emfile = Error::from_raw_os_error(24);
+        assert_eq!(accept_backoff(&emfile, 1), Some(Duration::from_millis(5)));
+        assert_eq!(accept_backoff(&emfile, 3), Some(Duration::from_millis(20)));
+        assert_eq!(accept_backoff(&emfile, 100), Some(Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn connect_gives_up_after_timeout() {
+        // Reserved for documentation, so nothing answers, or the network is unreachable
+        let options = ConnectOptions {
+            timeout: Duration::from_millis(50),
+            ..ConnectOptions::default()
+        };
+        let started = std::time::Instant::now();
+        let result = Server::connect("192.0.2.1:3306", TcpOptions::default(), &options).await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn unreachable_database_is_reported_to_client() {
+        // Nothing listens on a port that was just freed
+        let db_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let mut server = Server::new("127.0.0.1:0".to_string(), DatabaseType::MariaDB, db_addr)
+            .await
+            .with_retry_policy(RetryPolicy {
+                max_retries: 2,
+                base_delay: Duration::from_millis(1),
+                ..RetryPolicy::default()
+            });
+        let proxy_addr = server.local_addr().unwrap();
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let proxy = tokio::spawn(async move {
+            server.run(PassthroughHandler {}, kill_rx).await;
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received[3], 0); // sequence id
+        assert_eq!(received[4..7], [0xff, 0xd3, 0x07]);
+
+        kill_tx.send(()).unwrap();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_next_database() {
+        // Nothing listens on a port that was just freed
+        let primary = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let mut standby = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let standby_addr = standby.local_addr().unwrap().to_string();
+        let mut server = Server::new(
+            "127.0.0.1:0".to_string(),
+            DatabaseType::MariaDB,
+            primary.clone(),
+        )
+        .await
+        .with_failover(vec![standby_addr.clone()])
+        .with_circuit_breaker(Some(CircuitBreakerPolicy {
+            failure_threshold: 1,
+            ..CircuitBreakerPolicy::default()
+        }));
+        let proxy_addr = server.local_addr().unwrap();
+        let breaker = server.connect_options.circuit_breaker.clone().unwrap();
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let proxy = tokio::spawn(async move {
+            server.run(PassthroughHandler {}, kill_rx).await;
+        });
+
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let (mut db, _) = timeout(Duration::from_secs(5), standby.accept())
+                .await
+                .unwrap()
+                .unwrap();
+            let handshake = Packet::mariadb(0, b"\x0a10.4.12-MariaDB\0");
+            db.write_all(&handshake.bytes).await.unwrap();
+            let mut received = vec![0_u8; handshake.get_size()];
+            client.read_exact(&mut received).await.unwrap();
+            // The failed primary is skipped from now on
+            assert!(!breaker.allow(&primary, Instant::now()));
+        }
+
+        kill_tx.send(()).unwrap();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kills_a_single_connection() {
+        let mut backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let db_addr = backend.local_addr().unwrap().to_string();
+        let mut server =
+            Server::new("127.0.0.1:0".to_string(), DatabaseType::MariaDB, db_addr).await;
+        let proxy_addr = server.local_addr().unwrap();
+        let killer = server.connection_killer();
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let proxy = tokio::spawn(async move {
+            server.run(PassthroughHandler {}, kill_rx).await;
+        });
+
+        let mut first = TcpStream::connect(proxy_addr).await.unwrap();
+        let (_first_backend, _) = backend.accept().await.unwrap();
+        let mut second = TcpStream::connect(proxy_addr).await.unwrap();
+        let (mut second_backend, _) = backend.accept().await.unwrap();
+        assert!(killer.kill_connection(0));
+        assert!(!killer.kill_connection(0));
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(5), first.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The other connection is untouched
+        let handshake = Packet::mariadb(0, b"\x0a10.4.12-MariaDB\0");
+        second_backend.write_all(&handshake.bytes).await.unwrap();
+        let mut received = vec![0_u8; handshake.get_size()];
+        second.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, handshake.bytes);
+
+        drop(second);
+        drop(second_backend);
+        kill_tx.send(()).unwrap();
+        proxy.await.unwrap();
+        assert!(!killer.kill_connection(1));
+    }
+
+    #[tokio::test]
+    async fn accepts_on_every_listener() {
+        let mut backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let db_addr = backend.local_addr().unwrap().to_string();
+        let mut server = Server::new("127.0.0.1:0".to_string(), DatabaseType::MariaDB, db_addr)
+            .await
+            .with_listen_addr("127.0.0.1:0".to_string())
+            .await
+            .unwrap();
+        let proxy_addrs = server.local_addrs();
+        assert_eq!(proxy_addrs.len(), 2);
+        assert_eq!(proxy_addrs[0], server.local_addr().unwrap());
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let proxy = tokio::spawn(async move {
+            server.run(PassthroughHandler {}, kill_rx).await;
+        });
+
+        // Connect through the second listener first
+        for addr in proxy_addrs.iter().rev() {
+            let _client = TcpStream::connect(addr).await.unwrap();
+            let accepted = timeout(Duration::from_secs(5), backend.accept()).await;
+            assert!(accepted.is_ok());
+        }
+
+        kill_tx.send(()).unwrap();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn factory_creates_a_handler_per_connection() {
+        let mut backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let db_addr = backend.local_addr().unwrap().to_string();
+        let mut server =
+            Server::new("127.0.0.1:0".to_string(), DatabaseType::MariaDB, db_addr).await;
+        let proxy_addr = server.local_addr().unwrap();
+        let created = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let factory_created = created.clone();
+        let factory = move |connection_id: u64, _client_addr: &str| {
+            factory_created.lock().unwrap().push(connection_id);
+            Box::new(PassthroughHandler {}) as Box<dyn PacketHandler + Send>
+        };
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let proxy = tokio::spawn(async move {
+            server.run_with_factory(factory, kill_rx).await;
+        });
+
+        let _first = TcpStream::connect(proxy_addr).await.unwrap();
+        let (_first_backend, _) = backend.accept().await.unwrap();
+        let _second = TcpStream::connect(proxy_addr).await.unwrap();
+        let (_second_backend, _) = backend.accept().await.unwrap();
+        assert_eq!(*created.lock().unwrap(), vec![0, 1]);
+
+        kill_tx.send(()).unwrap();
+        proxy.await.unwrap();
+    }
+
+    /// Records the connections it's told have closed
+    struct CloseRecorder(Arc<std::sync::Mutex<Vec<u64>>>);
+
+    #[async_trait::async_trait]
+    impl PacketHandler for CloseRecorder {
+        async fn handle_request(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+
+        async fn handle_response(&mut self, _p: &Packet, _ctx: &PacketContext) -> HandlerAction {
+            HandlerAction::Forward
+        }
+
+        async fn connection_closed(&mut self, connection_id: u64) {
+            self.0.lock().unwrap().push(connection_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn tells_handler_connection_closed() {
+        let mut backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let db_addr = backend.local_addr().unwrap().to_string();
+        let mut server =
+            Server::new("127.0.0.1:0".to_string(), DatabaseType::MariaDB, db_addr).await;
+        let proxy_addr = server.local_addr().unwrap();
+        let closed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let handler = CloseRecorder(closed.clone());
+        let proxy = tokio::spawn(async move {
+            server.run(handler, kill_rx).await;
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let (backend_socket, _) = backend.accept().await.unwrap();
+        // The database hangs up without the client quitting
+        drop(backend_socket);
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+
+        kill_tx.send(()).unwrap();
+        proxy.await.unwrap();
+        assert_eq!(*closed.lock().unwrap(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn detects_client_db_type() {
+        let mut mariadb = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut postgres = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut server = Server::new(
+            "127.0.0.1:0".to_string(),
+            DatabaseType::MariaDB,
+            "unused".to_string(),
+        )
+        .await
+        .with_db_type_detection(
+            mariadb.local_addr().unwrap().to_string(),
+            postgres.local_addr().unwrap().to_string(),
+            Duration::from_millis(50),
+        );
+        let proxy_addr = server.local_addr().unwrap();
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let proxy = tokio::spawn(async move {
+            server.run(PassthroughHandler {}, kill_rx).await;
+        });
+
+        let mut postgres_client = TcpStream::connect(proxy_addr).await.unwrap();
+        let startup = [0, 0, 0, 9, 0, 3, 0, 0, 0];
+        postgres_client.write_all(&startup).await.unwrap();
+        let (mut db, _) = timeout(Duration::from_secs(5), postgres.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut received = [0_u8; 9];
+        db.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, startup);
+
+        let _mariadb_client = TcpStream::connect(proxy_addr).await.unwrap();
+        let accepted = timeout(Duration::from_secs(5), mariadb.accept()).await;
+        assert!(accepted.is_ok());
+
+        kill_tx.send(()).unwrap();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_connections() {
+        let mut backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let db_addr = backend.local_addr().unwrap().to_string();
+        let mut server =
+            Server::new("127.0.0.1:0".to_string(), DatabaseType::MariaDB, db_addr).await;
+        let proxy_addr = server.local_addr().unwrap();
+        let stats = server.stats();
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let proxy = tokio::spawn(async move {
+            server.run(PassthroughHandler {}, kill_rx).await;
+            server
+        });
+
+        let client = TcpStream::connect(proxy_addr).await.unwrap();
+        let (db, _) = backend.accept().await.unwrap();
+        assert_eq!(stats.connections_accepted(), 1);
+        assert_eq!(stats.connections_active(), 1);
+        assert_eq!(stats.connections_closed(), 0);
+
+        drop(client);
+        drop(db);
+        timeout(Duration::from_secs(5), async {
+            while stats.connections_closed() == 0 {
+                delay_for(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(stats.connections_active(), 0);
+
+        kill_tx.send(()).unwrap();
+        let server = proxy.await.unwrap();
+        assert_eq!(server.connections_accepted(), 1);
+        assert_eq!(server.connections_closed(), 1);
+    }
+
+    #[tokio::test]
+    async fn logs_matching_clients_verbosely() {
+        let mut backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let db_addr = backend.local_addr().unwrap().to_string();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = events.clone();
+        let options = PipeOptions {
+            log_sink: Some(Arc::new(move |event: crate::pipe::LogEvent| {
+                log.lock().unwrap().push(event);
+            })),
+            ..PipeOptions::default()
+        };
+        let mut server = Server::with_options(
+            "127.0.0.1:0".to_string(),
+            DatabaseType::MariaDB,
+            db_addr,
+            options,
+        )
+        .await
+        .with_verbose_clients(|client_addr| client_addr.starts_with("127.0.0.1:"));
+        let proxy_addr = server.local_addr().unwrap();
+        let logging = server.connection_logging();
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let proxy = tokio::spawn(async move {
+            server.run(PassthroughHandler {}, kill_rx).await;
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let (mut db, _) = backend.accept().await.unwrap();
+        let ping = Packet::mariadb(0, &[0x0e]);
+        client.write_all(&ping.bytes).await.unwrap();
+        let mut received = vec![0_u8; ping.get_size()];
+        db.read_exact(&mut received).await.unwrap();
+        assert!(events
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|e| e.event == "Processing packet" && e.level == log::Level::Info));
+        assert!(logging.set_verbose(0, false));
+        assert!(!logging.set_verbose(1, true));
+
+        drop(client);
+        drop(db);
+        kill_tx.send(()).unwrap();
+        proxy.await.unwrap();
+        assert!(!logging.set_verbose(0, true));
+    }
+
+    #[tokio::test]
+    async fn max_connections_blocks_extra_connections() {
+        let mut backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let db_addr = backend.local_addr().unwrap().to_string();
+        let mut server = Server::new("127.0.0.1:0".to_string(), DatabaseType::MariaDB, db_addr)
+            .await
+            .with_max_connections(1);
+        let proxy_addr = server.local_addr().unwrap();
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let proxy = tokio::spawn(async move {
+            server.run(PassthroughHandler {}, kill_rx).await;
+            server.active_connections()
+        });
+
+        let first = TcpStream::connect(proxy_addr).await.unwrap();
+        let (first_backend, _) = backend.accept().await.unwrap();
+        let second = TcpStream::connect(proxy_addr).await.unwrap();
+        // The second connection waits for a free slot
+        let waiting = timeout(Duration::from_millis(100), backend.accept()).await;
+        assert!(waiting.is_err());
+
+        drop(first);
+        drop(first_backend);
+        let accepted = timeout(Duration::from_secs(5), backend.accept()).await;
+        assert!(accepted.is_ok());
+
+        drop(second);
+        kill_tx.send(()).unwrap();
+        assert_eq!(proxy.await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,539 @@
+//! Session state accumulated by observing a connection's packets
+use byteorder::{ByteOrder, LittleEndian};
+use std::sync::Mutex;
+
+use crate::{
+    packet::{DatabaseType, Packet, PacketType},
+    packet_handler::Direction,
+    router::{first_keyword, skip_comments},
+};
+
+/// MariaDB capability flags used to parse the handshake response
+const CLIENT_CONNECT_WITH_DB: u32 = 0x0000_0008;
+pub(crate) const CLIENT_SECURE_CONNECTION: u32 = 0x0000_8000;
+pub(crate) const CLIENT_PLUGIN_AUTH: u32 = 0x0008_0000;
+const CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA: u32 = 0x0020_0000;
+/// MariaDB OK packet status flag
+const SERVER_STATUS_IN_TRANS: u16 = 0x0001;
+
+/// What the pipes know about a connection's session, see `PacketContext::session`.
+///
+/// For MariaDB:
+/// - `database` comes from the handshake response, COM_INIT_DB and `USE`,
+///   and is only updated once the database answers with an OK
+/// - `charset` is the collation id from the handshake response. `SET NAMES` is not tracked
+/// - `in_transaction` follows the status flags of OK packets
+/// - COM_CHANGE_USER, used by connection pools to reuse a connection, starts the session
+///   over: `database` and `charset` are reset to what it names, once the database answers
+///   with an OK. Before that, the database may renegotiate the auth plugin with an
+///   AuthSwitchRequest, and `SessionTracker::authenticated` is false until it is done
+///
+/// For PostgresSQL:
+/// - `database` comes from the StartupMessage (defaulting to the user name)
+/// - `charset` is never set
+/// - `in_transaction` follows ReadyForQuery, and is also true in a failed transaction,
+///   which `in_failed_transaction` tells apart
+///
+/// When the proxy terminates TLS for MariaDB, the handshake is never seen by the pipes,
+/// so `charset` and the initial `database` are unknown
+///
+/// Requests only count once the handler forwards them: one it drops or answers itself never
+/// reaches the database, and one it replaces counts as its replacements
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionState {
+    pub database: Option<String>,
+    pub charset: Option<u8>,
+    pub in_transaction: bool,
+    /// PostgresSQL only, the transaction failed and the database ignores queries until it ends
+    pub in_failed_transaction: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Tracked {
+    state: SessionState,
+    seen_request: bool,
+    authenticated: bool,
+    awaiting_response: bool,
+    pending_database: Option<String>,
+    client_quit: bool,
+    /// Whether the backward pipe has read the database's first MariaDB packet
+    handshake_seen: bool,
+    /// From the handshake response, COM_CHANGE_USER is parsed according to them
+    capabilities: Option<u32>,
+}
+
+/// The session state of a connection, shared by its forward and backward pipes
+#[derive(Debug, Default)]
+pub struct SessionTracker(Mutex<Tracked>);
+
+impl SessionTracker {
+    pub fn new() -> SessionTracker {
+        SessionTracker::default()
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.0.lock().unwrap().state.clone()
+    }
+
+    /// For when the MariaDB handshake and authentication happened before the pipes started
+    pub(crate) fn skip_handshake(&self) {
+        let mut tracked = self.0.lock().unwrap();
+        tracked.handshake_seen = true;
+        tracked.seen_request = true;
+        tracked.authenticated = true;
+    }
+
+    /// Whether the client has sent its MariaDB handshake response, or any first packet
+    pub(crate) fn seen_request(&self) -> bool {
+        self.0.lock().unwrap().seen_request
+    }
+
+    /// Whether the database has sent its MariaDB initial handshake, or any first packet in its
+    /// place, e.g. an ERR refusing the connection. Never true for PostgresSQL
+    pub fn handshake_seen(&self) -> bool {
+        self.0.lock().unwrap().handshake_seen
+    }
+
+    /// Whether the database has accepted the client: it answered the MariaDB handshake
+    /// response with an OK, or sent its first PostgresSQL ReadyForQuery
+    pub fn authenticated(&self) -> bool {
+        self.0.lock().unwrap().authenticated
+    }
+
+    /// Whether the forward pipe has passed on a MariaDB COM_QUIT, after which the
+    /// database closing the connection is expected
+    pub fn client_quit(&self) -> bool {
+        self.0.lock().unwrap().client_quit
+    }
+
+    pub(crate) fn set_client_quit(&self) {
+        self.0.lock().unwrap().client_quit = true;
+    }
+
+    /// Updates the state from a packet read by a pipe, and returns the result
+    pub(crate) fn observe(&self, p: &Packet, direction: Direction) -> SessionState {
+        let mut tracked = self.0.lock().unwrap();
+        tracked.observe(p, direction);
+        tracked.state.clone()
+    }
+
+    /// What `observe` would return, without updating the state. For requests the handler
+    /// may still drop or answer itself, which never reach the database
+    pub(crate) fn preview(&self, p: &Packet, direction: Direction) -> SessionState {
+        let mut tracked = self.0.lock().unwrap().clone();
+        tracked.observe(p, direction);
+        tracked.state
+    }
+}
+
+impl Tracked {
+    fn observe(&mut self, p: &Packet, direction: Direction) {
+        match (p.get_db_type(), direction) {
+            (DatabaseType::MariaDB, Direction::Forward) => self.observe_mariadb_request(p),
+            (DatabaseType::MariaDB, Direction::Backward) => self.observe_mariadb_response(p),
+            (DatabaseType::PostgresSQL, Direction::Forward) => self.observe_postgres_request(p),
+            (DatabaseType::PostgresSQL, Direction::Backward) => self.observe_postgres_response(p),
+        }
+    }
+
+    fn observe_mariadb_request(&mut self, p: &Packet) {
+        self.awaiting_response = true;
+        if !self.seen_request {
+            // The first packet from the client is its handshake response
+            self.seen_request = true;
+            let response = parse_handshake_response(&p.bytes[..]);
+            self.state.charset = response.charset;
+            self.pending_database = response.database;
+            self.capabilities = response.capabilities;
+            return;
+        }
+        if !self.authenticated {
+            // e.g. an authentication switch response
+            return;
+        }
+        if let Ok(PacketType::ComChangeUser) = p.get_packet_type() {
+            // Modern clients, e.g. those whose handshake went by under TLS, have both
+            let capabilities = self
+                .capabilities
+                .unwrap_or(CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH);
+            let change = parse_change_user(&p.bytes[..], capabilities);
+            // Like a new connection, authenticated by the OK, or after an auth switch
+            self.authenticated = false;
+            self.state = SessionState {
+                charset: change.charset,
+                ..SessionState::default()
+            };
+            self.pending_database = change.database;
+            return;
+        }
+        self.pending_database = match p.get_packet_type() {
+            Ok(PacketType::ComInitDb) => p.get_init_db().ok(),
+            Ok(PacketType::ComQuery) => p.get_query().ok().and_then(|q| used_database(&q)),
+            _ => None,
+        };
+    }
+
+    fn observe_mariadb_response(&mut self, p: &Packet) {
+        self.handshake_seen = true;
+        // Only the first packet of a response can be an OK, later ones may be rows
+        if !self.awaiting_response {
+            return;
+        }
+        self.awaiting_response = false;
+        match p.get_response_type() {
+            Ok(PacketType::ComOk) => {
+                self.authenticated = true;
+                if let Some(database) = self.pending_database.take() {
+                    self.state.database = Some(database);
+                }
+                if let Some(status) = ok_status_flags(&p.bytes[..]) {
+                    self.state.in_transaction = status & SERVER_STATUS_IN_TRANS != 0;
+                }
+            }
+            Ok(PacketType::ComErr) => self.pending_database = None,
+            // An authentication switch, the OK comes later
+            _ if !self.authenticated => self.awaiting_response = true,
+            // A result set
+            _ => {}
+        }
+    }
+
+    fn observe_postgres_request(&mut self, p: &Packet) {
+        if let Ok(startup) = p.get_postgres_startup() {
+            let param = |name: &str| {
+                startup
+                    .parameters
+                    .iter()
+                    .find(|(k, _v)| k == name)
+                    .map(|(_k, v)| v.clone())
+            };
+            self.state.database = param("database").or_else(|| param("user"));
+        }
+    }
+
+    fn observe_postgres_response(&mut self, p: &Packet) {
+        if let Ok(PacketType::ReadyForQuery) = p.get_packet_type() {
+            self.authenticated = true;
+            if let Some(status) = p.bytes.get(5) {
+                self.state.in_transaction = *status == b'T' || *status == b'E';
+                self.state.in_failed_transaction = *status == b'E';
+            }
+        }
+    }
+}
+
+/// Fields of a HandshakeResponse41, or of a COM_CHANGE_USER
+#[derive(Debug, Default)]
+pub(crate) struct HandshakeResponse {
+    /// Only in a HandshakeResponse41
+    pub capabilities: Option<u32>,
+    /// Collation id
+    pub charset: Option<u8>,
+    pub database: Option<String>,
+    pub auth_plugin: Option<String>,
+}
+
+/// Parses a HandshakeResponse41, as far as it is well formed
+/// https://mariadb.com/kb/en/connection/#client-handshake-response
+pub(crate) fn parse_handshake_response(bytes: &[u8]) -> HandshakeResponse {
+    let mut response = HandshakeResponse::default();
+    if bytes.len() < 4 + 32 {
+        return response;
+    }
+    let payload = &bytes[4..];
+    let capabilities = LittleEndian::read_u32(&payload[0..4]);
+    response.capabilities = Some(capabilities);
+    response.charset = Some(payload[8]);
+    // Skip the user name
+    let mut i = match payload[32..].iter().position(|b| *b == 0) {
+        Some(end) => 32 + end + 1,
+        None => return response,
+    };
+    // Skip the auth response
+    if capabilities & CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA != 0 {
+        match read_lenenc_int(&payload[i..]) {
+            Some((length, size)) => i += size + length as usize,
+            None => return response,
+        }
+    } else if capabilities & CLIENT_SECURE_CONNECTION != 0 {
+        match payload.get(i) {
+            Some(length) => i += 1 + *length as usize,
+            None => return response,
+        }
+    } else {
+        match payload[i..].iter().position(|b| *b == 0) {
+            Some(end) => i += end + 1,
+            None => return response,
+        }
+    }
+    if capabilities & CLIENT_CONNECT_WITH_DB != 0 {
+        response.database = read_null_terminated(payload, &mut i);
+    }
+    if capabilities & CLIENT_PLUGIN_AUTH != 0 {
+        response.auth_plugin = read_null_terminated(payload, &mut i);
+    }
+    response
+}
+
+/// Parses a COM_CHANGE_USER, as far as it is well formed. Unlike the handshake response,
+/// it doesn't repeat the client's capabilities
+/// https://mariadb.com/kb/en/com_change_user/
+pub(crate) fn parse_change_user(bytes: &[u8], capabilities: u32) -> HandshakeResponse {
+    let mut response = HandshakeResponse::default();
+    if bytes.len() < 5 {
+        return response;
+    }
+    let payload = &bytes[4..];
+    let mut i = 1;
+    // Skip the user name
+    if read_null_terminated(payload, &mut i).is_none() {
+        return response;
+    }
+    // Skip the auth response
+    if capabilities & CLIENT_SECURE_CONNECTION != 0 {
+        match payload.get(i) {
+            Some(length) => i += 1 + *length as usize,
+            None => return response,
+        }
+    } else if read_null_terminated(payload, &mut i).is_none() {
+        return response;
+    }
+    response.database = read_null_terminated(payload, &mut i).filter(|db| !db.is_empty());
+    // A 2-byte collation id, of which the session only tracks the first
+    if let Some(charset) = payload.get(i..(i + 2)) {
+        response.charset = Some(charset[0]);
+        i += 2;
+        if capabilities & CLIENT_PLUGIN_AUTH != 0 {
+            response.auth_plugin = read_null_terminated(payload, &mut i);
+        }
+    }
+    response
+}
+
+/// Reads a string at `i` up to a null byte or the end of `bytes`, and moves `i` past it
+pub(crate) fn read_null_terminated(bytes: &[u8], i: &mut usize) -> Option<String> {
+    let start = *i;
+    if start >= bytes.len() {
+        return None;
+    }
+    let end = bytes[start..]
+        .iter()
+        .position(|b| *b == 0)
+        .map_or(bytes.len(), |end| start + end);
+    *i = end + 1;
+    String::from_utf8(bytes[start..end].to_vec()).ok()
+}
+
+/// Status flags of an OK packet, after the affected rows and last insert id
+pub(crate) fn ok_status_flags(bytes: &[u8]) -> Option<u16> {
+    let mut i = 5;
+    for _ in 0..2 {
+        let (_value, size) = read_lenenc_int(bytes.get(i..)?)?;
+        i += size;
+    }
+    bytes.get(i..(i + 2)).map(LittleEndian::read_u16)
+}
+
+/// Returns a length-encoded integer and the number of bytes it took
+pub(crate) fn read_lenenc_int(bytes: &[u8]) -> Option<(u64, usize)> {
+    match *bytes.first()? {
+        b @ 0..=0xfa => Some((b as u64, 1)),
+        0xfc => bytes
+            .get(1..3)
+            .map(|b| (LittleEndian::read_u16(b) as u64, 3)),
+        0xfd => bytes
+            .get(1..4)
+            .map(|b| (LittleEndian::read_u24(b) as u64, 4)),
+        0xfe => bytes.get(1..9).map(|b| (LittleEndian::read_u64(b), 9)),
+        _ => None,
+    }
+}
+
+/// The database a query switches to with `USE db`, the last one if it has several statements
+pub(crate) fn used_database(query: &str) -> Option<String> {
+    used_databases(query).pop()
+}
+
+/// The databases of every `USE db` statement in a query, e.g. `SELECT 1; USE a`,
+/// skipping comments, with or without backticks, as in ``USE`a` ``.
+/// Splitting on every ';', even inside string literals, errs on the side of finding one
+pub(crate) fn used_databases(query: &str) -> Vec<String> {
+    query
+        .split(';')
+        .filter_map(|statement| {
+            let statement = skip_comments(statement);
+            let keyword = first_keyword(statement);
+            if !keyword.eq_ignore_ascii_case("USE") {
+                return None;
+            }
+            let rest = skip_comments(&statement[keyword.len()..]);
+            let database = match rest.strip_prefix('`') {
+                Some(quoted) => quoted.split('`').next().unwrap_or(""),
+                None => rest.split_whitespace().next().unwrap_or(""),
+            };
+            if database.is_empty() {
+                None
+            } else {
+                Some(database.to_string())
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(status: u16) -> Packet {
+        let mut payload = vec![0x00, 0, 0];
+        payload.push(status as u8);
+        payload.push((status >> 8) as u8);
+        payload.extend_from_slice(&[0, 0]);
+        Packet::mariadb(1, &payload)
+    }
+
+    fn handshake_response(database: &[u8]) -> Packet {
+        let mut payload = vec![0_u8; 32];
+        LittleEndian::write_u32(
+            &mut payload[0..4],
+            CLIENT_SECURE_CONNECTION | CLIENT_CONNECT_WITH_DB,
+        );
+        payload[8] = 45; // utf8mb4_general_ci
+        payload.extend_from_slice(b"root\0");
+        payload.extend_from_slice(&[3, 1, 2, 3]); // auth response
+        payload.extend_from_slice(database);
+        payload.push(0);
+        Packet::mariadb(1, &payload)
+    }
+
+    #[test]
+    fn finds_used_databases() {
+        assert_eq!(used_database("use db1;"), Some("db1".to_string()));
+        assert_eq!(used_database("USE`db1`"), Some("db1".to_string()));
+        assert_eq!(
+            used_database("/* app */ -- note\n USE `my db`"),
+            Some("my db".to_string())
+        );
+        assert_eq!(used_databases("SELECT 1; USE a; use b"), vec!["a", "b"]);
+        assert_eq!(used_database("SELECT 'USE a'"), None);
+        assert_eq!(used_database("USER"), None);
+        assert_eq!(used_database("USE"), None);
+    }
+
+    #[test]
+    fn notices_mariadb_handshake() {
+        let session = SessionTracker::new();
+        assert!(!session.handshake_seen());
+        let handshake = Packet::mariadb(0, b"\x0a5.5.5-10.5.8-MariaDB\0");
+        session.observe(&handshake, Direction::Backward);
+        assert!(session.handshake_seen());
+
+        let relayed = SessionTracker::new();
+        relayed.skip_handshake();
+        assert!(relayed.handshake_seen());
+    }
+
+    #[test]
+    fn change_user_resets_mariadb_session() {
+        let session = SessionTracker::new();
+        session.observe(&handshake_response(b"testdb"), Direction::Forward);
+        session.observe(&ok(SERVER_STATUS_IN_TRANS), Direction::Backward);
+        assert!(session.state().in_transaction);
+
+        let mut change_user = b"\x11app\0".to_vec();
+        change_user.extend_from_slice(&[3, 1, 2, 3]); // auth response
+        change_user.extend_from_slice(b"appdb\0");
+        change_user.extend_from_slice(&[33, 0]); // utf8_general_ci
+        let change_user = Packet::mariadb(0, &change_user);
+        // Until it's on its way to the database, nothing changes
+        let preview = session.preview(&change_user, Direction::Forward);
+        assert_eq!(preview.charset, Some(33));
+        assert!(session.state().in_transaction && session.authenticated());
+        session.observe(&change_user, Direction::Forward);
+        let state = session.state();
+        assert_eq!(state.database, None);
+        assert_eq!(state.charset, Some(33));
+        assert!(!state.in_transaction);
+        assert!(!session.authenticated());
+
+        // The database switches the auth plugin before accepting the new user
+        let auth_switch = Packet::mariadb(1, b"\xfemysql_native_password\0");
+        session.observe(&auth_switch, Direction::Backward);
+        session.observe(&Packet::mariadb(2, &[0; 20]), Direction::Forward);
+        assert_eq!(session.state().database, None);
+        session.observe(&ok(0), Direction::Backward);
+        assert!(session.authenticated());
+        assert_eq!(session.state().database, Some("appdb".to_string()));
+
+        let parsed = parse_change_user(
+            &Packet::mariadb(0, b"\x11app\0secret\0\0\x2d\0caching_sha2_password\0").bytes,
+            CLIENT_PLUGIN_AUTH,
+        );
+        assert_eq!(parsed.database, None);
+        assert_eq!(parsed.charset, Some(45));
+        assert_eq!(
+            parsed.auth_plugin,
+            Some("caching_sha2_password".to_string())
+        );
+    }
+
+    #[test]
+    fn tracks_mariadb_session() {
+        let session = SessionTracker::new();
+        session.observe(&handshake_response(b"testdb"), Direction::Forward);
+        // Not confirmed yet
+        assert_eq!(session.state().database, None);
+        let state = session.observe(&ok(0), Direction::Backward);
+        assert_eq!(state.database, Some("testdb".to_string()));
+        assert_eq!(state.charset, Some(45));
+
+        let use_db = Packet::mariadb(0, b"\x03USE `other`;");
+        session.observe(&use_db, Direction::Forward);
+        let err = Packet::error_packet_mariadb(1049, *b"42000", "Unknown database".to_string());
+        session.observe(&err, Direction::Backward);
+        assert_eq!(session.state().database, Some("testdb".to_string()));
+
+        // A result set whose row looks like an OK packet
+        session.observe(&Packet::mariadb(0, b"\x03SELECT ''"), Direction::Forward);
+        session.observe(&Packet::mariadb(1, &[1]), Direction::Backward);
+        session.observe(&Packet::mariadb(3, &[0, 0, 0, 1, 0]), Direction::Backward);
+        assert!(!session.state().in_transaction);
+
+        let init_db = Packet::mariadb(0, b"\x02other");
+        session.observe(&init_db, Direction::Forward);
+        session.observe(&ok(SERVER_STATUS_IN_TRANS), Direction::Backward);
+        let state = session.state();
+        assert_eq!(state.database, Some("other".to_string()));
+        assert!(state.in_transaction);
+    }
+
+    #[test]
+    fn ignores_mariadb_auth_switch() {
+        let session = SessionTracker::new();
+        session.observe(&handshake_response(b"testdb"), Direction::Forward);
+        let auth_switch =
+            Packet::mariadb(2, b"\xfemysql_native_password\x00abcdefghijklmnopqrst\x00");
+        session.observe(&auth_switch, Direction::Backward);
+        // Auth data that happens to start like a COM_INIT_DB
+        session.observe(&Packet::mariadb(3, b"\x02abc"), Direction::Forward);
+        let state = session.observe(&ok(0), Direction::Backward);
+        assert_eq!(state.database, Some("testdb".to_string()));
+    }
+
+    #[test]
+    fn tracks_postgres_session() {
+        let session = SessionTracker::new();
+        let startup = Packet::postgres_startup(&[("user", "root"), ("database", "testdb")]);
+        session.observe(&startup, Direction::Forward);
+        assert_eq!(session.state().database, Some("testdb".to_string()));
+        let ready = Packet::postgres(b'Z', b"T");
+        assert!(session.observe(&ready, Direction::Backward).in_transaction);
+        let ready = Packet::postgres(b'Z', b"E");
+        let state = session.observe(&ready, Direction::Backward);
+        assert!(state.in_transaction && state.in_failed_transaction);
+        let ready = Packet::postgres(b'Z', b"I");
+        let state = session.observe(&ready, Direction::Backward);
+        assert!(!state.in_transaction && !state.in_failed_transaction);
+    }
+}
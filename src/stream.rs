@@ -0,0 +1,143 @@
+//! TCP or Unix domain socket connections, for clients and databases.
+//! Addresses of the form `unix:/path/to/socket` are Unix sockets, anything else is TCP
+use std::{
+    io::Error,
+    mem::MaybeUninit,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, Result},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+/// Returns the socket path of a `unix:` address
+pub fn unix_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix("unix:")
+}
+
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub async fn connect(addr: &str) -> Result<Stream> {
+        match unix_path(addr) {
+            Some(path) => Ok(Stream::Unix(UnixStream::connect(path).await?)),
+            None => Ok(Stream::Tcp(TcpStream::connect(addr).await?)),
+        }
+    }
+
+    /// `None` for Unix sockets, which have no TCP options
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Stream::Tcp(s) => Some(s),
+            Stream::Unix(_s) => None,
+        }
+    }
+
+    pub fn as_tcp_mut(&mut self) -> Option<&mut TcpStream> {
+        match self {
+            Stream::Tcp(s) => Some(s),
+            Stream::Unix(_s) => None,
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [MaybeUninit<u8>]) -> bool {
+        match self {
+            Stream::Tcp(s) => s.prepare_uninitialized_buffer(buf),
+            Stream::Unix(s) => s.prepare_uninitialized_buffer(buf),
+        }
+    }
+
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, String),
+}
+
+impl Listener {
+    /// The socket file of a Unix listener must not exist yet
+    pub(crate) async fn bind(addr: &str) -> Result<Listener> {
+        match unix_path(addr) {
+            Some(path) => Ok(Listener::Unix(UnixListener::bind(path)?, addr.to_string())),
+            None => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    /// Returns the connection and a name for the client, used in logs.
+    /// Unix socket clients are unnamed, so they are named after the listener
+    pub(crate) async fn accept(&mut self) -> Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (socket, addr) = l.accept().await?;
+                Ok((Stream::Tcp(socket), addr.to_string()))
+            }
+            Listener::Unix(l, addr) => {
+                let (socket, _addr) = l.accept().await?;
+                Ok((Stream::Unix(socket), addr.clone()))
+            }
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Listener::Tcp(l) => l.local_addr(),
+            Listener::Unix(_l, addr) => Err(Error::other(format!(
+                "Listening on {}, which is not a TCP address",
+                addr
+            ))),
+        }
+    }
+}
+
+impl Drop for Listener {
+    /// Removes the socket file, so the address can be bound again
+    fn drop(&mut self) {
+        if let Listener::Unix(_l, addr) = self {
+            if let Some(path) = unix_path(addr) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
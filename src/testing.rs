@@ -0,0 +1,179 @@
+//! Helpers for testing handlers and pipes without a database.
+//! Built for the crate's own tests, and for others with the `test-util` feature
+use std::fmt;
+
+use crate::{
+    packet::{DatabaseType, Packet},
+    packet_handler::{Direction, HandlerAction, PacketContext, PacketHandler},
+    session::SessionState,
+};
+
+/// A context for calling a handler directly, as connection 0 with a fresh session.
+/// Tests needing more set the other fields, e.g. `PacketContext { connection_id: 7, ..ctx }`
+pub fn context(db_type: DatabaseType, direction: Direction) -> PacketContext {
+    PacketContext {
+        db_type,
+        direction,
+        pipe_name: "test".to_string(),
+        connection_id: 0,
+        session: SessionState::default(),
+        backpressure: false,
+        write_buf_len: 0,
+        handshake_seen: false,
+        handshake_response: false,
+    }
+}
+
+type Action = Box<dyn Fn(&Packet, &PacketContext) -> HandlerAction + Send>;
+
+/// Records every packet it is given, in order, and answers with its action, which forwards
+/// everything by default. To check what it saw after a pipe has run, keep a clone of the
+/// `Arc<Mutex<_>>` given to the pipe:
+/// ```ignore
+/// let recorder = Arc::new(Mutex::new(RecordingHandler::new()));
+/// // ... run pipes with recorder.clone() ...
+/// assert_eq!(recorder.lock().await.queries(), vec!["SELECT 1"]);
+/// ```
+pub struct RecordingHandler {
+    seen: Vec<(Direction, Packet)>,
+    action: Action,
+}
+
+impl RecordingHandler {
+    pub fn new() -> RecordingHandler {
+        RecordingHandler::with_action(|_p, _ctx| HandlerAction::Forward)
+    }
+
+    /// Answers every packet with `action`, e.g. to drop or rewrite some of them
+    pub fn with_action<F>(action: F) -> RecordingHandler
+    where
+        F: Fn(&Packet, &PacketContext) -> HandlerAction + Send + 'static,
+    {
+        RecordingHandler {
+            seen: Vec::new(),
+            action: Box::new(action),
+        }
+    }
+
+    /// Every packet, in the order the handler was given them
+    pub fn seen(&self) -> &[(Direction, Packet)] {
+        &self.seen
+    }
+
+    /// Packets given to `handle_request`
+    pub fn requests(&self) -> Vec<&Packet> {
+        self.packets(Direction::Forward)
+    }
+
+    /// Packets given to `handle_response`
+    pub fn responses(&self) -> Vec<&Packet> {
+        self.packets(Direction::Backward)
+    }
+
+    /// The SQL of the queries among the requests, see `Packet::get_query`
+    pub fn queries(&self) -> Vec<String> {
+        self.requests()
+            .into_iter()
+            .filter_map(|p| p.get_query().ok())
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.seen.clear();
+    }
+
+    fn packets(&self, direction: Direction) -> Vec<&Packet> {
+        self.seen
+            .iter()
+            .filter(|(d, _p)| *d == direction)
+            .map(|(_d, p)| p)
+            .collect()
+    }
+
+    fn record(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        self.seen.push((ctx.direction, p.clone()));
+        (self.action)(p, ctx)
+    }
+}
+
+impl Default for RecordingHandler {
+    fn default() -> RecordingHandler {
+        RecordingHandler::new()
+    }
+}
+
+impl fmt::Debug for RecordingHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RecordingHandler")
+            .field("seen", &self.seen)
+            .finish()
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketHandler for RecordingHandler {
+    async fn handle_request(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        self.record(p, ctx)
+    }
+
+    async fn handle_response(&mut self, p: &Packet, ctx: &PacketContext) -> HandlerAction {
+        self.record(p, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(sql: &str) -> Packet {
+        let mut bytes = vec![sql.len() as u8 + 1, 0, 0, 0, 0x03];
+        bytes.extend_from_slice(sql.as_bytes());
+        Packet::new(DatabaseType::MariaDB, bytes)
+    }
+
+    #[tokio::test]
+    async fn records_both_directions_in_order() {
+        let mut handler = RecordingHandler::new();
+        let ok = Packet::new(DatabaseType::MariaDB, vec![3, 0, 0, 1, 0, 0, 0]);
+        let forward = context(DatabaseType::MariaDB, Direction::Forward);
+        let backward = context(DatabaseType::MariaDB, Direction::Backward);
+
+        assert_eq!(
+            handler.handle_request(&query("SELECT 1"), &forward).await,
+            HandlerAction::Forward
+        );
+        handler.handle_response(&ok, &backward).await;
+        handler.handle_request(&query("SELECT 2"), &forward).await;
+
+        assert_eq!(handler.seen().len(), 3);
+        assert_eq!(handler.seen()[1], (Direction::Backward, ok.clone()));
+        assert_eq!(handler.responses(), vec![&ok]);
+        assert_eq!(handler.queries(), vec!["SELECT 1", "SELECT 2"]);
+
+        handler.clear();
+        assert!(handler.seen().is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_with_its_action() {
+        let mut handler = RecordingHandler::with_action(|p, _ctx| match p.get_query() {
+            Ok(ref sql) if sql.starts_with("DROP") => HandlerAction::Drop,
+            Ok(_) => HandlerAction::Replace(vec![query("SELECT 0")]),
+            Err(_) => HandlerAction::Forward,
+        });
+        let forward = context(DatabaseType::MariaDB, Direction::Forward);
+
+        assert_eq!(
+            handler
+                .handle_request(&query("DROP TABLE t"), &forward)
+                .await,
+            HandlerAction::Drop
+        );
+        assert_eq!(
+            handler.handle_request(&query("SELECT 1"), &forward).await,
+            HandlerAction::Replace(vec![query("SELECT 0")])
+        );
+        // Dropped and rewritten packets are recorded as they arrived
+        assert_eq!(handler.queries(), vec!["DROP TABLE t", "SELECT 1"]);
+    }
+}
@@ -0,0 +1,333 @@
+//! TLS termination for client connections.
+//! Clients negotiate TLS with the proxy, which talks plaintext to the database,
+//! unless the proxy is also told to connect to the database over TLS.
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::{
+    fs::File,
+    io::{BufReader, Cursor, Error, ErrorKind},
+    sync::Arc,
+};
+use tokio::io::{
+    split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, Result, WriteHalf,
+};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+        Certificate, ClientConfig, NoClientAuth, RootCertStore, ServerCertVerified,
+        ServerCertVerifier, ServerConfig, TLSError,
+    },
+    webpki::DNSNameRef,
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::packet::{DatabaseType, Packet, CLIENT_COMPRESS, CLIENT_SSL};
+
+/// Either half of a client connection, whether or not TLS was negotiated
+pub type ClientReader = Box<dyn AsyncRead + Send + Sync + Unpin>;
+pub type ClientWriter = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+/// Either half of a database connection, whether or not TLS was negotiated
+pub type ServerReader = Box<dyn AsyncRead + Send + Sync + Unpin>;
+pub type ServerWriter = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+/// How the proxy connects to the database over TLS, separately from how clients connect
+/// to the proxy. See `Server::with_backend_tls`
+#[derive(Clone, Debug, Default)]
+pub struct BackendTlsConfig {
+    /// PEM file of the CA certificates to trust. Nothing is trusted without one,
+    /// so either this or `danger_accept_invalid_certs` is needed
+    pub ca_path: Option<String>,
+    /// The name the database's certificate must be for, by default the host of its address
+    pub server_name: Option<String>,
+    /// Skip verifying the database's certificate, which leaves the connection open to
+    /// interception. Only for testing
+    pub danger_accept_invalid_certs: bool,
+}
+
+impl BackendTlsConfig {
+    /// The name to verify the certificate of the database at `db_addr` against
+    pub fn server_name_for<'a>(&'a self, db_addr: &'a str) -> &'a str {
+        if let Some(name) = &self.server_name {
+            return name;
+        }
+        // "host:port", or "[v6 address]:port"
+        let host = db_addr.rsplitn(2, ':').last().unwrap_or(db_addr);
+        host.trim_start_matches('[').trim_end_matches(']')
+    }
+}
+
+/// Accepts any certificate, see `BackendTlsConfig::danger_accept_invalid_certs`
+struct NoCertificateVerification {}
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> std::result::Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Builds a connector from a `BackendTlsConfig`
+pub fn load_connector(tls_config: &BackendTlsConfig) -> Result<TlsConnector> {
+    let mut config = ClientConfig::new();
+    if let Some(ca_path) = &tls_config.ca_path {
+        let (valid, _invalid) = config
+            .root_store
+            .add_pem_file(&mut BufReader::new(File::open(ca_path)?))
+            .map_err(|_e| Error::new(ErrorKind::InvalidInput, "Unable to parse certificates"))?;
+        if valid == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("No CA certificate found in {}", ca_path),
+            ));
+        }
+    }
+    if tls_config.danger_accept_invalid_certs {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerification {}));
+    }
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Asks the database for TLS with an SSLRequest, and upgrades once it agrees with an 'S'.
+/// A database that refuses is an error, rather than falling back to plaintext
+pub async fn connect_postgres<S>(
+    connector: &TlsConnector,
+    server_name: &str,
+    mut server: S,
+) -> Result<(ServerReader, ServerWriter)>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    let name = DNSNameRef::try_from_ascii_str(server_name).map_err(|_e| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid TLS server name: {}", server_name),
+        )
+    })?;
+    server
+        .write_all(&[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f])
+        .await?;
+    let mut answer = [0_u8; 1];
+    server.read_exact(&mut answer).await?;
+    if answer[0] != b'S' {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            "SQL database refused TLS",
+        ));
+    }
+    debug!("tls::connect_postgres: Database accepted SSLRequest, upgrading");
+    let stream = connector.connect(name, server).await?;
+    Ok(boxed(stream))
+}
+
+/// Builds an acceptor from PEM files. `key_path` may hold a PKCS#8 or RSA private key
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor> {
+    let cert_chain = certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_e| Error::new(ErrorKind::InvalidInput, "Unable to parse certificates"))?;
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_e| Error::new(ErrorKind::InvalidInput, "Unable to parse private key"))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|_e| Error::new(ErrorKind::InvalidInput, "Unable to parse private key"))?;
+    }
+    if keys.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("No private key found in {}", key_path),
+        ));
+    }
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, keys.remove(0))
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn boxed<C>(stream: C) -> (ClientReader, ClientWriter)
+where
+    C: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    let (reader, writer): (ReadHalf<C>, WriteHalf<C>) = split(stream);
+    (Box::new(reader), Box::new(writer))
+}
+
+/// The client asks for TLS with an SSLRequest, which we accept with an 'S'.
+/// Clients that don't ask for TLS are passed through in plaintext
+pub async fn accept_postgres<C>(
+    acceptor: &TlsAcceptor,
+    mut client: C,
+) -> Result<(ClientReader, ClientWriter)>
+where
+    C: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    let mut first = [0_u8; 8];
+    loop {
+        // Every message a client may start with is at least 8 bytes
+        client.read_exact(&mut first).await?;
+        let length = BigEndian::read_u32(&first[0..4]);
+        let code = BigEndian::read_u32(&first[4..8]);
+        match (length, code) {
+            (8, 80_877_103) => {
+                debug!("tls::accept_postgres: Got SSLRequest, accepting");
+                client.write_all(b"S").await?;
+                let stream = acceptor.accept(client).await?;
+                return Ok(boxed(stream));
+            }
+            (8, 80_877_104) => {
+                // Refuse GSSAPI encryption, the client may follow up with an SSLRequest
+                client.write_all(b"N").await?;
+            }
+            _ => {
+                debug!("tls::accept_postgres: Client did not request TLS");
+                let (reader, writer) = split(client);
+                let reader = Cursor::new(first.to_vec()).chain(reader);
+                return Ok((Box::new(reader), Box::new(writer)));
+            }
+        }
+    }
+}
+
+/// The database speaks first with its handshake, in which we advertise CLIENT_SSL,
+/// and hide CLIENT_COMPRESS, since the pipes can't follow compressed packets.
+/// This relays the handshake and authentication with the database,
+/// so those packets are never seen by the pipes.
+/// A client that wants TLS answers with a 32-byte SSLRequest (seq 1), upgrades,
+/// and then sends its handshake response (seq 2).
+/// The database never sees the SSLRequest, so we renumber the sequence ids of the
+/// authentication exchange until it completes with an OK or ERR
+pub async fn accept_mariadb<C, S>(
+    acceptor: &TlsAcceptor,
+    mut client: C,
+    server: &mut S,
+) -> Result<(ClientReader, ClientWriter)>
+where
+    C: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshake = read_mariadb_packet(server).await?;
+    let capabilities = handshake.get_handshake_capabilities()?;
+    handshake.set_handshake_capabilities((capabilities | CLIENT_SSL) & !CLIENT_COMPRESS)?;
+    client.write_all(&handshake.bytes).await?;
+
+    let response = read_mariadb_packet(&mut client).await?;
+    if !is_mariadb_ssl_request(&response) {
+        debug!("tls::accept_mariadb: Client did not request TLS");
+        server.write_all(&response.bytes).await?;
+        return Ok(boxed(client));
+    }
+    debug!("tls::accept_mariadb: Got SSLRequest, accepting");
+    let mut client = acceptor.accept(client).await?;
+
+    let mut response = read_mariadb_packet(&mut client).await?.bytes.to_vec();
+    if response.len() < 8 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Handshake response too short",
+        ));
+    }
+    // The connection to the database is plaintext
+    let capabilities = LittleEndian::read_u32(&response[4..8]) & !CLIENT_SSL;
+    LittleEndian::write_u32(&mut response[4..8], capabilities);
+    let response = Packet::new(DatabaseType::MariaDB, response);
+    relay_mariadb_packet(response, server, -1).await?;
+    loop {
+        let p = read_mariadb_packet(server).await?;
+        let first_byte = p.bytes.get(4).copied();
+        // caching_sha2_password fast auth success is immediately followed by an OK
+        let fast_auth = p.bytes.len() == 6 && p.bytes[4] == 0x01 && p.bytes[5] == 0x03;
+        relay_mariadb_packet(p, &mut client, 1).await?;
+        match first_byte {
+            Some(0x00) | Some(0xff) => break,
+            _ if fast_auth => continue,
+            _ => {
+                let p = read_mariadb_packet(&mut client).await?;
+                relay_mariadb_packet(p, server, -1).await?;
+            }
+        }
+    }
+    Ok(boxed(client))
+}
+
+async fn read_mariadb_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Packet> {
+    let mut header = [0_u8; 4];
+    reader.read_exact(&mut header).await?;
+    let length = LittleEndian::read_u24(&header[0..3]) as usize;
+    let mut bytes = vec![0_u8; 4 + length];
+    bytes[0..4].copy_from_slice(&header);
+    reader.read_exact(&mut bytes[4..]).await?;
+    Ok(Packet::new(DatabaseType::MariaDB, bytes))
+}
+
+async fn relay_mariadb_packet<W: AsyncWrite + Unpin>(
+    mut p: Packet,
+    writer: &mut W,
+    sequence_offset: i8,
+) -> Result<()> {
+    let sequence_id = p.get_sequence_id()?.wrapping_add(sequence_offset as u8);
+    p.set_sequence_id(sequence_id)?;
+    writer.write_all(&p.bytes).await
+}
+
+pub(crate) fn is_mariadb_ssl_request(p: &Packet) -> bool {
+    p.bytes.len() == 4 + 32 && LittleEndian::read_u32(&p.bytes[4..8]) & CLIENT_SSL != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn recognizes_mariadb_ssl_request() {
+        let mut payload = vec![0_u8; 32];
+        LittleEndian::write_u32(&mut payload[0..4], 0x000a_a285 | CLIENT_SSL);
+        assert!(is_mariadb_ssl_request(&Packet::mariadb(1, &payload)));
+        payload.extend_from_slice(b"root\0");
+        assert!(!is_mariadb_ssl_request(&Packet::mariadb(1, &payload)));
+    }
+
+    #[test]
+    fn backend_server_name_defaults_to_host() {
+        let config = BackendTlsConfig::default();
+        assert_eq!(config.server_name_for("db.internal:5432"), "db.internal");
+        assert_eq!(config.server_name_for("[::1]:5432"), "::1");
+        let config = BackendTlsConfig {
+            server_name: Some("db.example.com".to_string()),
+            ..BackendTlsConfig::default()
+        };
+        assert_eq!(config.server_name_for("10.0.0.1:5432"), "db.example.com");
+    }
+
+    #[tokio::test]
+    async fn postgres_database_refusing_tls_is_an_error() {
+        let connector = load_connector(&BackendTlsConfig {
+            danger_accept_invalid_certs: true,
+            ..BackendTlsConfig::default()
+        })
+        .unwrap();
+        let (server, mut db) = UnixStream::pair().unwrap();
+        db.write_all(b"N").await.unwrap();
+        let result = connect_postgres(&connector, "localhost", server).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::ConnectionRefused);
+        let mut ssl_request = [0_u8; 8];
+        db.read_exact(&mut ssl_request).await.unwrap();
+        assert_eq!(ssl_request, [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f]);
+    }
+
+    #[tokio::test]
+    async fn postgres_client_without_tls_is_passed_through() {
+        let acceptor = TlsAcceptor::from(Arc::new(ServerConfig::new(NoClientAuth::new())));
+        let (client, mut peer) = UnixStream::pair().unwrap();
+        let startup = [0, 0, 0, 9, 0, 3, 0, 0, 0];
+        peer.write_all(&startup).await.unwrap();
+        let (mut reader, _writer) = accept_postgres(&acceptor, client).await.unwrap();
+        let mut received = [0_u8; 9];
+        reader.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, startup);
+    }
+}
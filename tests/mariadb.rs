@@ -1,15 +1,13 @@
 #[macro_use]
 extern crate log;
 
-use env_logger;
 use futures::channel::oneshot;
 use mysql_async::prelude::*;
 use std::{error::Error, sync::Once};
-use tokio;
 
 use sql_proxy::{
     packet::{DatabaseType, Packet},
-    packet_handler::PacketHandler,
+    packet_handler::{HandlerAction, PacketContext, PacketHandler},
 };
 
 static INIT: Once = Once::new();
@@ -18,22 +16,22 @@ struct PassthroughHandler {}
 
 #[async_trait::async_trait]
 impl PacketHandler for PassthroughHandler {
-    async fn handle_request(&mut self, p: &Packet) -> Packet {
+    async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
         debug!(
             "c=>s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
-        p.clone()
+        HandlerAction::Forward
     }
 
-    async fn handle_response(&mut self, p: &Packet) -> Packet {
+    async fn handle_response(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
         debug!(
             "c<=s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
-        p.clone()
+        HandlerAction::Forward
     }
 }
 
@@ -0,0 +1,144 @@
+//! Runs a connection's two pipes over in-memory streams, no database needed
+use futures::{
+    channel::{mpsc, oneshot},
+    join,
+    lock::Mutex,
+};
+use std::sync::Arc;
+use tokio::{
+    io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    task::JoinHandle,
+};
+
+use sql_proxy::{
+    packet::{DatabaseType, Packet},
+    packet_handler::{Direction, PassthroughHandler},
+    pipe::{CloseReason, PipeBuilder, SslState},
+};
+
+type PipeResult = Result<CloseReason, CloseReason>;
+
+/// The client and database ends of a proxied connection
+struct Harness {
+    client: DuplexStream,
+    db: DuplexStream,
+    pipes: JoinHandle<(PipeResult, PipeResult)>,
+}
+
+fn connect(db_type: DatabaseType) -> Harness {
+    let (client, proxy_client) = duplex(64 * 1024);
+    let (db, proxy_db) = duplex(64 * 1024);
+    let (client_reader, client_writer) = split(proxy_client);
+    let (db_reader, db_writer) = split(proxy_db);
+    let handler = Arc::new(Mutex::new(PassthroughHandler {}));
+    let ssl_state = Arc::new(SslState::new());
+    let mut forward_pipe = PipeBuilder::new(
+        "harness".to_string(),
+        db_type,
+        handler.clone(),
+        Direction::Forward,
+        client_reader,
+        db_writer,
+    )
+    .with_ssl_state(ssl_state.clone())
+    .build();
+    let mut backward_pipe = PipeBuilder::new(
+        "harness".to_string(),
+        db_type,
+        handler,
+        Direction::Backward,
+        db_reader,
+        client_writer,
+    )
+    .with_ssl_state(ssl_state)
+    .build();
+    let pipes = tokio::spawn(async move {
+        let (fb_tx, fb_rx) = mpsc::channel::<Packet>(16);
+        let (bf_tx, bf_rx) = mpsc::channel::<Packet>(16);
+        let (_forward_kill, forward_kill_rx) = oneshot::channel();
+        let (_backward_kill, backward_kill_rx) = oneshot::channel();
+        join!(
+            forward_pipe.run(fb_tx, bf_rx, forward_kill_rx),
+            backward_pipe.run(bf_tx, fb_rx, backward_kill_rx),
+        )
+    });
+    Harness { client, db, pipes }
+}
+
+async fn read_bytes(stream: &mut DuplexStream, n: usize) -> Vec<u8> {
+    let mut bytes = vec![0_u8; n];
+    stream.read_exact(&mut bytes).await.unwrap();
+    bytes
+}
+
+#[tokio::test]
+async fn harness_proxies_mariadb() {
+    let Harness {
+        mut client,
+        mut db,
+        pipes,
+    } = connect(DatabaseType::MariaDB);
+
+    let handshake = Packet::mariadb(0, b"\x0a10.4.12-MariaDB\0");
+    db.write_all(&handshake.bytes).await.unwrap();
+    assert_eq!(
+        read_bytes(&mut client, handshake.get_size()).await,
+        handshake.bytes
+    );
+
+    // Several packets in one write, one split across writes
+    let query = Packet::mariadb(0, b"\x03SELECT 1");
+    let ping = Packet::mariadb(0, &[0x0e]);
+    let mut requests = query.bytes.to_vec();
+    requests.extend_from_slice(&ping.bytes);
+    client.write_all(&requests[..3]).await.unwrap();
+    client.write_all(&requests[3..]).await.unwrap();
+    assert_eq!(read_bytes(&mut db, requests.len()).await, requests);
+
+    let ok = Packet::mariadb(1, &[0, 0, 0, 2, 0, 0, 0]);
+    db.write_all(&ok.bytes).await.unwrap();
+    assert_eq!(read_bytes(&mut client, ok.get_size()).await, ok.bytes);
+
+    // The client leaves, then the database closes its side
+    drop(client);
+    let mut rest = Vec::new();
+    db.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    drop(db);
+    let (forward, backward) = pipes.await.unwrap();
+    assert!(forward.is_ok());
+    assert!(backward.is_ok());
+}
+
+#[tokio::test]
+async fn harness_proxies_postgres() {
+    let Harness {
+        mut client,
+        mut db,
+        pipes,
+    } = connect(DatabaseType::PostgresSQL);
+
+    let startup = Packet::postgres_startup(&[("user", "root")]);
+    client.write_all(&startup.bytes).await.unwrap();
+    assert_eq!(read_bytes(&mut db, startup.get_size()).await, startup.bytes);
+
+    let mut responses = Packet::postgres(b'R', &[0, 0, 0, 0]).bytes.to_vec();
+    responses.extend_from_slice(&Packet::postgres(b'Z', b"I").bytes);
+    db.write_all(&responses).await.unwrap();
+    assert_eq!(read_bytes(&mut client, responses.len()).await, responses);
+
+    let query = Packet::postgres(b'Q', b"SELECT 1\0");
+    client.write_all(&query.bytes).await.unwrap();
+    assert_eq!(read_bytes(&mut db, query.get_size()).await, query.bytes);
+
+    let terminate = Packet::postgres(b'X', &[]);
+    client.write_all(&terminate.bytes).await.unwrap();
+    assert_eq!(
+        read_bytes(&mut db, terminate.get_size()).await,
+        terminate.bytes
+    );
+    drop(client);
+    drop(db);
+    let (forward, _backward) = pipes.await.unwrap();
+    assert!(forward.is_ok());
+}
@@ -3,12 +3,11 @@ extern crate log;
 
 use futures::channel::oneshot;
 use std::{error::Error, sync::Once};
-use tokio;
 use tokio_postgres::{NoTls, SimpleQueryMessage};
 
 use sql_proxy::{
     packet::{DatabaseType, Packet},
-    packet_handler::PacketHandler,
+    packet_handler::{HandlerAction, PacketContext, PacketHandler},
 };
 
 static INIT: Once = Once::new();
@@ -17,25 +16,26 @@ struct PassthroughHandler {}
 
 #[async_trait::async_trait]
 impl PacketHandler for PassthroughHandler {
-    async fn handle_request(&mut self, p: &Packet) -> Packet {
+    async fn handle_request(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
         debug!(
             "c=>s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
-        p.clone()
+        HandlerAction::Forward
     }
 
-    async fn handle_response(&mut self, p: &Packet) -> Packet {
+    async fn handle_response(&mut self, p: &Packet, _ctx: &PacketContext) -> HandlerAction {
         debug!(
             "c<=s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
-        p.clone()
+        HandlerAction::Forward
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, PartialEq, Eq, Clone)]
 struct Payment {
     customer_id: i32,